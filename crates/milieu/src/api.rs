@@ -1,6 +1,9 @@
-use crate::error::{MilieuError, Result};
-use reqwest::StatusCode;
+use crate::error::{ApiError, MilieuError, Result};
+use rand_core::{OsRng, RngCore};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
 use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+use std::time::Duration;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginRequest {
@@ -15,10 +18,24 @@ pub struct RegisterRequest {
     pub password: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordChangeRequest {
+    pub current_password: String,
+    pub new_password: String,
+    pub revoke_other_sessions: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LoginResponse {
     pub access_token: String,
     pub user_id: String,
+    // Older servers send neither expiry nor refresh token.
+    #[serde(default)]
+    pub expires_at: Option<String>,
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    #[serde(default)]
+    pub refresh_expires_at: Option<String>,
     pub warning: Option<String>,
 }
 
@@ -52,6 +69,33 @@ pub struct ObjectRequest {
     pub ciphertext_hash: String,
     pub created_at: String,
     pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upload_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_count: Option<u32>,
+    // Last synced version (0 for a new file); None writes unconditionally.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_version: Option<u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkRequest {
+    pub upload_id: String,
+    pub path: String,
+    pub index: u32,
+    pub total: u32,
+    pub nonce: String,
+    pub ciphertext: String,
+    pub ciphertext_hash: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChunkResponse {
+    pub index: u32,
+    pub total: u32,
+    pub nonce: String,
+    pub ciphertext: String,
+    pub ciphertext_hash: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -66,6 +110,35 @@ pub struct ObjectResponse {
     pub schema_version: u32,
 }
 
+// One path in a batch latest-object request. The server returns metadata only
+// when the latest object matches `known_hash` or is no newer than
+// `since_version`.
+#[derive(Debug, Serialize)]
+pub struct LatestQuery {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub known_hash: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since_version: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct LatestBatchRequest<'a> {
+    paths: &'a [LatestQuery],
+}
+
+#[derive(Deserialize)]
+struct LatestBatchResponse {
+    objects: Vec<LatestBatchEntry>,
+}
+
+#[derive(Deserialize)]
+struct LatestBatchEntry {
+    path: String,
+    status: String,
+    object: Option<ObjectResponse>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct HistoryEntry {
     pub version: u32,
@@ -94,6 +167,9 @@ pub struct SessionInfo {
     pub expires_at: String,
     pub token_suffix: String,
     pub active: bool,
+    // The session making the request; older servers do not say.
+    #[serde(default)]
+    pub current: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -105,6 +181,22 @@ pub struct RepoAccessEntry {
     pub created_at: String,
     pub public_key: Option<String>,
     pub key_algorithm: Option<String>,
+    // Older servers do not say.
+    #[serde(default)]
+    pub has_repo_key: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AccountDeleteRequest {
+    pub password: String,
+    pub transfers: Vec<RepoTransfer>,
+    pub delete_repos: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RepoTransfer {
+    pub repo_id: String,
+    pub email: String,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -117,6 +209,33 @@ pub struct InviteInfo {
     pub created_at: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServiceTokenRequest {
+    pub name: Option<String>,
+    pub branch: String,
+    pub access: String,
+    pub wrapped_key: String,
+    pub algorithm: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServiceTokenCreated {
+    pub id: String,
+    pub name: String,
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServiceTokenInfo {
+    pub id: String,
+    pub name: String,
+    pub branch: String,
+    pub access: String,
+    pub token_suffix: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct UserKeyResponse {
     pub public_key: String,
@@ -144,24 +263,229 @@ pub struct RepoResponse {
     pub name: String,
 }
 
-
 #[derive(Clone)]
 pub struct ApiClient {
     base_url: String,
     token: Option<String>,
     client: reqwest::Client,
+    offline: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct NetworkSettings {
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    pub max_retries: u32,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(10),
+            request_timeout: Duration::from_secs(30),
+            max_retries: 3,
+        }
+    }
+}
+
+static NETWORK: OnceLock<NetworkSettings> = OnceLock::new();
+
+// Set once from config and global flags before any client is built.
+pub fn configure(settings: NetworkSettings) {
+    let _ = NETWORK.set(settings);
+}
+
+fn network() -> NetworkSettings {
+    NETWORK.get().copied().unwrap_or_default()
+}
+
+const BACKOFF_BASE: Duration = Duration::from_millis(250);
+const BACKOFF_MAX: Duration = Duration::from_secs(8);
+// A 429 asking for a longer wait than this is reported instead of waited out.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+trait SendExt {
+    async fn send_retrying(self) -> Result<Response>;
+    async fn send_idempotent(self) -> Result<Response>;
+    async fn send_once(self) -> Result<Response>;
+}
+
+impl SendExt for RequestBuilder {
+    // GET, PUT and DELETE are retried on network errors and 5xx responses.
+    // Any request is retried on 429 or when the connection was never made,
+    // since the server has not acted on it.
+    async fn send_retrying(self) -> Result<Response> {
+        send_with_retries(self, None).await
+    }
+
+    // For POSTs that only read, such as the batch latest-object lookup.
+    async fn send_idempotent(self) -> Result<Response> {
+        send_with_retries(self, Some(true)).await
+    }
+
+    // For PUTs and DELETEs whose effect or response changes on a second run,
+    // such as a password change or an account delete: exactly one attempt.
+    async fn send_once(self) -> Result<Response> {
+        self.send().await.map_err(request_error)
+    }
+}
+
+async fn send_with_retries(builder: RequestBuilder, idempotent: Option<bool>) -> Result<Response> {
+    let (client, request) = builder.build_split();
+    let request = request?;
+    let idempotent = idempotent.unwrap_or_else(|| {
+        matches!(*request.method(), Method::GET | Method::PUT | Method::DELETE | Method::HEAD)
+    });
+    let settings = network();
+    let mut attempt = 0;
+    loop {
+        let Some(next) = request.try_clone() else {
+            return client.execute(request).await.map_err(request_error);
+        };
+        let result = client.execute(next).await;
+        let delay = match &result {
+            Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                match retry_after(response) {
+                    Some(wait) if wait > MAX_RETRY_AFTER => None,
+                    Some(wait) => Some(wait),
+                    None => Some(backoff(attempt)),
+                }
+            }
+            Ok(response) if idempotent && response.status().is_server_error() => {
+                Some(backoff(attempt))
+            }
+            Ok(_) => None,
+            Err(err) if err.is_connect() => Some(backoff(attempt)),
+            Err(err) if idempotent && (err.is_timeout() || err.is_request()) => {
+                Some(backoff(attempt))
+            }
+            Err(_) => None,
+        };
+        match delay {
+            Some(delay) if attempt < settings.max_retries => {
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            _ => return result.map_err(request_error),
+        }
+    }
+}
+
+// Exponential backoff with jitter: half the step is fixed, half random.
+fn backoff(attempt: u32) -> Duration {
+    let step = BACKOFF_BASE
+        .saturating_mul(1 << attempt.min(16))
+        .min(BACKOFF_MAX);
+    let half = step.as_millis() as u64 / 2;
+    Duration::from_millis(half + OsRng.next_u64() % (half + 1))
+}
+
+// Only the delta-seconds form is used by the server.
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(reqwest::header::RETRY_AFTER)?;
+    let seconds = value.to_str().ok()?.trim().parse::<u64>().ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
+fn request_error(err: reqwest::Error) -> MilieuError {
+    if err.is_timeout() {
+        return MilieuError::Network(format!(
+            "request timed out after {}s; raise the limit with --timeout",
+            network().request_timeout.as_secs()
+        ));
+    }
+    MilieuError::Http(err)
+}
+
+#[derive(Deserialize, Default)]
+struct ErrorBody {
+    error: Option<String>,
+    message: Option<String>,
+    hint: Option<String>,
+}
+
+// Reads the server's error body and maps the status to a typed error. Older
+// servers only send a code, which is shown with spaces for underscores.
+async fn api_error(response: Response, action: &str) -> MilieuError {
+    let status = response.status();
+    let retry_after = retry_after(&response).map(|wait| wait.as_secs());
+    let body: ErrorBody = response.json().await.unwrap_or_default();
+    let code = body.error.unwrap_or_else(|| status.as_u16().to_string());
+    let message = body.message.unwrap_or_else(|| match status.canonical_reason() {
+        Some(reason) if code.chars().all(|c| c.is_ascii_digit()) => reason.to_lowercase(),
+        _ => code.replace('_', " "),
+    });
+    let err = ApiError {
+        action: action.to_string(),
+        code,
+        message,
+        hint: body.hint,
+        retry_after,
+    };
+    match status {
+        StatusCode::UNAUTHORIZED if err.code == "invalid_credentials" => MilieuError::Forbidden(err),
+        StatusCode::UNAUTHORIZED => MilieuError::SessionExpired(err),
+        StatusCode::FORBIDDEN => MilieuError::Forbidden(err),
+        StatusCode::NOT_FOUND => MilieuError::NotFound(err),
+        StatusCode::CONFLICT => MilieuError::Conflict(err),
+        StatusCode::TOO_MANY_REQUESTS => MilieuError::RateLimited(err),
+        status if status.is_server_error() => MilieuError::ServerError(err),
+        _ => MilieuError::BadRequest(err),
+    }
+}
+
+// The server answers writes without write access as if the repo did not
+// exist, so a read-only collaborator gets a hint about that.
+async fn write_error(response: Response, action: &str) -> MilieuError {
+    match api_error(response, action).await {
+        MilieuError::NotFound(mut err) if err.code == "repo_not_found" => {
+            err.hint = Some("you may only have read access; ask the owner for write access".to_string());
+            MilieuError::NotFound(err)
+        }
+        err => err,
+    }
+}
+
+// Largest number of paths the server accepts in one batch request.
+const MAX_BATCH_PATHS: usize = 100;
+
+// Result of a conditional latest-object request.
+pub enum LatestObject {
+    NotModified,
+    Missing,
+    Found(ObjectResponse),
 }
 
 impl ApiClient {
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
     pub fn new(base_url: &str, token: Option<String>) -> Result<Self> {
-        let client = reqwest::Client::builder().build()?;
+        let settings = network();
+        let client = reqwest::Client::builder()
+            .connect_timeout(settings.connect_timeout)
+            .timeout(settings.request_timeout)
+            .build()?;
         Ok(Self {
             base_url: base_url.to_string(),
             token,
             client,
+            offline: false,
         })
     }
 
+    // Offline clients never touch the network; callers read from the local
+    // object cache instead and any request fails with a clear error.
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
     fn endpoint(&self, path: &str) -> String {
         format!(
             "{}/{}",
@@ -171,6 +495,11 @@ impl ApiClient {
     }
 
     fn auth_header(&self) -> Result<String> {
+        if self.offline {
+            return Err(MilieuError::CommandFailed(
+                "this needs the server; rerun without --offline".to_string(),
+            ));
+        }
         match &self.token {
             Some(token) => Ok(format!("Bearer {}", token)),
             None => Err(MilieuError::AuthMissing),
@@ -179,24 +508,72 @@ impl ApiClient {
 
     pub async fn login(&self, request: &LoginRequest) -> Result<LoginResponse> {
         let url = self.endpoint("/v1/auth/login");
-        let response = self.client.post(url).json(request).send().await?;
+        let response = self.client.post(url).json(request).send_retrying().await?;
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "login failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "login").await);
         }
         Ok(response.json().await?)
     }
 
+    pub async fn refresh(&self, refresh_token: &str) -> Result<LoginResponse> {
+        let url = self.endpoint("/v1/auth/refresh");
+        let response = self
+            .client
+            .post(url)
+            .json(&serde_json::json!({ "refresh_token": refresh_token }))
+            .send_retrying()
+            .await?;
+        if !response.status().is_success() {
+            return Err(api_error(response, "refresh session").await);
+        }
+        Ok(response.json().await?)
+    }
+
+    // Returns how many other sessions were signed out.
+    pub async fn change_password(&self, request: &PasswordChangeRequest) -> Result<u64> {
+        let url = self.endpoint("/v1/users/me/password");
+        let response = self
+            .client
+            .put(url)
+            .header("Authorization", self.auth_header()?)
+            .json(request)
+            .send_once()
+            .await?;
+        if !response.status().is_success() {
+            return Err(api_error(response, "change password").await);
+        }
+        #[derive(Deserialize)]
+        struct PasswordChangeResponse {
+            #[serde(default)]
+            revoked: u64,
+        }
+        let body: PasswordChangeResponse = response.json().await?;
+        Ok(body.revoked)
+    }
+
+    // Removes the account with its keys, sessions and shared access. Owned
+    // repos are transferred or deleted in the same request, and only once the
+    // server has checked the password and the whole plan.
+    pub async fn delete_account(&self, request: &AccountDeleteRequest) -> Result<()> {
+        let url = self.endpoint("/v1/users/me");
+        let response = self
+            .client
+            .delete(url)
+            .header("Authorization", self.auth_header()?)
+            .json(request)
+            .send_once()
+            .await?;
+        if !response.status().is_success() {
+            return Err(api_error(response, "delete account").await);
+        }
+        Ok(())
+    }
+
     pub async fn register(&self, request: &RegisterRequest) -> Result<RegisterResponse> {
         let url = self.endpoint("/v1/auth/register");
-        let response = self.client.post(url).json(request).send().await?;
+        let response = self.client.post(url).json(request).send_retrying().await?;
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "register failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "register").await);
         }
         Ok(response.json().await?)
     }
@@ -207,17 +584,14 @@ impl ApiClient {
             .client
             .get(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_retrying()
             .await?;
 
         if response.status() == StatusCode::NOT_FOUND {
             return Ok(None);
         }
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "get umk failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "get umk").await);
         }
         Ok(Some(response.json().await?))
     }
@@ -229,14 +603,11 @@ impl ApiClient {
             .put(url)
             .header("Authorization", self.auth_header()?)
             .json(request)
-            .send()
+            .send_retrying()
             .await?;
 
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "put umk failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "put umk").await);
         }
         Ok(())
     }
@@ -257,53 +628,151 @@ impl ApiClient {
             .post(url)
             .header("Authorization", self.auth_header()?)
             .json(request)
-            .send()
+            .send_retrying()
             .await?;
 
         if !response.status().is_success() {
-            let message = match response.status() {
-                StatusCode::NOT_FOUND => {
-                    "post object failed: repo not found or no write access (read-only)".to_string()
-                }
-                StatusCode::UNAUTHORIZED => "post object failed: unauthorized".to_string(),
-                StatusCode::FORBIDDEN => "post object failed: forbidden".to_string(),
-                _ => format!("post object failed: {}", response.status()),
-            };
-            return Err(MilieuError::CommandFailed(message));
+            return Err(write_error(response, "push object").await);
         }
         Ok(response.json().await?)
     }
 
-    pub async fn get_latest(
+    pub async fn post_chunk(
+        &self,
+        repo_id: &str,
+        branch: &str,
+        request: &ChunkRequest,
+    ) -> Result<()> {
+        let path = format!(
+            "/v1/repos/{}/branches/{}/objects/chunks",
+            repo_id, branch
+        );
+        let url = self.endpoint(&path);
+        let response = self
+            .client
+            .post(url)
+            .header("Authorization", self.auth_header()?)
+            .json(request)
+            .send_retrying()
+            .await?;
+
+        if !response.status().is_success() {
+            return Err(api_error(response, "post chunk").await);
+        }
+        Ok(())
+    }
+
+    pub async fn get_chunk(
         &self,
         repo_id: &str,
         branch: &str,
         path: &str,
-    ) -> Result<Option<ObjectResponse>> {
+        version: u32,
+        index: u32,
+    ) -> Result<ChunkResponse> {
         let endpoint = format!(
-            "/v1/repos/{}/branches/{}/objects/latest?path={}",
+            "/v1/repos/{}/branches/{}/objects/chunks?path={}&version={}&index={}",
             repo_id,
             branch,
-            urlencoding::encode(path)
+            urlencoding::encode(path),
+            version,
+            index
         );
         let url = self.endpoint(&endpoint);
         let response = self
             .client
             .get(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_retrying()
             .await?;
 
+        if !response.status().is_success() {
+            return Err(api_error(response, "get chunk").await);
+        }
+        Ok(response.json().await?)
+    }
+
+    // Sends the cached ciphertext hash as an ETag so an unchanged object is
+    // not downloaded again.
+    pub async fn get_latest_if_changed(
+        &self,
+        repo_id: &str,
+        branch: &str,
+        path: &str,
+        known_hash: Option<&str>,
+    ) -> Result<LatestObject> {
+        let endpoint = format!(
+            "/v1/repos/{}/branches/{}/objects/latest?path={}",
+            repo_id,
+            branch,
+            urlencoding::encode(path)
+        );
+        let url = self.endpoint(&endpoint);
+        let mut request = self
+            .client
+            .get(url)
+            .header("Authorization", self.auth_header()?);
+        if let Some(hash) = known_hash {
+            request = request.header("If-None-Match", format!("\"{}\"", hash));
+        }
+        let response = request.send_retrying().await?;
+
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(LatestObject::NotModified);
+        }
         if response.status() == StatusCode::NOT_FOUND {
-            return Ok(None);
+            return Ok(LatestObject::Missing);
         }
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "get latest failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "get latest").await);
         }
-        Ok(Some(response.json().await?))
+        Ok(LatestObject::Found(response.json().await?))
+    }
+
+    // Latest objects for many paths of one branch, in the order queried.
+    // Returns None when the server predates the batch endpoint, so callers
+    // can fall back to one request per path.
+    pub async fn get_latest_batch(
+        &self,
+        repo_id: &str,
+        branch: &str,
+        queries: &[LatestQuery],
+    ) -> Result<Option<Vec<LatestObject>>> {
+        let endpoint = format!("/v1/repos/{}/branches/{}/objects/latest", repo_id, branch);
+        let url = self.endpoint(&endpoint);
+        let mut objects = Vec::with_capacity(queries.len());
+        for batch in queries.chunks(MAX_BATCH_PATHS) {
+            let response = self
+                .client
+                .post(&url)
+                .header("Authorization", self.auth_header()?)
+                .json(&LatestBatchRequest { paths: batch })
+                .send_idempotent()
+                .await?;
+
+            if response.status() == StatusCode::METHOD_NOT_ALLOWED {
+                return Ok(None);
+            }
+            if !response.status().is_success() {
+                return Err(api_error(response, "get latest").await);
+            }
+            let body: LatestBatchResponse = response.json().await?;
+            if body.objects.len() != batch.len()
+                || body.objects.iter().zip(batch).any(|(entry, query)| entry.path != query.path)
+            {
+                return Err(MilieuError::CommandFailed(
+                    "get latest failed: server returned mismatched paths".to_string(),
+                ));
+            }
+            for entry in body.objects {
+                objects.push(match (entry.status.as_str(), entry.object) {
+                    ("not_modified", _) => LatestObject::NotModified,
+                    ("found", Some(object)) => LatestObject::Found(object),
+                    _ => LatestObject::Missing,
+                });
+            }
+        }
+        Ok(Some(objects))
     }
 
     pub async fn get_version(
@@ -325,14 +794,11 @@ impl ApiClient {
             .client
             .get(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_retrying()
             .await?;
 
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "get version failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "get version").await);
         }
         Ok(response.json().await?)
     }
@@ -354,14 +820,11 @@ impl ApiClient {
             .client
             .get(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_retrying()
             .await?;
 
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "get history failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "get history").await);
         }
         let body: Vec<HistoryEntry> = response.json().await?;
         Ok(body)
@@ -373,14 +836,11 @@ impl ApiClient {
             .client
             .get(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_retrying()
             .await?;
 
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "get repos failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "get repos").await);
         }
         let body: RepoListResponse = response.json().await?;
         Ok(body.repos)
@@ -392,13 +852,10 @@ impl ApiClient {
             .client
             .get(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_retrying()
             .await?;
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "get access failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "get access").await);
         }
         #[derive(Deserialize)]
         struct AccessResponse {
@@ -420,13 +877,10 @@ impl ApiClient {
             .post(url)
             .header("Authorization", self.auth_header()?)
             .json(&serde_json::json!({ "email": email, "role": role }))
-            .send()
+            .send_retrying()
             .await?;
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "invite failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "invite").await);
         }
         Ok(())
     }
@@ -443,13 +897,10 @@ impl ApiClient {
             .patch(url)
             .header("Authorization", self.auth_header()?)
             .json(&serde_json::json!({ "email": email, "role": role }))
-            .send()
+            .send_retrying()
             .await?;
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "update access failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "update access").await);
         }
         Ok(())
     }
@@ -464,13 +915,62 @@ impl ApiClient {
             .client
             .delete(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_once()
+            .await?;
+        if !response.status().is_success() {
+            return Err(api_error(response, "revoke access").await);
+        }
+        Ok(())
+    }
+
+    pub async fn create_service_token(
+        &self,
+        repo_id: &str,
+        request: &ServiceTokenRequest,
+    ) -> Result<ServiceTokenCreated> {
+        let url = self.endpoint(&format!("/v1/repos/{}/tokens", repo_id));
+        let response = self
+            .client
+            .post(url)
+            .header("Authorization", self.auth_header()?)
+            .json(request)
+            .send_retrying()
+            .await?;
+        if !response.status().is_success() {
+            return Err(api_error(response, "create token").await);
+        }
+        Ok(response.json().await?)
+    }
+
+    pub async fn list_service_tokens(&self, repo_id: &str) -> Result<Vec<ServiceTokenInfo>> {
+        let url = self.endpoint(&format!("/v1/repos/{}/tokens", repo_id));
+        let response = self
+            .client
+            .get(url)
+            .header("Authorization", self.auth_header()?)
+            .send_retrying()
+            .await?;
+        if !response.status().is_success() {
+            return Err(api_error(response, "list tokens").await);
+        }
+        #[derive(Deserialize)]
+        struct TokensResponse {
+            tokens: Vec<ServiceTokenInfo>,
+        }
+        let body: TokensResponse = response.json().await?;
+        Ok(body.tokens)
+    }
+
+    pub async fn revoke_service_token(&self, repo_id: &str, token_id: &str) -> Result<()> {
+        let url = self.endpoint(&format!("/v1/repos/{}/tokens/{}", repo_id, token_id));
+        let response = self
+            .client
+            .delete(url)
+            .header("Authorization", self.auth_header()?)
+            .send_once()
             .await?;
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "revoke access failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "revoke token").await);
         }
         Ok(())
     }
@@ -481,13 +981,10 @@ impl ApiClient {
             .client
             .delete(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_once()
             .await?;
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "delete repo failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "delete repo").await);
         }
         Ok(())
     }
@@ -498,13 +995,10 @@ impl ApiClient {
             .client
             .get(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_retrying()
             .await?;
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "get invites failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "get invites").await);
         }
         Ok(response.json().await?)
     }
@@ -515,17 +1009,14 @@ impl ApiClient {
             .client
             .get(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_retrying()
             .await?;
 
         if response.status() == StatusCode::NOT_FOUND {
             return Ok(None);
         }
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "get user key failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "get user key").await);
         }
         Ok(Some(response.json().await?))
     }
@@ -537,13 +1028,10 @@ impl ApiClient {
             .put(url)
             .header("Authorization", self.auth_header()?)
             .json(&serde_json::json!({ "public_key": public_key, "algorithm": algorithm }))
-            .send()
+            .send_retrying()
             .await?;
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "put user key failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "put user key").await);
         }
         Ok(())
     }
@@ -554,16 +1042,13 @@ impl ApiClient {
             .client
             .get(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_retrying()
             .await?;
         if response.status() == StatusCode::NOT_FOUND {
             return Ok(None);
         }
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "get repo key failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "get repo key").await);
         }
         Ok(Some(response.json().await?))
     }
@@ -585,13 +1070,10 @@ impl ApiClient {
             .put(url)
             .header("Authorization", self.auth_header()?)
             .json(&body)
-            .send()
+            .send_retrying()
             .await?;
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "put repo key failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "put repo key").await);
         }
         Ok(())
     }
@@ -605,13 +1087,10 @@ impl ApiClient {
             .client
             .post(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_retrying()
             .await?;
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "accept invite failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "accept invite").await);
         }
         Ok(())
     }
@@ -625,13 +1104,10 @@ impl ApiClient {
             .client
             .post(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_retrying()
             .await?;
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "reject invite failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "reject invite").await);
         }
         Ok(())
     }
@@ -642,30 +1118,56 @@ impl ApiClient {
             .client
             .get(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_retrying()
             .await?;
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "get sessions failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "get sessions").await);
         }
         Ok(response.json().await?)
     }
 
+    // Returns how many sessions were revoked.
+    pub async fn revoke_session(&self, token_suffix: &str) -> Result<u64> {
+        let url = self.endpoint(&format!(
+            "/v1/users/me/sessions/{}",
+            urlencoding::encode(token_suffix)
+        ));
+        self.delete_sessions(url, "revoke session").await
+    }
+
+    pub async fn revoke_other_sessions(&self) -> Result<u64> {
+        let url = self.endpoint("/v1/users/me/sessions");
+        self.delete_sessions(url, "revoke sessions").await
+    }
+
+    async fn delete_sessions(&self, url: String, action: &str) -> Result<u64> {
+        let response = self
+            .client
+            .delete(url)
+            .header("Authorization", self.auth_header()?)
+            .send_once()
+            .await?;
+        if !response.status().is_success() {
+            return Err(api_error(response, action).await);
+        }
+        #[derive(Deserialize)]
+        struct RevokeResponse {
+            revoked: u64,
+        }
+        let body: RevokeResponse = response.json().await?;
+        Ok(body.revoked)
+    }
+
     pub async fn logout(&self) -> Result<()> {
         let url = self.endpoint("/v1/auth/logout");
         let response = self
             .client
             .post(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_retrying()
             .await?;
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "logout failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "logout").await);
         }
         Ok(())
     }
@@ -679,14 +1181,11 @@ impl ApiClient {
             .json(&RepoCreateRequest {
                 name: name.to_string(),
             })
-            .send()
+            .send_retrying()
             .await?;
 
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "create repo failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "create repo").await);
         }
         Ok(response.json().await?)
     }
@@ -698,17 +1197,11 @@ impl ApiClient {
             .client
             .get(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_retrying()
             .await?;
 
-        if response.status() == StatusCode::NOT_FOUND {
-            return Err(MilieuError::CommandFailed("repo not found".to_string()));
-        }
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "get repo failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "get repo").await);
         }
         Ok(response.json().await?)
     }
@@ -720,14 +1213,11 @@ impl ApiClient {
             .client
             .get(url)
             .header("Authorization", self.auth_header()?)
-            .send()
+            .send_retrying()
             .await?;
 
         if !response.status().is_success() {
-            return Err(MilieuError::CommandFailed(format!(
-                "get manifest failed: {}",
-                response.status()
-            )));
+            return Err(api_error(response, "get manifest").await);
         }
         Ok(response.json().await?)
     }
@@ -741,20 +1231,109 @@ impl ApiClient {
             .put(url)
             .header("Authorization", self.auth_header()?)
             .json(&sanitized)
-            .send()
+            .send_retrying()
             .await?;
 
         if !response.status().is_success() {
-            let message = match response.status() {
-                StatusCode::NOT_FOUND => {
-                    "put manifest failed: repo not found or no write access (read-only)".to_string()
-                }
-                StatusCode::UNAUTHORIZED => "put manifest failed: unauthorized".to_string(),
-                StatusCode::FORBIDDEN => "put manifest failed: forbidden".to_string(),
-                _ => format!("put manifest failed: {}", response.status()),
-            };
-            return Err(MilieuError::CommandFailed(message));
+            return Err(write_error(response, "put manifest").await);
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Answers each request with the next canned status line and headers,
+    // repeating the last one, and counts the requests it saw.
+    fn serve(responses: &[&str]) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let responses: Vec<String> = responses.iter().map(|r| r.to_string()).collect();
+        let count = Arc::new(AtomicUsize::new(0));
+        let seen = count.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match stream.read(&mut buf) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+                let n = seen.fetch_add(1, Ordering::SeqCst);
+                let head = &responses[n.min(responses.len() - 1)];
+                let reply = format!(
+                    "HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    head
+                );
+                let _ = stream.write_all(reply.as_bytes());
+            }
+        });
+        (url, count)
+    }
+
+    async fn status_of(builder: RequestBuilder, idempotent: Option<bool>) -> u16 {
+        send_with_retries(builder, idempotent)
+            .await
+            .unwrap()
+            .status()
+            .as_u16()
+    }
+
+    #[test]
+    fn backoff_stays_within_its_step() {
+        for attempt in 0..20 {
+            let step = BACKOFF_BASE.saturating_mul(1 << attempt.min(16)).min(BACKOFF_MAX);
+            let delay = backoff(attempt);
+            assert!(delay >= step / 2 && delay <= step, "{:?} outside {:?}", delay, step);
+        }
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried_for_idempotent_requests() {
+        let (url, count) = serve(&["503 Service Unavailable", "200 OK"]);
+        let status = status_of(reqwest::Client::new().get(&url), None).await;
+        assert_eq!((status, count.load(Ordering::SeqCst)), (200, 2));
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_not_retried_for_posts() {
+        let (url, count) = serve(&["503 Service Unavailable", "200 OK"]);
+        let status = status_of(reqwest::Client::new().post(&url), None).await;
+        assert_eq!((status, count.load(Ordering::SeqCst)), (503, 1));
+    }
+
+    #[tokio::test]
+    async fn retries_stop_after_the_limit() {
+        let (url, count) = serve(&["500 Internal Server Error"]);
+        let status = status_of(reqwest::Client::new().get(&url), Some(true)).await;
+        let attempts = network().max_retries as usize + 1;
+        assert_eq!((status, count.load(Ordering::SeqCst)), (500, attempts));
+    }
+
+    #[tokio::test]
+    async fn rate_limits_honour_retry_after_even_for_posts() {
+        let (url, count) = serve(&["429 Too Many Requests\r\nretry-after: 0", "200 OK"]);
+        let status = status_of(reqwest::Client::new().post(&url), None).await;
+        assert_eq!((status, count.load(Ordering::SeqCst)), (200, 2));
+    }
+
+    #[tokio::test]
+    async fn long_retry_after_is_reported_instead_of_waited() {
+        let (url, count) = serve(&["429 Too Many Requests\r\nretry-after: 120", "200 OK"]);
+        let response = send_with_retries(reqwest::Client::new().get(&url), None)
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(retry_after(&response), Some(Duration::from_secs(120)));
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,5 +1,7 @@
 use crate::error::{MilieuError, Result};
+use crate::api::LoginResponse;
 use crate::keychain;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::{Mutex, OnceLock};
@@ -11,6 +13,15 @@ struct SessionSecret {
     umk: Option<String>,
     phrase: Option<String>,
     email: Option<String>,
+    #[serde(default)]
+    expires_at: Option<String>,
+    #[serde(default)]
+    refresh_token: Option<String>,
+    #[serde(default)]
+    refresh_expires_at: Option<String>,
+    // When the user last proved they wrote the phrase down.
+    #[serde(default)]
+    phrase_verified_at: Option<String>,
 }
 
 static SESSION_CACHE: OnceLock<Mutex<HashMap<String, SessionSecret>>> = OnceLock::new();
@@ -28,6 +39,12 @@ fn session_key(profile: &str) -> String {
 }
 
 fn load_session(profile: &str) -> Result<SessionSecret> {
+    if let Some(token) = crate::token::active()? {
+        return Ok(SessionSecret {
+            auth_token: Some(token.credential.clone()),
+            ..SessionSecret::default()
+        });
+    }
     let key = profile_key(profile);
     if let Ok(cache) = cache().lock() {
         if let Some(entry) = cache.get(&key) {
@@ -38,11 +55,10 @@ fn load_session(profile: &str) -> Result<SessionSecret> {
     let entry = keychain::get_secret(&session_key(profile))?;
     let secret = match entry.as_ref() {
         Some(value) => serde_json::from_str::<SessionSecret>(value)
-            .map_err(|e| MilieuError::Json(e))?,
+            .map_err(MilieuError::Json)?,
         None => SessionSecret::default(),
     };
 
-
     if let Ok(mut cache) = cache().lock() {
         cache.insert(key, secret.clone());
     }
@@ -60,13 +76,40 @@ fn store_session(profile: &str, secret: &SessionSecret) -> Result<()> {
     Ok(())
 }
 
-pub fn store_auth(profile: &str, token: &str, user_id: &str) -> Result<()> {
+// Stores a login or refresh response: the token and when it (and the refresh
+// token, if the server issued one) stops working.
+pub fn store_login(profile: &str, login: &LoginResponse) -> Result<()> {
     let mut session = load_session(profile)?;
-    session.auth_token = Some(token.to_string());
-    session.user_id = Some(user_id.to_string());
+    session.auth_token = Some(login.access_token.clone());
+    session.user_id = Some(login.user_id.clone());
+    session.expires_at = login.expires_at.clone();
+    session.refresh_token = login.refresh_token.clone();
+    session.refresh_expires_at = login.refresh_expires_at.clone();
     store_session(profile, &session)
 }
 
+pub fn load_expires_at(profile: &str) -> Result<Option<DateTime<Utc>>> {
+    let session = load_session(profile)?;
+    Ok(session.expires_at.as_deref().and_then(parse_time))
+}
+
+// The refresh token, unless it has expired too.
+pub fn load_refresh_token(profile: &str) -> Result<Option<String>> {
+    let session = load_session(profile)?;
+    let usable = session
+        .refresh_expires_at
+        .as_deref()
+        .and_then(parse_time)
+        .is_some_and(|expires| expires > Utc::now());
+    Ok(session.refresh_token.filter(|_| usable))
+}
+
+fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
 pub fn store_email(profile: &str, email: &str) -> Result<()> {
     let mut session = load_session(profile)?;
     session.email = Some(email.to_string());
@@ -99,12 +142,32 @@ pub fn load_umk(profile: &str) -> Result<String> {
     session.umk.ok_or(MilieuError::UmkMissing)
 }
 
-pub fn store_phrase(profile: &str, phrase: &str) -> Result<()> {
+// Records a newly generated phrase, which starts out unconfirmed. `None`
+// keeps it out of the keychain.
+pub fn store_phrase(profile: &str, phrase: Option<&str>) -> Result<()> {
+    let mut session = load_session(profile)?;
+    session.phrase = phrase.map(str::to_string);
+    session.phrase_verified_at = None;
+    store_session(profile, &session)
+}
+
+pub fn forget_phrase(profile: &str) -> Result<()> {
     let mut session = load_session(profile)?;
-    session.phrase = Some(phrase.to_string());
+    session.phrase = None;
     store_session(profile, &session)
 }
 
+pub fn mark_phrase_verified(profile: &str) -> Result<()> {
+    let mut session = load_session(profile)?;
+    session.phrase_verified_at = Some(Utc::now().to_rfc3339());
+    store_session(profile, &session)
+}
+
+pub fn load_phrase_verified_at(profile: &str) -> Result<Option<DateTime<Utc>>> {
+    let session = load_session(profile)?;
+    Ok(session.phrase_verified_at.as_deref().and_then(parse_time))
+}
+
 pub fn load_phrase(profile: &str) -> Result<Option<String>> {
     let session = load_session(profile)?;
     Ok(session.phrase)
@@ -114,6 +177,9 @@ pub fn clear_auth(profile: &str) -> Result<()> {
     let mut session = load_session(profile)?;
     session.auth_token = None;
     session.user_id = None;
+    session.expires_at = None;
+    session.refresh_token = None;
+    session.refresh_expires_at = None;
     store_session(profile, &session)
 }
 
@@ -121,6 +187,7 @@ pub fn clear_umk(profile: &str) -> Result<()> {
     let mut session = load_session(profile)?;
     session.umk = None;
     session.phrase = None;
+    session.phrase_verified_at = None;
     store_session(profile, &session)
 }
 
@@ -0,0 +1,276 @@
+use crate::crypto::{decrypt_bytes, encrypt_bytes, UMK_LEN};
+use crate::error::{MilieuError, Result};
+use crate::repo::{manifest_path, milieu_dir, write_secure};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Older backups beyond this count are pruned when a new one is written.
+const KEEP_BACKUPS: usize = 10;
+const INDEX_FILE: &str = "index.json";
+const MANIFEST_FILE: &str = "manifest.toml";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupIndex {
+    pub id: String,
+    pub command: String,
+    pub repo_id: String,
+    pub created_at: String,
+    pub files: Vec<BackupFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BackupFile {
+    pub path: String,
+    // None when the file did not exist before; undo removes it.
+    pub blob: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EncryptedBlob {
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Local content about to be overwritten by a pull or checkout, encrypted
+/// with the repo key. The manifest is captured when the backup is created.
+pub struct Backup {
+    index: BackupIndex,
+    dir: PathBuf,
+    repo_key: [u8; UMK_LEN],
+    manifest: Option<Vec<u8>>,
+}
+
+impl Backup {
+    pub fn new(command: &str, repo_id: &str, repo_key: &[u8; UMK_LEN]) -> Result<Self> {
+        let now = chrono::Utc::now();
+        let id = now.format("%Y%m%dT%H%M%S%.3fZ").to_string();
+        Ok(Self {
+            dir: backups_dir()?.join(&id),
+            index: BackupIndex {
+                id,
+                command: command.to_string(),
+                repo_id: repo_id.to_string(),
+                created_at: now.to_rfc3339(),
+                files: Vec::new(),
+            },
+            repo_key: *repo_key,
+            manifest: fs::read(manifest_path()?).ok(),
+        })
+    }
+
+    pub fn save(&mut self, path: &str) -> Result<()> {
+        if self.index.files.iter().any(|file| file.path == path) {
+            return Ok(());
+        }
+        self.start()?;
+
+        let blob = match fs::read(path) {
+            Ok(data) => {
+                let name = format!("{}.json", self.index.files.len());
+                let aad = backup_aad(&self.index, path);
+                write_sealed(&self.dir.join(&name), &self.repo_key, &aad, &data)?;
+                Some(name)
+            }
+            Err(_) => None,
+        };
+        self.index.files.push(BackupFile {
+            path: path.to_string(),
+            blob,
+        });
+        self.write_index()
+    }
+
+    // A run that overwrote nothing leaves no backup, so `undo` goes back to
+    // the last command that did.
+    pub fn finish(self) -> Result<()> {
+        if self.index.files.is_empty() {
+            return Ok(());
+        }
+        self.write_index()
+    }
+
+    fn start(&self) -> Result<()> {
+        if self.dir.exists() {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)?;
+        if let Some(manifest) = &self.manifest {
+            write_secure(&self.dir.join(MANIFEST_FILE).to_string_lossy(), manifest)?;
+        }
+        Ok(())
+    }
+
+    // Written after every save so an interrupted command can still be undone.
+    fn write_index(&self) -> Result<()> {
+        let data = serde_json::to_vec_pretty(&self.index)?;
+        write_secure(&self.dir.join(INDEX_FILE).to_string_lossy(), &data)?;
+        prune_backups()
+    }
+}
+
+// Encrypted local copies, shared with the stash.
+pub fn write_sealed(path: &Path, repo_key: &[u8; UMK_LEN], aad: &[u8], data: &[u8]) -> Result<()> {
+    let (nonce, ciphertext) = encrypt_bytes(repo_key, aad, data)?;
+    let encoded = serde_json::to_vec(&EncryptedBlob { nonce, ciphertext })?;
+    write_secure(&path.to_string_lossy(), &encoded)
+}
+
+pub fn read_sealed(path: &Path, repo_key: &[u8; UMK_LEN], aad: &[u8]) -> Result<Vec<u8>> {
+    let blob: EncryptedBlob = serde_json::from_slice(&fs::read(path)?)?;
+    decrypt_bytes(repo_key, aad, &blob.nonce, &blob.ciphertext)
+}
+
+fn backup_aad(index: &BackupIndex, path: &str) -> Vec<u8> {
+    format!("backup|{}|{}|{}", index.repo_id, index.id, path).into_bytes()
+}
+
+fn backups_dir() -> Result<PathBuf> {
+    Ok(milieu_dir()?.join("backups"))
+}
+
+// Backup ids are timestamps, so name order is chronological.
+fn backup_ids() -> Result<Vec<String>> {
+    let dir = backups_dir()?;
+    let mut ids = Vec::new();
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(ids),
+    };
+    for entry in entries.flatten() {
+        if entry.path().join(INDEX_FILE).exists() {
+            if let Some(name) = entry.file_name().to_str() {
+                ids.push(name.to_string());
+            }
+        }
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+fn prune_backups() -> Result<()> {
+    let ids = backup_ids()?;
+    if ids.len() > KEEP_BACKUPS {
+        for id in &ids[..ids.len() - KEEP_BACKUPS] {
+            fs::remove_dir_all(backups_dir()?.join(id))?;
+        }
+    }
+    Ok(())
+}
+
+pub fn latest() -> Result<Option<BackupIndex>> {
+    let Some(id) = backup_ids()?.pop() else {
+        return Ok(None);
+    };
+    let data = fs::read(backups_dir()?.join(&id).join(INDEX_FILE))?;
+    Ok(Some(serde_json::from_slice(&data)?))
+}
+
+/// Restores every file (and the manifest) recorded in the backup, then
+/// removes it so the next undo goes one step further back.
+pub fn restore(index: &BackupIndex, repo_key: &[u8; UMK_LEN]) -> Result<()> {
+    let dir = backups_dir()?.join(&index.id);
+    let mut restored = Vec::new();
+    for file in &index.files {
+        let data = match &file.blob {
+            Some(name) => {
+                let aad = backup_aad(index, &file.path);
+                Some(read_sealed(&dir.join(name), repo_key, &aad)?)
+            }
+            None => None,
+        };
+        restored.push((file.path.as_str(), data));
+    }
+
+    for (path, data) in restored {
+        match data {
+            Some(data) => write_secure(path, &data)?,
+            None => match fs::remove_file(path) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => return Err(MilieuError::Io(err)),
+            },
+        }
+    }
+
+    if let Ok(manifest) = fs::read(dir.join(MANIFEST_FILE)) {
+        write_secure(&manifest_path()?.to_string_lossy(), &manifest)?;
+    }
+    fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::in_temp_project;
+
+    const KEY: [u8; UMK_LEN] = [3; UMK_LEN];
+
+    #[test]
+    fn undo_restores_files_and_the_manifest() {
+        in_temp_project(|| {
+            fs::write(".env", "A=1").unwrap();
+            fs::write(manifest_path().unwrap(), "version = 1").unwrap();
+            let mut backup = Backup::new("pull", "repo", &KEY).unwrap();
+            backup.save(".env").unwrap();
+            backup.save(".env.new").unwrap();
+            fs::write(".env", "A=2").unwrap();
+            // Only the content from before the command is kept.
+            backup.save(".env").unwrap();
+            fs::write(".env.new", "B=1").unwrap();
+            fs::write(manifest_path().unwrap(), "version = 2").unwrap();
+            backup.finish().unwrap();
+
+            let index = latest().unwrap().unwrap();
+            assert_eq!(index.command, "pull");
+            assert_eq!(index.files.len(), 2);
+            restore(&index, &KEY).unwrap();
+
+            assert_eq!(fs::read_to_string(".env").unwrap(), "A=1");
+            assert!(!Path::new(".env.new").exists());
+            assert_eq!(fs::read_to_string(manifest_path().unwrap()).unwrap(), "version = 1");
+            assert!(latest().unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn runs_that_overwrite_nothing_leave_no_backup() {
+        in_temp_project(|| {
+            Backup::new("pull", "repo", &KEY).unwrap().finish().unwrap();
+            assert!(latest().unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn restore_rejects_a_blob_moved_to_another_path() {
+        in_temp_project(|| {
+            fs::write(".env", "A=1").unwrap();
+            let mut backup = Backup::new("pull", "repo", &KEY).unwrap();
+            backup.save(".env").unwrap();
+            backup.finish().unwrap();
+
+            let mut index = latest().unwrap().unwrap();
+            index.files[0].path = ".env.prod".to_string();
+            assert!(restore(&index, &KEY).is_err());
+            assert!(!Path::new(".env.prod").exists());
+            assert!(restore(&latest().unwrap().unwrap(), &[4; UMK_LEN]).is_err());
+        });
+    }
+
+    #[test]
+    fn only_the_newest_backups_are_kept() {
+        in_temp_project(|| {
+            fs::write(".env", "A=1").unwrap();
+            let mut ids = Vec::new();
+            for _ in 0..KEEP_BACKUPS + 2 {
+                let mut backup = Backup::new("pull", "repo", &KEY).unwrap();
+                backup.save(".env").unwrap();
+                ids.push(backup.index.id.clone());
+                backup.finish().unwrap();
+                std::thread::sleep(std::time::Duration::from_millis(2));
+            }
+            assert_eq!(backup_ids().unwrap(), ids[2..]);
+        });
+    }
+}
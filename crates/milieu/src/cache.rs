@@ -0,0 +1,233 @@
+use crate::api::{ApiClient, ChunkResponse, LatestObject, LatestQuery, ObjectResponse};
+use crate::error::{MilieuError, Result};
+use crate::repo::{milieu_dir, write_secure};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Downloaded ciphertext is stored under `.milieu/objects/blobs`, addressed by
+// the blake3 hash of the ciphertext. `refs` maps repo/branch/path/version
+// (and "latest") to a blob. Nothing here is plaintext.
+
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+// Dry runs read the cache but never add to it.
+pub fn set_read_only() {
+    READ_ONLY.store(true, Ordering::Relaxed);
+}
+
+fn objects_dir() -> Result<PathBuf> {
+    Ok(milieu_dir()?.join("objects"))
+}
+
+fn ref_path(parts: &[&str]) -> Result<PathBuf> {
+    let key = blake3::hash(parts.join("|").as_bytes()).to_hex().to_string();
+    Ok(objects_dir()?.join("refs").join(key))
+}
+
+fn blob_path(hash: &str) -> Result<PathBuf> {
+    Ok(objects_dir()?.join("blobs").join(format!("{}.json", hash)))
+}
+
+fn ciphertext_hash(ciphertext: &str) -> String {
+    blake3::hash(ciphertext.as_bytes()).to_hex().to_string()
+}
+
+fn write_file(path: PathBuf, data: &[u8]) -> Result<()> {
+    write_secure(&path.to_string_lossy(), data)
+}
+
+fn store<T: Serialize>(refs: &[&[&str]], ciphertext: &str, value: &T) -> Result<()> {
+    if READ_ONLY.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let hash = ciphertext_hash(ciphertext);
+    let blob = blob_path(&hash)?;
+    if !blob.exists() {
+        write_file(blob, &serde_json::to_vec(value)?)?;
+    }
+    for parts in refs {
+        write_file(ref_path(parts)?, hash.as_bytes())?;
+    }
+    Ok(())
+}
+
+// A blob whose ciphertext no longer matches its address is treated as missing.
+fn load<T: DeserializeOwned>(parts: &[&str], ciphertext: impl Fn(&T) -> &str) -> Option<T> {
+    let hash = fs::read_to_string(ref_path(parts).ok()?).ok()?;
+    let data = fs::read(blob_path(hash.trim()).ok()?).ok()?;
+    let value: T = serde_json::from_slice(&data).ok()?;
+    (ciphertext_hash(ciphertext(&value)) == hash.trim()).then_some(value)
+}
+
+pub fn store_object(repo_id: &str, branch: &str, object: &ObjectResponse) -> Result<()> {
+    let Some(version) = object.version else {
+        return Ok(());
+    };
+    let version = version.to_string();
+    store(
+        &[
+            &[repo_id, branch, &object.path, &version],
+            &[repo_id, branch, &object.path, "latest"],
+        ],
+        &object.ciphertext,
+        object,
+    )
+}
+
+pub fn cached_version(repo_id: &str, branch: &str, path: &str, version: u32) -> Option<ObjectResponse> {
+    load(&[repo_id, branch, path, &version.to_string()], |obj: &ObjectResponse| {
+        &obj.ciphertext
+    })
+}
+
+pub fn cached_latest(repo_id: &str, branch: &str, path: &str) -> Option<ObjectResponse> {
+    load(&[repo_id, branch, path, "latest"], |obj: &ObjectResponse| {
+        &obj.ciphertext
+    })
+}
+
+/// Latest object for a file. Online, the server is asked only whether the
+/// cached copy is still current; offline, the last seen version is returned.
+pub async fn latest(
+    client: &ApiClient,
+    repo_id: &str,
+    branch: &str,
+    path: &str,
+) -> Result<Option<ObjectResponse>> {
+    let cached = cached_latest(repo_id, branch, path);
+    if client.is_offline() {
+        return Ok(cached);
+    }
+    let known_hash = cached.as_ref().map(known_hash);
+    let result = client
+        .get_latest_if_changed(repo_id, branch, path, known_hash.as_deref())
+        .await?;
+    resolve(repo_id, branch, result, cached)
+}
+
+/// Latest objects for several files of one branch, in the order given. A
+/// single batch request is used when the server supports it.
+pub async fn latest_many(
+    client: &ApiClient,
+    repo_id: &str,
+    branch: &str,
+    paths: &[String],
+) -> Result<Vec<Option<ObjectResponse>>> {
+    let cached: Vec<Option<ObjectResponse>> = paths
+        .iter()
+        .map(|path| cached_latest(repo_id, branch, path))
+        .collect();
+    if client.is_offline() || paths.is_empty() {
+        return Ok(cached);
+    }
+
+    let queries: Vec<LatestQuery> = paths
+        .iter()
+        .zip(&cached)
+        .map(|(path, cached)| LatestQuery {
+            path: path.clone(),
+            known_hash: cached.as_ref().map(known_hash),
+            since_version: cached.as_ref().and_then(|obj| obj.version),
+        })
+        .collect();
+    let Some(results) = client.get_latest_batch(repo_id, branch, &queries).await? else {
+        return crate::commands::run_bounded(paths.iter().collect(), |path| {
+            latest(client, repo_id, branch, path)
+        })
+        .await
+        .into_iter()
+        .collect();
+    };
+    results
+        .into_iter()
+        .zip(cached)
+        .map(|(result, cached)| resolve(repo_id, branch, result, cached))
+        .collect()
+}
+
+fn known_hash(object: &ObjectResponse) -> String {
+    object
+        .ciphertext_hash
+        .clone()
+        .unwrap_or_else(|| ciphertext_hash(&object.ciphertext))
+}
+
+fn resolve(
+    repo_id: &str,
+    branch: &str,
+    result: LatestObject,
+    cached: Option<ObjectResponse>,
+) -> Result<Option<ObjectResponse>> {
+    match result {
+        LatestObject::NotModified => Ok(cached),
+        LatestObject::Missing => Ok(None),
+        LatestObject::Found(object) => {
+            store_object(repo_id, branch, &object)?;
+            Ok(Some(object))
+        }
+    }
+}
+
+/// A specific version; versions never change, so a cached copy is always used.
+pub async fn version(
+    client: &ApiClient,
+    repo_id: &str,
+    branch: &str,
+    path: &str,
+    version: u32,
+) -> Result<ObjectResponse> {
+    if let Some(object) = cached_version(repo_id, branch, path, version) {
+        return Ok(object);
+    }
+    if client.is_offline() {
+        return Err(MilieuError::CommandFailed(format!(
+            "{}@v{} is not in the local cache",
+            path, version
+        )));
+    }
+    let object = client.get_version(repo_id, branch, path, version).await?;
+    store_object(repo_id, branch, &object)?;
+    Ok(object)
+}
+
+pub fn store_chunk(
+    repo_id: &str,
+    branch: &str,
+    path: &str,
+    version: u32,
+    chunk: &ChunkResponse,
+) -> Result<()> {
+    let version = version.to_string();
+    let index = format!("chunk{}", chunk.index);
+    store(
+        &[&[repo_id, branch, path, &version, &index]],
+        &chunk.ciphertext,
+        chunk,
+    )
+}
+
+pub async fn chunk(
+    client: &ApiClient,
+    repo_id: &str,
+    branch: &str,
+    path: &str,
+    version: u32,
+    index: u32,
+) -> Result<ChunkResponse> {
+    let key = [repo_id, branch, path, &version.to_string(), &format!("chunk{}", index)];
+    if let Some(chunk) = load(&key, |chunk: &ChunkResponse| &chunk.ciphertext) {
+        return Ok(chunk);
+    }
+    if client.is_offline() {
+        return Err(MilieuError::CommandFailed(format!(
+            "chunk {} of {}@v{} is not in the local cache",
+            index, path, version
+        )));
+    }
+    let chunk = client.get_chunk(repo_id, branch, path, version, index).await?;
+    store_chunk(repo_id, branch, path, version, &chunk)?;
+    Ok(chunk)
+}
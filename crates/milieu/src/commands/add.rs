@@ -1,19 +1,19 @@
 use crate::error::Result;
 use crate::manifest::Manifest;
-use crate::repo::{manifest_path, validate_env_path};
+use crate::repo::manifest_path;
 use crate::style;
 
 pub fn run(path: &str, tag: Option<String>, branch_override: Option<String>) -> Result<()> {
-    validate_env_path(path)?;
     let manifest_path = manifest_path()?;
     let mut manifest = Manifest::load(&manifest_path)?;
+    manifest.validate_tracked(path)?;
 
     let branch_name = branch_override.unwrap_or_else(|| manifest.active_branch.clone());
     crate::commands::print_scope_branch(&manifest, &branch_name);
     let branch = manifest.find_branch_mut(&branch_name)?;
 
     if branch.files.iter().any(|entry| entry.path() == path) {
-        println!(
+        say!(
             "{}",
             style::paint(style::YELLOW, &format!("already tracked: {}", path))
         );
@@ -26,7 +26,7 @@ pub fn run(path: &str, tag: Option<String>, branch_override: Option<String>) ->
     ));
 
     manifest.save(&manifest_path)?;
-    println!(
+    say!(
         "{}",
         style::paint(style::GREEN, &format!("added {} to {} (+tracked)", path, branch_name))
     );
@@ -3,7 +3,7 @@ use crate::auth;
 use crate::config::Config;
 use crate::error::{MilieuError, Result};
 use crate::manifest::{Branch, FileEntry, Manifest};
-use crate::repo::{manifest_path, validate_env_path};
+use crate::repo::manifest_path;
 use crate::style;
 
 pub fn add(name: &str, files: Vec<String>, tags: Vec<String>) -> Result<()> {
@@ -20,7 +20,7 @@ pub fn add(name: &str, files: Vec<String>, tags: Vec<String>) -> Result<()> {
     manifest.ensure_unique_branch(name)?;
     let mut entries = Vec::new();
     for (idx, file) in files.into_iter().enumerate() {
-        validate_env_path(&file)?;
+        manifest.validate_tracked(&file)?;
         let tag = tags.get(idx).cloned();
         entries.push(FileEntry::new(file, tag));
     }
@@ -30,7 +30,7 @@ pub fn add(name: &str, files: Vec<String>, tags: Vec<String>) -> Result<()> {
     });
     crate::commands::print_scope_repo(&manifest);
     manifest.save(&path)?;
-    println!(
+    say!(
         "{}",
         style::paint(style::GREEN, &format!("added branch {}", name))
     );
@@ -52,7 +52,7 @@ pub fn remove(name: &str) -> Result<()> {
     }
     crate::commands::print_scope_repo(&manifest);
     manifest.save(&path)?;
-    println!(
+    say!(
         "{}",
         style::paint(style::PEACH, &format!("removed branch {}", name))
     );
@@ -66,7 +66,7 @@ pub fn set_default(name: &str) -> Result<()> {
     manifest.active_branch = name.to_string();
     crate::commands::print_scope_repo(&manifest);
     manifest.save(&path)?;
-    println!(
+    say!(
         "{}",
         style::paint(style::GREEN, &format!("active branch set to {}", name))
     );
@@ -77,15 +77,22 @@ pub fn list() -> Result<()> {
     let path = manifest_path()?;
     let manifest = Manifest::load(&path)?;
     crate::commands::print_scope_repo(&manifest);
+    if crate::output::is_json() {
+        let names: Vec<&str> = manifest.branches.iter().map(|b| b.name.as_str()).collect();
+        return crate::output::emit(&serde_json::json!({
+            "active": manifest.active_branch,
+            "branches": names,
+        }));
+    }
 
-    println!("{}", style::bold(style::MAUVE, "branches:"));
+    say!("{}", style::bold(style::MAUVE, "branches:"));
     for branch in &manifest.branches {
         let label = if branch.name == manifest.active_branch {
             format!("* {}", branch.name)
         } else {
             format!("  {}", branch.name)
         };
-        println!("{}", style::paint(style::TEXT, &label));
+        say!("{}", style::paint(style::TEXT, &label));
     }
     Ok(())
 }
@@ -1,11 +1,11 @@
-use crate::api::ApiClient;
+use crate::api::{ApiClient, ObjectResponse};
 use crate::auth;
-use crate::config::Config;
-use crate::crypto::{aad_for, decrypt_bytes};
+use crate::cache;
 use crate::error::{MilieuError, Result};
 use crate::keys;
 use crate::manifest::Manifest;
-use crate::repo::{manifest_path, validate_env_path};
+use crate::objects::{self, ObjectScope};
+use crate::repo::manifest_path;
 use crate::style;
 use similar::TextDiff;
 use std::fs;
@@ -15,31 +15,39 @@ pub async fn run(
     path: Option<String>,
     branch_override: Option<String>,
     version: Option<u32>,
+    offline: bool,
 ) -> Result<()> {
     let manifest = Manifest::load(&manifest_path()?)?;
-    let branch_name = branch_override.unwrap_or_else(|| manifest.active_branch.clone());
+    let branch_name = match branch_override {
+        Some(name) => name,
+        None => crate::commands::default_branch(&manifest)?,
+    };
     let branch = manifest.find_branch(&branch_name)?;
     crate::commands::print_scope_branch(&manifest, &branch_name);
-
-    let config = Config::load()?;
-    let mut base_url = config.base_url_for(profile)?;
-    if let Some(remote) = &manifest.remote {
-        if let Some(url) = &remote.base_url {
-            base_url = url.clone();
-        }
+    if offline {
+        crate::commands::print_offline_notice();
     }
 
-    let token = auth::load_auth_token(profile)?;
-    let client = ApiClient::new(&base_url, Some(token))?;
+    let base_url = crate::commands::repo_base_url(profile, &manifest)?;
+
+    let token = if offline {
+        auth::load_auth_token(profile).ok()
+    } else {
+        Some(auth::load_auth_token(profile)?)
+    };
+    let client = ApiClient::new(&base_url, token)?.with_offline(offline);
     let repo_key = keys::get_or_fetch_repo_key(profile, &client, &manifest.repo_id).await?;
 
     let entries: Vec<_> = match path {
         Some(target) => branch
             .files
             .iter()
-            .filter(|entry| entry.path() == target)
+            .filter(|entry| {
+                entry.path() == target || entry.matched_by.as_deref() == Some(target.as_str())
+            })
+            .filter(|entry| !entry.is_pattern())
             .collect(),
-        None => branch.files.iter().collect(),
+        None => branch.files.iter().filter(|entry| !entry.is_pattern()).collect(),
     };
 
     if entries.is_empty() {
@@ -48,27 +56,56 @@ pub async fn run(
 
     let mut printed = false;
     let mut header_printed = false;
+    let mut changes = Vec::new();
 
     for entry in entries {
         let file_path = entry.path();
-        validate_env_path(file_path)?;
+        manifest.validate_path(file_path)?;
+        let scope = ObjectScope {
+            repo_id: &manifest.repo_id,
+            branch: &branch.name,
+            path: file_path,
+            tag: entry.tag(),
+        };
+
+        if manifest.is_opaque(file_path) {
+            let local = fs::read(file_path).ok();
+            let remote = match remote_object(&client, &manifest, &branch.name, file_path, version).await? {
+                Some(obj) => Some(objects::open(&client, &repo_key, &scope, &obj).await?),
+                None => None,
+            };
+            if local.is_none() && remote.is_none() {
+                continue;
+            }
+            say!(
+                "{}",
+                style::bold(style::MAUVE, &format!("FILE: {} (opaque)", file_path))
+            );
+            let describe = |data: &Option<Vec<u8>>| match data {
+                Some(bytes) => crate::commands::describe_blob(bytes),
+                None => "missing".to_string(),
+            };
+            changes.push(serde_json::json!({
+                "path": file_path,
+                "opaque": true,
+                "status": change_status(local.is_some(), remote.is_some(), local == remote),
+            }));
+            if local == remote {
+                say!("{}", style::paint(style::GREEN, "NO DIFF"));
+            } else {
+                say!("{}", style::paint(style::RED, &format!("-remote {}", describe(&remote))));
+                say!("{}", style::paint(style::GREEN, &format!("+local  {}", describe(&local))));
+            }
+            printed = true;
+            continue;
+        }
 
         let local_text = fs::read_to_string(file_path).ok();
-        let remote_obj = match version {
-            Some(ver) => client
-                .get_version(&manifest.repo_id, &branch.name, file_path, ver)
-                .await
-                .map(Some)?,
-            None => client
-                .get_latest(&manifest.repo_id, &branch.name, file_path)
-                .await?,
-        };
+        let remote_obj = remote_object(&client, &manifest, &branch.name, file_path, version).await?;
 
         let remote_text = match remote_obj {
             Some(ref obj) => {
-                let schema_version = obj.schema_version;
-                let aad = aad_for(schema_version, &manifest.repo_id, &branch.name, file_path, entry.tag());
-                let plaintext = decrypt_bytes(&repo_key, &aad, &obj.nonce, &obj.ciphertext)?;
+                let plaintext = objects::open(&client, &repo_key, &scope, obj).await?;
                 Some(String::from_utf8_lossy(&plaintext).to_string())
             }
             None => None,
@@ -78,7 +115,7 @@ pub async fn run(
             continue;
         }
 
-        println!(
+        say!(
             "{}",
             style::bold(style::MAUVE, &format!("FILE: {}", file_path))
         );
@@ -87,15 +124,28 @@ pub async fn run(
         let local_body = local_text.as_deref().unwrap_or("");
 
         let diff = TextDiff::from_lines(remote_body, local_body);
-        if diff.ratio() == 1.0 {
-            println!("{}", style::paint(style::GREEN, "NO DIFF"));
+        let same = diff.ratio() == 1.0;
+        let mut change = serde_json::json!({
+            "path": file_path,
+            "opaque": false,
+            "status": change_status(local_text.is_some(), remote_text.is_some(), same),
+        });
+        if !same {
+            change["diff"] = serde_json::json!(diff
+                .unified_diff()
+                .header("remote", "local")
+                .to_string());
+        }
+        changes.push(change);
+        if same {
+            say!("{}", style::paint(style::GREEN, "NO DIFF"));
             printed = true;
             continue;
         }
 
         if !header_printed {
-            println!("{}", style::paint(style::SUBTEXT1, "--- remote"));
-            println!("{}", style::paint(style::SUBTEXT1, "+++ local"));
+            say!("{}", style::paint(style::SUBTEXT1, "--- remote"));
+            say!("{}", style::paint(style::SUBTEXT1, "+++ local"));
             header_printed = true;
         }
 
@@ -118,20 +168,52 @@ pub async fn run(
     }
 
     if !printed {
-        println!("{}", style::paint(style::SUBTEXT1, "no diffs to show"));
+        say!("{}", style::paint(style::SUBTEXT1, "no diffs to show"));
+    }
+    if crate::output::is_json() {
+        crate::output::emit(&serde_json::json!({
+            "branch": branch.name,
+            "version": version,
+            "files": changes,
+        }))?;
     }
 
     Ok(())
 }
 
+// Named from the local side: a file only on the server shows as deleted.
+fn change_status(local: bool, remote: bool, same: bool) -> &'static str {
+    match (local, remote) {
+        (true, false) => "added",
+        (false, true) => "deleted",
+        _ if same => "unchanged",
+        _ => "modified",
+    }
+}
+
 // path validation centralized in repo::validate_env_path
 
+async fn remote_object(
+    client: &ApiClient,
+    manifest: &Manifest,
+    branch: &str,
+    path: &str,
+    version: Option<u32>,
+) -> Result<Option<ObjectResponse>> {
+    match version {
+        Some(ver) => cache::version(client, &manifest.repo_id, branch, path, ver)
+            .await
+            .map(Some),
+        None => cache::latest(client, &manifest.repo_id, branch, path).await,
+    }
+}
+
 fn print_colored_diff_line(prefix: char, line: &str) {
     let content = line.trim_end_matches('\n');
     match prefix {
-        '+' => println!("{}", style::paint(style::GREEN, &format!("+{}", content))),
-        '-' => println!("{}", style::paint(style::RED, &format!("-{}", content))),
-        ' ' => println!("{}", style::paint(style::SUBTEXT1, &format!(" {}", content))),
-        _ => println!("{}", style::paint(style::SUBTEXT1, content)),
+        '+' => say!("{}", style::paint(style::GREEN, &format!("+{}", content))),
+        '-' => say!("{}", style::paint(style::RED, &format!("-{}", content))),
+        ' ' => say!("{}", style::paint(style::SUBTEXT1, &format!(" {}", content))),
+        _ => say!("{}", style::paint(style::SUBTEXT1, content)),
     }
 }
@@ -1,27 +1,32 @@
 use crate::api::ApiClient;
 use crate::auth;
-use crate::config::Config;
-use crate::crypto::{aad_for, decrypt_bytes};
+use crate::backup::Backup;
+use crate::cache;
 use crate::error::{MilieuError, Result};
 use crate::keys;
 use crate::manifest::Manifest;
-use crate::repo::manifest_path;
+use crate::objects::{self, ObjectScope};
+use crate::repo::{manifest_path, write_secure};
 use crate::style;
 use std::fs;
-use std::io::Write;
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
 
 pub async fn run(
     profile: &str,
     path: String,
     version: u32,
     branch_override: Option<String>,
+    dry_run: bool,
 ) -> Result<()> {
     let manifest = Manifest::load(&manifest_path()?)?;
-    let branch_name = branch_override.unwrap_or_else(|| manifest.active_branch.clone());
+    let branch_name = match branch_override {
+        Some(name) => name,
+        None => crate::commands::default_branch(&manifest)?,
+    };
     let branch = manifest.find_branch(&branch_name)?;
     crate::commands::print_scope_branch(&manifest, &branch_name);
+    if dry_run {
+        crate::commands::print_dry_run_notice();
+    }
 
     let entry = branch
         .files
@@ -29,44 +34,49 @@ pub async fn run(
         .find(|f| f.path() == path)
         .ok_or_else(|| MilieuError::CommandFailed("file not tracked".to_string()))?;
 
-    let config = Config::load()?;
-    let mut base_url = config.base_url_for(profile)?;
-    if let Some(remote) = &manifest.remote {
-        if let Some(url) = &remote.base_url {
-            base_url = url.clone();
-        }
-    }
+    let base_url = crate::commands::repo_base_url(profile, &manifest)?;
 
     let token = auth::load_auth_token(profile)?;
     let client = ApiClient::new(&base_url, Some(token))?;
     let repo_key = keys::get_or_fetch_repo_key(profile, &client, &manifest.repo_id).await?;
 
-    let remote = client
-        .get_version(&manifest.repo_id, &branch_name, &path, version)
+    let remote = cache::version(&client, &manifest.repo_id, &branch_name, &path, version)
         .await?;
 
-    let schema_version = remote.schema_version;
-    let aad = aad_for(schema_version, &manifest.repo_id, &branch_name, &path, entry.tag());
-    let plaintext = decrypt_bytes(&repo_key, &aad, &remote.nonce, &remote.ciphertext)?;
+    let scope = ObjectScope {
+        repo_id: &manifest.repo_id,
+        branch: &branch_name,
+        path: &path,
+        tag: entry.tag(),
+    };
+    let plaintext = objects::open(&client, &repo_key, &scope, &remote).await?;
+
+    if dry_run {
+        let local = fs::read(&path).ok();
+        let local_modified = match (&local, entry.last_synced_hash.as_deref()) {
+            (Some(data), Some(base)) => blake3::hash(data).to_hex().as_str() != base,
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+        let target = format!("{}@v{}", path, version);
+        if local.as_deref() == Some(plaintext.as_slice()) {
+            crate::commands::print_planned("unchanged", &target, None);
+        } else if local_modified {
+            crate::commands::print_planned("would checkout", &target, Some("overwrites local changes"));
+        } else {
+            crate::commands::print_planned("would checkout", &target, None);
+        }
+        return Ok(());
+    }
+
+    let mut backup = Backup::new("checkout", &manifest.repo_id, &repo_key)?;
+    backup.save(&path)?;
     write_secure(&path, &plaintext)?;
+    backup.finish()?;
 
-    println!(
+    say!(
         "{}",
         style::paint(style::GREEN, &format!("checked out {}@v{}", path, version))
     );
     Ok(())
 }
-
-fn write_secure(path: &str, data: &[u8]) -> Result<()> {
-    if let Some(parent) = Path::new(path).parent() {
-        if !parent.as_os_str().is_empty() {
-            fs::create_dir_all(parent)?;
-        }
-    }
-    let mut file = fs::File::create(path)?;
-    file.write_all(data)?;
-    let mut perms = file.metadata()?.permissions();
-    perms.set_mode(0o600);
-    fs::set_permissions(path, perms)?;
-    Ok(())
-}
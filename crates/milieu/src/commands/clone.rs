@@ -39,7 +39,7 @@ pub async fn run(profile: &str, repo_name: Option<String>) -> Result<()> {
 
     crate::commands::print_scope_repo(&manifest);
     manifest.save(&manifest_path)?;
-    println!(
+    say!(
         "{}",
         style::paint(style::GREEN, &format!("cloned repo '{}'", manifest.repo_name))
     );
@@ -12,7 +12,7 @@ use std::fs;
 pub async fn run(profile: &str, name_override: Option<String>) -> Result<()> {
     let manifest_path = manifest_path()?;
     if manifest_path.exists() {
-        println!(
+        say!(
             "{}",
             style::paint(
                 style::YELLOW,
@@ -48,6 +48,8 @@ pub async fn run(profile: &str, name_override: Option<String>) -> Result<()> {
         repo_id: repo.repo_id,
         repo_name: repo.name,
         active_branch: "dev".to_string(),
+        allowed_files: Vec::new(),
+        max_repo_bytes: None,
         branches: vec![Branch {
             name: "dev".to_string(),
             files: Vec::new(),
@@ -72,7 +74,7 @@ pub async fn run(profile: &str, name_override: Option<String>) -> Result<()> {
     crate::commands::print_scope_repo(&manifest);
     manifest.save(&manifest_path)?;
     client.put_manifest(&manifest).await?;
-    println!(
+    say!(
         "{}",
         style::paint(
             style::GREEN,
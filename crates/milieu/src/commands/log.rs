@@ -1,6 +1,5 @@
 use crate::api::ApiClient;
 use crate::auth;
-use crate::config::Config;
 use crate::error::{MilieuError, Result};
 use crate::manifest::Manifest;
 use crate::repo::manifest_path;
@@ -12,13 +11,7 @@ pub async fn run(profile: &str, path: String, branch_override: Option<String>) -
     let _branch = manifest.find_branch(&branch_name)?;
     crate::commands::print_scope_branch(&manifest, &branch_name);
 
-    let config = Config::load()?;
-    let mut base_url = config.base_url_for(profile)?;
-    if let Some(remote) = &manifest.remote {
-        if let Some(url) = &remote.base_url {
-            base_url = url.clone();
-        }
-    }
+    let base_url = crate::commands::repo_base_url(profile, &manifest)?;
 
     let token = auth::load_auth_token(profile)?;
     let client = ApiClient::new(&base_url, Some(token))?;
@@ -27,24 +20,31 @@ pub async fn run(profile: &str, path: String, branch_override: Option<String>) -
     if history.is_empty() {
         return Err(MilieuError::CommandFailed("no history for file".to_string()));
     }
+    if crate::output::is_json() {
+        return crate::output::emit(&serde_json::json!({
+            "path": path,
+            "branch": branch_name,
+            "history": history,
+        }));
+    }
 
-    println!(
+    say!(
         "{}",
         style::bold(style::MAUVE, &format!("history: {}", path))
     );
-    println!(
+    say!(
         "{}  {}",
         style::bold(style::MAUVE, "version"),
         style::bold(style::MAUVE, "created_at")
     );
-    println!(
+    say!(
         "{}  {}",
         style::paint(style::SUBTEXT1, "-------"),
         style::paint(style::SUBTEXT1, "----------")
     );
 
     for entry in history {
-        println!(
+        say!(
             "{}  {}",
             style::paint(style::TEXT, &entry.version.to_string()),
             style::paint(style::SUBTEXT1, &entry.created_at)
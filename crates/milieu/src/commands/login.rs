@@ -1,24 +1,140 @@
-use crate::api::{ApiClient, LoginRequest, UmkRequest};
+use crate::api::{ApiClient, LoginRequest, UmkRequest, UmkResponse};
 use crate::auth;
-use crate::commands::{prompt, prompt_password};
+use crate::commands::{prompt, prompt_account_password, prompt_email, prompt_phrase};
 use crate::config::Config;
-use crate::crypto::{decrypt_umk_blob, derive_key, encrypt_umk_blob, generate_umk, KdfParams};
-use crate::error::Result;
+use crate::crypto::{
+    decode_key, decrypt_umk_blob, derive_key, derive_keypair_from_umk, encrypt_umk_blob,
+    generate_umk, KdfParams, UMK_LEN,
+};
+use crate::error::{ApiError, MilieuError, Result};
 use crate::keys;
+use crate::manifest::Manifest;
+use crate::repo::manifest_path;
 use crate::style;
 use bip39::{Language, Mnemonic};
+use chrono::{Duration, Utc};
 use rand_core::{OsRng, RngCore};
 use tracing::debug;
 
-pub async fn run(profile_override: Option<String>) -> Result<()> {
+// Refresh this far ahead of expiry, and warn this far ahead when the session
+// cannot be refreshed.
+const REFRESH_BEFORE_HOURS: i64 = 24;
+const WARN_BEFORE_DAYS: i64 = 3;
+
+/// Checks the stored session before a command talks to the server. An expired
+/// or nearly expired session is swapped for a new one with the refresh token;
+/// failing that, an expired session means logging in again. `in_repo` says the
+/// command calls the server named by the project manifest.
+pub async fn ensure_session(profile: &str, in_repo: bool) -> Result<()> {
+    if crate::token::active()?.is_some() || auth::load_auth_token(profile).is_err() {
+        return Ok(());
+    }
+    // Sessions from older servers carry no expiry; the server has the last word.
+    let Some(expires_at) = auth::load_expires_at(profile)? else {
+        return Ok(());
+    };
+    let remaining = expires_at - Utc::now();
+    // A dry run cannot store new tokens, and the server may retire the old
+    // ones on refresh, so it makes do with the current session.
+    if crate::keychain::is_read_only() {
+        return if remaining > Duration::zero() {
+            Ok(())
+        } else {
+            Err(session_expired())
+        };
+    }
+    let mut refresh_token = auth::load_refresh_token(profile)?;
+    if remaining < Duration::hours(REFRESH_BEFORE_HOURS) {
+        if let Some(token) = &refresh_token {
+            let client = ApiClient::new(&session_base_url(profile, in_repo)?, None)?;
+            match client.refresh(token).await {
+                Ok(login) => {
+                    auth::store_login(profile, &login)?;
+                    debug!("refreshed session");
+                    return Ok(());
+                }
+                // Revoked or expired refresh token.
+                Err(MilieuError::SessionExpired(_)) => refresh_token = None,
+                // Offline: the current token still works for now.
+                Err(err) if remaining > Duration::zero() => {
+                    debug!(error = ?err, "session refresh failed");
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    if remaining > Duration::zero() {
+        if refresh_token.is_none() && remaining < Duration::days(WARN_BEFORE_DAYS) {
+            say!(
+                "{}",
+                style::paint(
+                    style::PEACH,
+                    &format!(
+                        "warning: session expires in {}; run `milieu login` to renew it",
+                        format_remaining(remaining)
+                    )
+                )
+            );
+        }
+        return Ok(());
+    }
+
+    if !crate::commands::is_interactive() {
+        return Err(session_expired());
+    }
+    let answer = prompt("Session expired. Log in again? [Y/n] ")?.to_lowercase();
+    if answer == "n" || answer == "no" {
+        return Err(session_expired());
+    }
+    run(Some(profile.to_string()), false).await
+}
+
+// A missing manifest falls back to the profile; the command reports it.
+fn session_base_url(profile: &str, in_repo: bool) -> Result<String> {
+    let manifest = if in_repo {
+        Manifest::load(&manifest_path()?).ok()
+    } else {
+        None
+    };
+    match manifest {
+        Some(manifest) => crate::commands::repo_base_url(profile, &manifest),
+        None => Config::load()?.base_url_for(profile),
+    }
+}
+
+fn session_expired() -> MilieuError {
+    MilieuError::SessionExpired(ApiError {
+        action: "session".to_string(),
+        code: "token_expired".to_string(),
+        message: "your session expired".to_string(),
+        hint: None,
+        retry_after: None,
+    })
+}
+
+// "3 days", "5 hours", "12 minutes"
+pub fn format_remaining(remaining: Duration) -> String {
+    let (value, unit) = if remaining.num_days() > 0 {
+        (remaining.num_days(), "day")
+    } else if remaining.num_hours() > 0 {
+        (remaining.num_hours(), "hour")
+    } else {
+        (remaining.num_minutes().max(0), "minute")
+    };
+    format!("{} {}{}", value, unit, if value == 1 { "" } else { "s" })
+}
+
+pub async fn run(profile_override: Option<String>, skip_verify: bool) -> Result<()> {
     crate::commands::print_scope_user(profile_override.as_deref().unwrap_or("default"));
     let mut config = Config::load()?;
     let base_url = config.base_url_for(profile_override.as_deref().unwrap_or("default"))?;
 
-    let email = prompt("Email: ")?;
-    let password = prompt_password("Password: ")?;
+    let email = prompt_email()?;
+    let password = prompt_account_password("Password: ")?;
     let normalized_email = email.trim().to_lowercase();
     let profile = profile_override.unwrap_or_else(|| normalized_email.clone());
+    crate::keychain::configure(config.secret_store_for(&profile)?)?;
 
     let client = ApiClient::new(&base_url, None)?;
     let host = hostname::get()
@@ -34,12 +150,13 @@ pub async fn run(profile_override: Option<String>) -> Result<()> {
         })
         .await?;
 
-    auth::store_auth(&profile, &login.access_token, &login.user_id)?;
+    auth::store_login(&profile, &login)?;
     auth::store_email(&profile, &normalized_email)?;
 
     let token_client = ApiClient::new(&base_url, Some(login.access_token))?;
     let umk = match token_client.get_umk().await? {
         None => {
+            crate::commands::user::require_phrase_quiz(skip_verify)?;
             let umk = generate_umk();
             let mut entropy = [0u8; 16];
             OsRng.fill_bytes(&mut entropy);
@@ -55,39 +172,30 @@ pub async fn run(profile_override: Option<String>) -> Result<()> {
                 version: 1,
             };
             token_client.put_umk(&request).await?;
-            auth::store_phrase(&profile, &phrase)?;
+            auth::store_phrase(&profile, config.store_phrase.then_some(phrase.as_str()))?;
 
-            println!(
+            say!(
                 "{}",
                 style::paint(
                     style::YELLOW,
                     "Recovery phrase (save this for new devices):"
                 )
             );
-            println!("{}", style::bold(style::LAVENDER, &phrase));
+            say!("{}", style::bold(style::LAVENDER, &phrase));
+            crate::commands::user::quiz_phrase(&profile, &phrase, skip_verify)?;
             umk
         }
-        Some(response) => {
-            let params: KdfParams = serde_json::from_value(response.kdf_params)?;
-            let phrase = match auth::load_phrase(&profile)? {
-                Some(value) => value,
-                None => prompt_password("Recovery phrase: ")?,
-            };
-            let pdk = derive_key(&phrase, &params)?;
-            match decrypt_umk_blob(&pdk, &response.encrypted_umk) {
-                Ok(umk) => umk,
-                Err(err) => {
-                    debug!(error = ?err, "failed to decrypt UMK");
-                    return Err(crate::error::MilieuError::CommandFailed(
-                        "recovery phrase incorrect or does not match this account; run `milieu phrase show` to confirm, or `milieu logout` and login again with the correct phrase".to_string(),
-                    ));
-                }
-            }
-        }
+        Some(response) => match cached_umk(&profile, &token_client).await? {
+            Some(umk) => umk,
+            None => unlock_umk(&profile, response)?,
+        },
     };
 
     let umk_b64 = crate::crypto::encode_key(&umk);
     auth::store_umk(&profile, &umk_b64)?;
+    if !config.store_phrase {
+        auth::forget_phrase(&profile)?;
+    }
     config.active_profile = profile.clone();
     config.set_base_url(&profile, base_url);
     config.save()?;
@@ -100,9 +208,9 @@ pub async fn run(profile_override: Option<String>) -> Result<()> {
     let warning = login
         .warning
         .unwrap_or_else(|| "Beta testing: use at your own risk.".to_string());
-    println!("{}", style::paint(style::YELLOW, &warning));
+    say!("{}", style::paint(style::YELLOW, &warning));
 
-    println!(
+    say!(
         "{}",
         style::paint(
             style::GREEN,
@@ -111,3 +219,43 @@ pub async fn run(profile_override: Option<String>) -> Result<()> {
     );
     Ok(())
 }
+
+// A UMK still cached for this profile (say, after `logout` kept local data)
+// is reused when it matches the account's public key, so the phrase is only
+// asked for when there is nothing usable on this machine.
+async fn cached_umk(profile: &str, client: &ApiClient) -> Result<Option<[u8; UMK_LEN]>> {
+    let Ok(encoded) = auth::load_umk(profile) else {
+        return Ok(None);
+    };
+    let umk = decode_key(&encoded)?;
+    let keypair = derive_keypair_from_umk(&umk)?;
+    match client.get_user_key().await? {
+        Some(key) if key.public_key == keypair.public_key_b64 => Ok(Some(umk)),
+        _ => Ok(None),
+    }
+}
+
+fn unlock_umk(profile: &str, response: UmkResponse) -> Result<[u8; UMK_LEN]> {
+    let params: KdfParams = serde_json::from_value(response.kdf_params)?;
+    let stored = auth::load_phrase(profile)?;
+    let typed = stored.is_none();
+    let phrase = match stored {
+        Some(value) => value,
+        None => prompt_phrase("Recovery phrase: ")?,
+    };
+    let pdk = derive_key(&phrase, &params)?;
+    match decrypt_umk_blob(&pdk, &response.encrypted_umk) {
+        // Typing the whole phrase from memory or paper proves it was kept.
+        Ok(umk) if typed => {
+            auth::mark_phrase_verified(profile)?;
+            Ok(umk)
+        }
+        Ok(umk) => Ok(umk),
+        Err(err) => {
+            debug!(error = ?err, "failed to decrypt UMK");
+            Err(MilieuError::CommandFailed(
+                "recovery phrase incorrect or does not match this account; run `milieu phrase show` to confirm, or `milieu logout` and login again with the correct phrase".to_string(),
+            ))
+        }
+    }
+}
@@ -1,15 +1,14 @@
 use crate::api::ApiClient;
 use crate::auth;
-use crate::commands::prompt;
+use crate::commands::{is_interactive, prompt};
 use crate::config::Config;
 use crate::error::Result;
 use crate::style;
-use atty::Stream;
 
 pub async fn run(profile: &str) -> Result<()> {
     crate::commands::print_scope_user(profile);
     let mut keep_local = false;
-    if atty::is(Stream::Stdin) {
+    if is_interactive() {
         let answer = prompt("Keep login details in keychain? [y/N] ")?;
         let answer = answer.trim().to_lowercase();
         keep_local = answer == "y" || answer == "yes";
@@ -25,14 +24,14 @@ pub async fn run(profile: &str) -> Result<()> {
     }
     if keep_local {
         let _ = auth::clear_auth(profile);
-        println!(
+        say!(
             "{}",
             style::paint(
                 style::PEACH,
                 "Logged out. Kept recovery phrase + local keychain data."
             )
         );
-        println!(
+        say!(
             "{}",
             style::paint(
                 style::SUBTEXT1,
@@ -43,7 +42,7 @@ pub async fn run(profile: &str) -> Result<()> {
         let _ = auth::clear_umk(profile);
         let _ = auth::clear_auth(profile);
         let _ = auth::delete_session(profile);
-        println!(
+        say!(
             "{}",
             style::paint(style::PEACH, &format!("Logged out ({})", profile))
         );
@@ -1,6 +1,9 @@
 use crate::error::{MilieuError, Result};
 use crate::manifest::Manifest;
 use crate::style;
+use futures_util::stream::{self, StreamExt};
+use std::future::Future;
+use std::sync::OnceLock;
 
 pub mod push;
 pub mod clone;
@@ -15,27 +18,46 @@ pub mod logout;
 pub mod pull;
 pub mod repos;
 pub mod branches;
+pub mod stash;
 pub mod status;
 pub mod remove;
+pub mod undo;
 pub mod user;
 
+// Upper bound on server requests in flight at once.
+pub const MAX_CONCURRENT_REQUESTS: usize = 8;
+
+// Runs `task` for every item with at most MAX_CONCURRENT_REQUESTS in flight.
+// Results come back in input order, so output stays deterministic.
+pub async fn run_bounded<T, R, F, Fut>(items: Vec<T>, task: F) -> Vec<R>
+where
+    F: FnMut(T) -> Fut,
+    Fut: Future<Output = R>,
+{
+    stream::iter(items)
+        .map(task)
+        .buffered(MAX_CONCURRENT_REQUESTS)
+        .collect()
+        .await
+}
+
 pub fn print_scope_user(profile: &str) {
     let label = match crate::auth::load_email(profile) {
         Ok(Some(email)) => format!("SCOPE: user {}", email),
         _ => "SCOPE: user".to_string(),
     };
-    println!("{}", style::bold(style::MAUVE, &label));
+    say!("{}", style::bold(style::MAUVE, &label));
 }
 
 pub fn print_scope_repo(manifest: &Manifest) {
-    println!(
+    say!(
         "{}",
         style::bold(style::MAUVE, &format!("SCOPE: repo {}", manifest.repo_name))
     );
 }
 
 pub fn print_scope_branch(manifest: &Manifest, branch: &str) {
-    println!(
+    say!(
         "{}",
         style::bold(
             style::MAUVE,
@@ -44,16 +66,325 @@ pub fn print_scope_branch(manifest: &Manifest, branch: &str) {
     );
 }
 
+// The branch commands act on when none is named: the service token's branch
+// under MILIEU_TOKEN, otherwise the active one.
+pub fn default_branch(manifest: &Manifest) -> Result<String> {
+    match crate::token::active()? {
+        Some(token) => Ok(token.branch.clone()),
+        None => Ok(manifest.active_branch.clone()),
+    }
+}
+
+// Branches named by repeated --branch flags, every branch for --all-branches,
+// or the active branch when neither is given.
+pub fn select_branches(manifest: &Manifest, names: Vec<String>, all: bool) -> Result<Vec<String>> {
+    if all {
+        return Ok(manifest.branches.iter().map(|b| b.name.clone()).collect());
+    }
+    if names.is_empty() {
+        return Ok(vec![default_branch(manifest)?]);
+    }
+    let mut selected: Vec<String> = Vec::new();
+    for name in names {
+        manifest.find_branch(&name)?;
+        if !selected.contains(&name) {
+            selected.push(name);
+        }
+    }
+    Ok(selected)
+}
+
+// Prints the scope line for one branch, or the repo scope when several
+// branches are processed and each gets its own header.
+pub fn print_scope_branches(manifest: &Manifest, branches: &[String]) {
+    match branches {
+        [branch] => print_scope_branch(manifest, branch),
+        _ => print_scope_repo(manifest),
+    }
+}
+
+pub fn print_branch_header(branches: &[String], branch: &str) {
+    if branches.len() > 1 {
+        say!("{}", style::bold(style::LAVENDER, &format!("branch {}", branch)));
+    }
+}
+
+// Per-branch file actions, printed as counts at the end of multi-branch
+// push/pull and listed in full with `--output json`.
+pub struct BranchSummary {
+    pub branch: String,
+    files: Vec<(String, &'static str)>,
+}
+
+impl BranchSummary {
+    pub fn new(branch: &str) -> Self {
+        Self {
+            branch: branch.to_string(),
+            files: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, action: &'static str, path: &str) {
+        self.files.push((path.to_string(), action));
+    }
+
+    fn counts(&self) -> Vec<(&'static str, usize)> {
+        let mut counts: Vec<(&'static str, usize)> = Vec::new();
+        for (_, action) in &self.files {
+            match counts.iter_mut().find(|(label, _)| label == action) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((action, 1)),
+            }
+        }
+        counts
+    }
+}
+
+pub fn print_branch_summaries(summaries: &[BranchSummary]) {
+    if summaries.len() < 2 {
+        return;
+    }
+    say!("{}", style::bold(style::MAUVE, "summary:"));
+    for summary in summaries {
+        let counts = summary.counts();
+        let counts = if counts.is_empty() {
+            "no files".to_string()
+        } else {
+            counts
+                .iter()
+                .map(|(label, count)| format!("{} {}", label, count))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        say!(
+            "{}",
+            style::paint(style::TEXT, &format!("  {}: {}", summary.branch, counts))
+        );
+    }
+}
+
+pub fn emit_branch_summaries(
+    command: &str,
+    dry_run: bool,
+    summaries: &[BranchSummary],
+) -> Result<()> {
+    let branches: Vec<_> = summaries
+        .iter()
+        .map(|summary| {
+            let files: Vec<_> = summary
+                .files
+                .iter()
+                .map(|(path, action)| serde_json::json!({ "path": path, "action": action }))
+                .collect();
+            serde_json::json!({ "branch": summary.branch, "files": files })
+        })
+        .collect();
+    crate::output::emit(&serde_json::json!({
+        "command": command,
+        "dry_run": dry_run,
+        "branches": branches,
+    }))
+}
+
+// Repo key from the keychain, fetching it from the server only when it has
+// not been cached yet. Used by commands that otherwise work offline.
+pub async fn local_repo_key(profile: &str, manifest: &Manifest) -> Result<[u8; 32]> {
+    if let Some(key) = crate::keys::load_repo_key(profile, &manifest.repo_id)? {
+        return Ok(key);
+    }
+    let base_url = repo_base_url(profile, manifest)?;
+    let token = crate::auth::load_auth_token(profile)?;
+    let client = crate::api::ApiClient::new(&base_url, Some(token))?;
+    crate::keys::get_or_fetch_repo_key(profile, &client, &manifest.repo_id).await
+}
+
+// The manifest's remote wins over the profile's server, but never over a
+// service token: a committed manifest must not redirect the token elsewhere.
+pub fn repo_base_url(profile: &str, manifest: &Manifest) -> Result<String> {
+    let config = crate::config::Config::load()?;
+    if let Some(token) = crate::token::active()? {
+        return Ok(token.base_url.clone());
+    }
+    match manifest.remote.as_ref().and_then(|remote| remote.base_url.clone()) {
+        Some(url) => Ok(url),
+        None => config.base_url_for(profile),
+    }
+}
+
+pub fn describe_blob(data: &[u8]) -> String {
+    let hash = blake3::hash(data).to_hex();
+    format!("{} bytes, blake3 {}", data.len(), &hash[..12])
+}
+
+pub fn print_offline_notice() {
+    say!(
+        "{}",
+        style::paint(style::SUBTEXT1, "offline: remote state as of the last download")
+    );
+}
+
+pub fn print_dry_run_notice() {
+    say!(
+        "{}",
+        style::paint(style::SUBTEXT1, "dry run: nothing will be uploaded or written")
+    );
+}
+
+// One line of a --dry-run plan, e.g. "would push .env (+2 -1)".
+pub fn print_planned(action: &str, path: &str, detail: Option<&str>) {
+    let color = match action {
+        "conflict" => style::RED,
+        "unchanged" => style::SUBTEXT1,
+        "kept local" => style::GREEN,
+        _ => style::SKY,
+    };
+    let line = match detail {
+        Some(detail) => format!("{} {} ({})", action, path, detail),
+        None => format!("{} {}", action, path),
+    };
+    say!("{}", style::paint(color, &line));
+}
+
+/// How commands may ask for input, from the global `--yes`,
+/// `--password-stdin` and `--phrase-stdin` flags.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InputSettings {
+    pub assume_yes: bool,
+    pub password_stdin: bool,
+    pub phrase_stdin: bool,
+}
+
+static INPUT: OnceLock<InputSettings> = OnceLock::new();
+
+pub fn configure_input(settings: InputSettings) {
+    let _ = INPUT.set(settings);
+}
+
+fn input() -> InputSettings {
+    INPUT.get().copied().unwrap_or_default()
+}
+
+pub fn assume_yes() -> bool {
+    input().assume_yes
+}
+
+// Prompts need a person at a terminal. Anything else fails fast instead of
+// blocking a CI job on stdin.
+pub fn is_interactive() -> bool {
+    !input().assume_yes && atty::is(atty::Stream::Stdin)
+}
+
+pub fn require_interactive(what: &str) -> Result<()> {
+    if is_interactive() {
+        Ok(())
+    } else {
+        Err(MilieuError::InputRequired(what.to_string()))
+    }
+}
+
+// Yes/no question before a destructive step; `--yes` answers it.
+pub fn confirm(text: &str) -> Result<bool> {
+    if assume_yes() {
+        return Ok(true);
+    }
+    require_interactive("confirmation")?;
+    let answer = prompt(text)?.to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+pub fn prompt_email() -> Result<String> {
+    if let Some(email) = env_value("MILIEU_EMAIL") {
+        return Ok(email.trim().to_string());
+    }
+    require_interactive("email")?;
+    prompt("Email: ")
+}
+
+pub fn prompt_account_password(text: &str) -> Result<String> {
+    read_secret(text, "password", "MILIEU_PASSWORD", input().password_stdin)
+}
+
+pub fn prompt_phrase(text: &str) -> Result<String> {
+    read_secret(text, "recovery phrase", "MILIEU_PHRASE", input().phrase_stdin)
+}
+
+// The replacement password when changing it: MILIEU_NEW_PASSWORD, the line
+// after the current password with --password-stdin, or typed twice.
+pub fn prompt_new_password() -> Result<String> {
+    let from_stdin = input().password_stdin;
+    if from_stdin || env_value("MILIEU_NEW_PASSWORD").is_some() {
+        return read_secret("", "new password", "MILIEU_NEW_PASSWORD", from_stdin);
+    }
+    require_interactive("new password")?;
+    let password = prompt_password("New password: ")?;
+    if password.is_empty() {
+        return Err(MilieuError::Validation("new password is empty".to_string()));
+    }
+    if prompt_password("Repeat new password: ")? != password {
+        return Err(MilieuError::Validation("passwords do not match".to_string()));
+    }
+    Ok(password)
+}
+
+// `--*-stdin` wins over the environment, which wins over a prompt. With both
+// stdin flags set the password is the first line and the phrase the second.
+// Secrets are taken as given: stdin loses only its line ending, like the
+// prompt, so surrounding spaces count wherever the secret comes from.
+fn read_secret(text: &str, what: &str, env_var: &str, from_stdin: bool) -> Result<String> {
+    if from_stdin {
+        let mut line = String::new();
+        std::io::stdin().read_line(&mut line)?;
+        let line = strip_newline(&line);
+        if line.is_empty() {
+            return Err(MilieuError::InputRequired(what.to_string()));
+        }
+        return Ok(line.to_string());
+    }
+    if let Some(value) = env_value(env_var) {
+        return Ok(value);
+    }
+    require_interactive(what)?;
+    prompt_password(text)
+}
+
+fn strip_newline(line: &str) -> &str {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+    line.strip_suffix('\r').unwrap_or(line)
+}
+
+fn env_value(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
 pub fn prompt(text: &str) -> Result<String> {
     use std::io::{self, Write};
-    let mut stdout = io::stdout();
-    write!(stdout, "{}", text)?;
-    stdout.flush()?;
+    // Keep stdout clean for the JSON document.
+    let mut out: Box<dyn Write> = if crate::output::is_json() {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    };
+    write!(out, "{}", text)?;
+    out.flush()?;
     let mut input = String::new();
     io::stdin().read_line(&mut input)?;
     Ok(input.trim().to_string())
 }
 
 pub fn prompt_password(text: &str) -> Result<String> {
-    rpassword::prompt_password(text).map_err(|e| MilieuError::Io(e))
+    rpassword::prompt_password(text).map_err(MilieuError::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stdin_secrets_lose_only_the_line_ending() {
+        assert_eq!(strip_newline("hunter2\n"), "hunter2");
+        assert_eq!(strip_newline("hunter2\r\n"), "hunter2");
+        assert_eq!(strip_newline(" spaced out \n"), " spaced out ");
+        assert_eq!(strip_newline("no newline"), "no newline");
+        assert_eq!(strip_newline("\n"), "");
+    }
 }
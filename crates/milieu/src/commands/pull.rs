@@ -1,74 +1,172 @@
-use crate::api::ApiClient;
+use crate::api::{ApiClient, ObjectResponse};
 use crate::auth;
-use crate::config::Config;
-use crate::crypto::{aad_for, decrypt_bytes};
+use crate::backup::Backup;
+use crate::cache;
+use crate::commands::BranchSummary;
 use crate::error::{MilieuError, Result};
 use crate::keys;
 use crate::manifest::{Branch, FileEntry, Manifest};
-use crate::repo::{manifest_path, validate_env_path};
+use crate::objects::{self, ObjectScope};
+use crate::repo::{is_env_file, manifest_path, pattern_matches, validate_repo_path, write_secure};
 use crate::style;
 use base64::engine::general_purpose::STANDARD as B64;
 use base64::Engine;
 use std::collections::HashMap;
 use std::fs;
-use std::io::Write;
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
 
-pub async fn run(profile: &str, branch_override: Option<String>) -> Result<()> {
+pub async fn run(
+    profile: &str,
+    branches: Vec<String>,
+    all_branches: bool,
+    dry_run: bool,
+) -> Result<()> {
     let manifest_path = manifest_path()?;
     let mut manifest = Manifest::load(&manifest_path)?;
 
-    let config = Config::load()?;
-    let mut base_url = config.base_url_for(profile)?;
-    if let Some(remote) = &manifest.remote {
-        if let Some(url) = &remote.base_url {
-            base_url = url.clone();
-        }
-    }
+    let base_url = crate::commands::repo_base_url(profile, &manifest)?;
 
     let token = auth::load_auth_token(profile)?;
     let client = ApiClient::new(&base_url, Some(token))?;
     let repo_key = keys::get_or_fetch_repo_key(profile, &client, &manifest.repo_id).await?;
 
-    if let Ok(remote_manifest) = client.get_manifest(&manifest.repo_id).await {
-        manifest = merge_manifests(&manifest, &remote_manifest);
+    let mut backup = Backup::new("pull", &manifest.repo_id, &repo_key)?;
+    // A repo that was never pushed has no remote manifest yet.
+    match client.get_manifest(&manifest.repo_id).await {
+        Ok(remote_manifest) => {
+            report_remote_allowlist(&manifest, &remote_manifest);
+            manifest = merge_manifests(&manifest, &remote_manifest);
+            expand_remote_patterns(&mut manifest, &remote_manifest);
+            if !dry_run {
+                manifest.save(&manifest_path)?;
+            }
+        }
+        Err(MilieuError::NotFound(err)) if err.code == "not_found" => {}
+        Err(err) => return Err(err),
+    }
+
+    let branch_names = crate::commands::select_branches(&manifest, branches, all_branches)?;
+    ensure_disjoint_paths(&manifest, &branch_names)?;
+    crate::commands::print_scope_branches(&manifest, &branch_names);
+    if dry_run {
+        crate::commands::print_dry_run_notice();
+    }
+
+    let mut summaries = Vec::new();
+    for branch_name in &branch_names {
+        crate::commands::print_branch_header(&branch_names, branch_name);
+        let result = pull_branch(
+            &client,
+            &repo_key,
+            &mut manifest,
+            branch_name,
+            &mut backup,
+            dry_run,
+        )
+        .await;
+        match result {
+            Ok(summary) => summaries.push(summary),
+            // Branches already written stay on disk; close the backup so
+            // `undo` can take them back.
+            Err(err) => {
+                if !dry_run {
+                    backup.finish()?;
+                }
+                return Err(err);
+            }
+        }
+    }
+    crate::commands::print_branch_summaries(&summaries);
+
+    if !dry_run {
+        backup.finish()?;
         manifest.save(&manifest_path)?;
     }
+    if crate::output::is_json() {
+        crate::commands::emit_branch_summaries("pull", dry_run, &summaries)?;
+    }
+    Ok(())
+}
 
-    let branch_name = branch_override.unwrap_or_else(|| manifest.active_branch.clone());
+// Pulling two branches that track the same path would leave whichever ran
+// last in the working tree, so that combination is refused up front.
+fn ensure_disjoint_paths(manifest: &Manifest, branch_names: &[String]) -> Result<()> {
+    let mut owners: HashMap<&str, &str> = HashMap::new();
+    for branch_name in branch_names {
+        let branch = manifest.find_branch(branch_name)?;
+        for entry in branch.files.iter().filter(|entry| !entry.is_pattern()) {
+            if let Some(other) = owners.insert(entry.path(), &branch.name) {
+                if other != branch.name {
+                    return Err(MilieuError::CommandFailed(format!(
+                        "branches {} and {} both track {}; pull them one at a time",
+                        other,
+                        branch.name,
+                        entry.path()
+                    )));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn pull_branch(
+    client: &ApiClient,
+    repo_key: &[u8; 32],
+    manifest: &mut Manifest,
+    branch_name: &str,
+    backup: &mut Backup,
+    dry_run: bool,
+) -> Result<BranchSummary> {
     let repo_id = manifest.repo_id.clone();
-    crate::commands::print_scope_branch(&manifest, &branch_name);
-    let branch = manifest.find_branch_mut(&branch_name)?;
+    let allowed = manifest.allowed_files.clone();
+    let branch = manifest.find_branch_mut(branch_name)?;
     let branch_label = branch.name.clone();
+    let mut summary = BranchSummary::new(&branch_label);
+
+    let mut paths = Vec::new();
+    let mut tags = Vec::new();
+    for entry in branch.files.iter().filter(|entry| !entry.is_pattern()) {
+        validate_repo_path(entry.path(), &allowed)?;
+        paths.push(entry.path.clone());
+        tags.push(entry.tag().map(str::to_string));
+    }
+    let latest = cache::latest_many(client, &repo_id, &branch_label, &paths).await?;
+    let jobs: Vec<_> = paths.into_iter().zip(tags).zip(latest).collect();
+    let fetched = crate::commands::run_bounded(jobs, |((path, tag), response)| {
+        let (repo_id, branch_label) = (&repo_id, &branch_label);
+        async move {
+            let Some(response) = response else {
+                return Ok(None);
+            };
+            let scope = ObjectScope {
+                repo_id,
+                branch: branch_label,
+                path: &path,
+                tag: tag.as_deref(),
+            };
+            open_remote(client, repo_key, &scope, response).await.map(Some)
+        }
+    })
+    .await
+    .into_iter()
+    // Every download must succeed before the first file is overwritten.
+    .collect::<Result<Vec<_>>>()?;
 
-    for entry in &mut branch.files {
+    let entries = branch.files.iter_mut().filter(|entry| !entry.is_pattern());
+    for (entry, fetched) in entries.zip(fetched) {
         let path = entry.path.clone();
-        validate_env_path(&path)?;
-        let response = match client
-            .get_latest(&repo_id, &branch_label, &path)
-            .await?
-        {
+        let opaque = !is_env_file(&path);
+        let (response, remote_plain) = match fetched {
             None => {
-                println!(
+                say!(
                     "{}",
                     style::paint(style::YELLOW, &format!("missing remote for {}", path))
                 );
+                summary.record("missing remote", &path);
                 continue;
             }
             Some(value) => value,
         };
-
-        let schema_version = response.schema_version;
-        let aad = aad_for(schema_version, &repo_id, &branch_label, &path, entry.tag());
-        let aad_b64 = B64.encode(&aad);
-        if response.aad != aad_b64 {
-            return Err(MilieuError::Crypto(format!(
-                "aad mismatch for {}",
-                path
-            )));
-        }
-        let remote_plain = decrypt_bytes(&repo_key, &aad, &response.nonce, &response.ciphertext)?;
         let remote_hash = blake3::hash(&remote_plain);
         let local_plain = fs::read(&path).ok();
         let local_hash = local_plain.as_ref().map(|data| blake3::hash(data));
@@ -77,89 +175,112 @@ pub async fn run(profile: &str, branch_override: Option<String>) -> Result<()> {
             .as_deref()
             .and_then(|hex| blake3::Hash::from_hex(hex).ok());
 
-        match (local_plain, base_hash) {
-            (None, _) => {
+        let action = merge_action(local_hash, base_hash, remote_hash);
+        if dry_run {
+            let label = match action {
+                Merge::Take => "would pull",
+                Merge::UpToDate => "unchanged",
+                Merge::KeepLocal => "kept local",
+                Merge::Conflict => "conflict",
+            };
+            crate::commands::print_planned(label, &path, None);
+            summary.record(label, &path);
+            continue;
+        }
+
+        match action {
+            Merge::Take => {
+                backup.save(&path)?;
                 write_secure(&path, &remote_plain)?;
                 entry.set_synced(remote_hash.to_hex().to_string(), response.version);
-                println!(
+                say!(
                     "{}",
                     style::paint(style::GREEN, &format!("pulled {}", path))
                 );
+                summary.record("pulled", &path);
             }
-            (Some(local_bytes), None) => {
-                if local_hash == Some(remote_hash) {
-                    entry.set_synced(remote_hash.to_hex().to_string(), response.version);
-                    println!(
-                        "{}",
-                        style::paint(style::GREEN, &format!("up to date {}", path))
-                    );
-                } else {
+            Merge::UpToDate => {
                 entry.set_synced(remote_hash.to_hex().to_string(), response.version);
-                let merged = format!(
-                    "<<<<<<< local\n{}\n=======\n{}\n>>>>>>> remote\n",
-                    String::from_utf8_lossy(&local_bytes),
-                    String::from_utf8_lossy(&remote_plain)
+                say!(
+                    "{}",
+                    style::paint(style::GREEN, &format!("up to date {}", path))
                 );
-                write_secure(&path, merged.as_bytes())?;
-                    println!(
-                        "{}",
-                        style::paint(
-                            style::RED,
-                            &format!("merge conflict in {}; resolve then push", path)
-                        )
-                    );
-                }
+                summary.record("up to date", &path);
             }
-            (Some(local_bytes), Some(base)) => {
-                let local_hash = local_hash.unwrap_or_else(|| blake3::hash(&local_bytes));
-                if local_hash == base {
-                    write_secure(&path, &remote_plain)?;
-                    entry.set_synced(remote_hash.to_hex().to_string(), response.version);
-                    println!(
-                        "{}",
-                        style::paint(style::GREEN, &format!("pulled {}", path))
-                    );
-                } else if remote_hash == base {
-                    entry.last_synced_version = response.version;
-                    println!(
-                        "{}",
-                        style::paint(style::GREEN, &format!("kept local {}", path))
-                    );
-                } else if local_hash == remote_hash {
-                    entry.set_synced(remote_hash.to_hex().to_string(), response.version);
-                    println!(
-                        "{}",
-                        style::paint(style::GREEN, &format!("up to date {}", path))
-                    );
-                } else {
-                    entry.set_synced(remote_hash.to_hex().to_string(), response.version);
-                    let merged = format!(
-                        "<<<<<<< local\n{}\n=======\n{}\n>>>>>>> remote\n",
-                        String::from_utf8_lossy(&local_bytes),
-                        String::from_utf8_lossy(&remote_plain)
-                    );
-                    write_secure(&path, merged.as_bytes())?;
-                    println!(
-                        "{}",
-                        style::paint(
-                            style::RED,
-                            &format!("merge conflict in {}; resolve then push", path)
-                        )
-                    );
-                }
+            Merge::KeepLocal => {
+                entry.last_synced_version = response.version;
+                say!(
+                    "{}",
+                    style::paint(style::GREEN, &format!("kept local {}", path))
+                );
+                summary.record("kept local", &path);
+            }
+            Merge::Conflict => {
+                entry.set_synced(remote_hash.to_hex().to_string(), response.version);
+                let local_bytes = local_plain.unwrap_or_default();
+                write_conflict(backup, &path, &local_bytes, &remote_plain, "remote", opaque)?;
+                summary.record("conflict", &path);
             }
         }
     }
 
-    manifest.save(&manifest_path)?;
-    Ok(())
+    Ok(summary)
 }
 
+async fn open_remote(
+    client: &ApiClient,
+    repo_key: &[u8; 32],
+    scope: &ObjectScope<'_>,
+    response: ObjectResponse,
+) -> Result<(ObjectResponse, Vec<u8>)> {
+    let aad_b64 = B64.encode(scope.aad(response.schema_version));
+    if response.aad != aad_b64 {
+        return Err(MilieuError::Crypto(format!(
+            "aad mismatch for {}",
+            scope.path
+        )));
+    }
+    let remote_plain = objects::open(client, repo_key, scope, &response).await?;
+    Ok((response, remote_plain))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Merge {
+    Take,
+    UpToDate,
+    KeepLocal,
+    Conflict,
+}
+
+// Three-way decision shared by pull and `stash pop`: `incoming` is the content
+// being applied over the local file and `base` the last content both agreed on.
+pub fn merge_action(
+    local: Option<blake3::Hash>,
+    base: Option<blake3::Hash>,
+    incoming: blake3::Hash,
+) -> Merge {
+    match (local, base) {
+        (None, _) => Merge::Take,
+        (Some(local), _) if local == incoming => Merge::UpToDate,
+        (Some(local), Some(base)) if local == base => Merge::Take,
+        (Some(_), Some(base)) if incoming == base => Merge::KeepLocal,
+        _ => Merge::Conflict,
+    }
+}
+
+// The allowlist decides which paths pull may write, so it is never taken
+// from the remote: a collaborator could otherwise widen it to any file.
+// Remote entries outside the local list are left out until the user adds
+// the pattern themselves.
 fn merge_manifests(local: &Manifest, remote: &Manifest) -> Manifest {
     let mut branches: HashMap<String, Branch> = HashMap::new();
 
     for branch in &remote.branches {
-        branches.insert(branch.name.clone(), branch.clone());
+        let mut branch = branch.clone();
+        branch
+            .files
+            .retain(|file| local.validate_tracked(file.path()).is_ok());
+        branches.insert(branch.name.clone(), branch);
     }
 
     for branch in &local.branches {
@@ -181,6 +302,8 @@ fn merge_manifests(local: &Manifest, remote: &Manifest) -> Manifest {
         repo_id: remote.repo_id.clone(),
         repo_name: remote.repo_name.clone(),
         active_branch: remote.active_branch.clone(),
+        allowed_files: local.allowed_files.clone(),
+        max_repo_bytes: local.max_repo_bytes.or(remote.max_repo_bytes),
         branches: branches.into_values().collect(),
         remote: local.remote.clone(),
     };
@@ -192,18 +315,256 @@ fn merge_manifests(local: &Manifest, remote: &Manifest) -> Manifest {
     merged
 }
 
-fn write_secure(path: &str, data: &[u8]) -> Result<()> {
-    if let Some(parent) = Path::new(path).parent() {
-        if !parent.as_os_str().is_empty() {
-            fs::create_dir_all(parent)?;
+fn remote_only_patterns(local: &Manifest, remote: &Manifest) -> Vec<String> {
+    remote
+        .allowed_files
+        .iter()
+        .filter(|pattern| !local.allowed_files.contains(pattern))
+        .cloned()
+        .collect()
+}
+
+fn report_remote_allowlist(local: &Manifest, remote: &Manifest) {
+    let patterns = remote_only_patterns(local, remote);
+    if !patterns.is_empty() {
+        say!(
+            "{}",
+            style::paint(
+                style::PEACH,
+                &format!(
+                    "remote allowed_files adds {}; add to allowed_files in .milieu/manifest.toml to sync those files",
+                    patterns.join(", ")
+                )
+            )
+        );
+    }
+    for branch in &remote.branches {
+        for file in &branch.files {
+            if let Err(err) = local.validate_tracked(file.path()) {
+                say!(
+                    "{}",
+                    style::paint(
+                        style::YELLOW,
+                        &format!("skipped {} ({}): {}", file.path(), branch.name, err)
+                    )
+                );
+            }
+        }
+    }
+}
+
+// Patterns are expanded against the paths the remote manifest already lists,
+// so files a teammate pushed through a pattern show up here before they exist
+// in the local working tree.
+fn expand_remote_patterns(manifest: &mut Manifest, remote: &Manifest) {
+    let allowed = manifest.allowed_files.clone();
+    for branch in &mut manifest.branches {
+        let Some(remote_branch) = remote.branches.iter().find(|b| b.name == branch.name) else {
+            continue;
+        };
+        for pattern in branch.patterns() {
+            let matches: Vec<String> = remote_branch
+                .files
+                .iter()
+                .filter(|file| !file.is_pattern() && pattern_matches(pattern.path(), file.path()))
+                .filter(|file| validate_repo_path(file.path(), &allowed).is_ok())
+                .map(|file| file.path.clone())
+                .collect();
+            branch.apply_pattern_matches(&pattern, &matches);
         }
     }
-    let mut file = fs::File::create(path)?;
-    file.write_all(data)?;
-    let mut perms = file.metadata()?.permissions();
-    perms.set_mode(0o600);
-    fs::set_permissions(path, perms)?;
+}
+
+// Opaque files can't carry conflict markers, so the local copy is kept and
+// the user decides which version to push.
+pub fn write_conflict(
+    backup: &mut Backup,
+    path: &str,
+    local: &[u8],
+    incoming: &[u8],
+    incoming_label: &str,
+    opaque: bool,
+) -> Result<()> {
+    if opaque {
+        say!(
+            "{}",
+            style::paint(
+                style::RED,
+                &format!(
+                    "conflict in {} (local {}, {} {}); kept local, resolve then push",
+                    path,
+                    crate::commands::describe_blob(local),
+                    incoming_label,
+                    crate::commands::describe_blob(incoming)
+                )
+            )
+        );
+        return Ok(());
+    }
+    let merged = format!(
+        "<<<<<<< local\n{}\n=======\n{}\n>>>>>>> {}\n",
+        String::from_utf8_lossy(local),
+        String::from_utf8_lossy(incoming),
+        incoming_label
+    );
+    backup.save(path)?;
+    write_secure(path, merged.as_bytes())?;
+    say!(
+        "{}",
+        style::paint(
+            style::RED,
+            &format!("merge conflict in {}; resolve then push", path)
+        )
+    );
     Ok(())
 }
 
 // path validation centralized in repo::validate_env_path
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(toml: &str) -> Manifest {
+        toml::from_str(toml).unwrap()
+    }
+
+    fn hash(data: &str) -> blake3::Hash {
+        blake3::hash(data.as_bytes())
+    }
+
+    #[test]
+    fn text_conflicts_get_markers_and_a_backup() {
+        crate::repo::in_temp_project(|| {
+            let key = [1; crate::crypto::UMK_LEN];
+            fs::write(".env", "A=1").unwrap();
+            let mut backup = Backup::new("pull", "repo", &key).unwrap();
+            write_conflict(&mut backup, ".env", b"A=1", b"A=2", "remote v3", false).unwrap();
+            backup.finish().unwrap();
+
+            assert_eq!(
+                fs::read_to_string(".env").unwrap(),
+                "<<<<<<< local\nA=1\n=======\nA=2\n>>>>>>> remote v3\n"
+            );
+            let index = crate::backup::latest().unwrap().unwrap();
+            crate::backup::restore(&index, &key).unwrap();
+            assert_eq!(fs::read_to_string(".env").unwrap(), "A=1");
+        });
+    }
+
+    #[test]
+    fn opaque_conflicts_keep_the_local_file() {
+        crate::repo::in_temp_project(|| {
+            let key = [1; crate::crypto::UMK_LEN];
+            fs::write("cert.pem", [0xff, 0x00]).unwrap();
+            let mut backup = Backup::new("pull", "repo", &key).unwrap();
+            write_conflict(&mut backup, "cert.pem", &[0xff, 0x00], &[0xfe], "remote v3", true)
+                .unwrap();
+            backup.finish().unwrap();
+
+            assert_eq!(fs::read("cert.pem").unwrap(), [0xff, 0x00]);
+            assert!(crate::backup::latest().unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn missing_local_file_takes_incoming() {
+        assert_eq!(merge_action(None, None, hash("a")), Merge::Take);
+        assert_eq!(merge_action(None, Some(hash("b")), hash("a")), Merge::Take);
+    }
+
+    #[test]
+    fn identical_local_file_is_up_to_date() {
+        assert_eq!(merge_action(Some(hash("a")), None, hash("a")), Merge::UpToDate);
+        assert_eq!(
+            merge_action(Some(hash("a")), Some(hash("b")), hash("a")),
+            Merge::UpToDate
+        );
+    }
+
+    #[test]
+    fn unchanged_local_file_takes_incoming() {
+        assert_eq!(
+            merge_action(Some(hash("base")), Some(hash("base")), hash("new")),
+            Merge::Take
+        );
+    }
+
+    #[test]
+    fn local_edit_over_unchanged_incoming_is_kept() {
+        assert_eq!(
+            merge_action(Some(hash("mine")), Some(hash("base")), hash("base")),
+            Merge::KeepLocal
+        );
+    }
+
+    #[test]
+    fn both_sides_changed_is_a_conflict() {
+        assert_eq!(
+            merge_action(Some(hash("mine")), Some(hash("base")), hash("theirs")),
+            Merge::Conflict
+        );
+        // Without a base there is no telling which side moved.
+        assert_eq!(merge_action(Some(hash("mine")), None, hash("theirs")), Merge::Conflict);
+    }
+
+    const LOCAL: &str = r#"
+version = 1
+repo_id = "r"
+repo_name = "r"
+active_branch = "dev"
+allowed_files = ["*.pem"]
+
+[[branch]]
+name = "dev"
+
+[[branch.files]]
+path = ".env"
+"#;
+
+    const REMOTE: &str = r#"
+version = 1
+repo_id = "r"
+repo_name = "r"
+active_branch = "dev"
+allowed_files = ["*.pem", "*", ".git/hooks/*"]
+
+[[branch]]
+name = "dev"
+
+[[branch.files]]
+path = ".env.shared"
+
+[[branch.files]]
+path = "certs/ca.pem"
+
+[[branch.files]]
+path = "run.sh"
+
+[[branch.files]]
+path = ".git/hooks/post-merge"
+"#;
+
+    #[test]
+    fn merge_keeps_the_local_allowlist() {
+        let merged = merge_manifests(&manifest(LOCAL), &manifest(REMOTE));
+        assert_eq!(merged.allowed_files, vec!["*.pem".to_string()]);
+
+        let mut paths: Vec<_> = merged.branches[0]
+            .files
+            .iter()
+            .map(|file| file.path.clone())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, vec![".env", ".env.shared", "certs/ca.pem"]);
+    }
+
+    #[test]
+    fn remote_only_patterns_are_reported() {
+        assert_eq!(
+            remote_only_patterns(&manifest(LOCAL), &manifest(REMOTE)),
+            vec!["*".to_string(), ".git/hooks/*".to_string()]
+        );
+        assert!(remote_only_patterns(&manifest(LOCAL), &manifest(LOCAL)).is_empty());
+    }
+}
@@ -1,163 +1,351 @@
-use crate::api::{ApiClient, ObjectRequest};
+use crate::api::{ApiClient, ObjectResponse};
 use crate::auth;
+use crate::cache;
+use crate::commands::BranchSummary;
 use crate::config::Config;
-use crate::crypto::{aad_for, decrypt_bytes, encrypt_bytes};
-use crate::error::{MilieuError, Result};
+use crate::crypto::compress;
+use crate::error::{ApiError, MilieuError, Result};
 use crate::keys;
 use crate::manifest::Manifest;
-use crate::repo::{manifest_path, validate_env_path};
+use crate::objects::{self, ObjectScope};
+use crate::repo::{expand_pattern, is_env_file, manifest_path, validate_repo_path};
 use crate::style;
-use base64::engine::general_purpose::STANDARD as B64;
-use base64::Engine;
 use similar::TextDiff;
 use similar::ChangeTag;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::path::Path;
 
-const MAX_REPO_BYTES: u64 = 1 * 1024 * 1024;
-
-pub async fn run(profile: &str, branch_override: Option<String>) -> Result<()> {
+pub async fn run(
+    profile: &str,
+    branches: Vec<String>,
+    all_branches: bool,
+    dry_run: bool,
+    force: bool,
+) -> Result<()> {
     let manifest_path = manifest_path()?;
     let mut manifest = Manifest::load(&manifest_path)?;
-    enforce_repo_size_limit(&manifest)?;
 
-    let branch_name = branch_override.unwrap_or_else(|| manifest.active_branch.clone());
-    let repo_id = manifest.repo_id.clone();
-    let branch_snapshot = manifest.find_branch(&branch_name)?.clone();
-    crate::commands::print_scope_branch(&manifest, &branch_name);
+    let branch_names = crate::commands::select_branches(&manifest, branches, all_branches)?;
+    for branch_name in &branch_names {
+        expand_patterns(&mut manifest, branch_name)?;
+    }
 
     let config = Config::load()?;
-    let mut base_url = config.base_url_for(profile)?;
-    if let Some(remote) = &manifest.remote {
-        if let Some(url) = &remote.base_url {
-            base_url = url.clone();
-        }
+    // The profile cap mirrors the server's; a manifest can only lower it.
+    let server_bytes = config.max_repo_bytes_for(profile);
+    let max_bytes = manifest
+        .max_repo_bytes
+        .map_or(server_bytes, |bytes| bytes.min(server_bytes));
+    enforce_repo_size_limit(&manifest, max_bytes)?;
+    crate::commands::print_scope_branches(&manifest, &branch_names);
+    if dry_run {
+        crate::commands::print_dry_run_notice();
     }
 
+    let base_url = crate::commands::repo_base_url(profile, &manifest)?;
+
     let token = auth::load_auth_token(profile)?;
     let client = ApiClient::new(&base_url, Some(token))?;
     let repo_key = keys::get_or_fetch_repo_key(profile, &client, &manifest.repo_id).await?;
 
+    // Every selected branch is checked before anything is uploaded, so a
+    // conflict on one branch leaves all of them untouched. The objects fetched
+    // here are reused for the upload pass.
+    let remotes = fetch_remotes(&client, &repo_key, &manifest, &branch_names).await?;
     let mut conflicts = Vec::new();
-    for entry in &branch_snapshot.files {
-        let path = entry.path();
-        validate_env_path(path)?;
-        let data = fs::read(&path).map_err(|_| {
-            MilieuError::CommandFailed(format!("missing file: {}", path))
-        })?;
-        let local_hash = blake3::hash(&data);
+    for branch_name in &branch_names {
+        for path in find_conflicts(&manifest, branch_name, &remotes)? {
+            conflicts.push((branch_name.clone(), path));
+        }
+    }
 
-        let remote = client
-            .get_latest(&repo_id, &branch_snapshot.name, path)
-            .await?;
-
-        if let Some(remote_obj) = &remote {
-            let schema_version = remote_obj.schema_version;
-            let aad = aad_for(schema_version, &repo_id, &branch_snapshot.name, path, entry.tag());
-            let plaintext = decrypt_bytes(&repo_key, &aad, &remote_obj.nonce, &remote_obj.ciphertext)?;
-            let remote_hash = blake3::hash(&plaintext);
-            let base_hash = entry
-                .last_synced_hash
-                .as_deref()
-                .and_then(|hex| blake3::Hash::from_hex(hex).ok());
-
-            match base_hash {
-                None => {
-                    if local_hash != remote_hash {
-                        conflicts.push(path.to_string());
-                    }
-                }
-                Some(base) => {
-                    if remote_hash != base {
-                        conflicts.push(path.to_string());
-                    }
+    if force {
+        for (branch_name, path) in conflicts.drain(..) {
+            let label = conflict_label(&branch_names, &branch_name, &path);
+            say!(
+                "{}",
+                style::paint(
+                    style::PEACH,
+                    &format!("warning: overwriting remote changes to {}", label)
+                )
+            );
+        }
+    }
+    if !conflicts.is_empty() && !dry_run {
+        return Err(pull_first(&branch_names, &conflicts));
+    }
+
+    // Service tokens may only write objects on their branch; the shared
+    // manifest stays with the members who manage the repo.
+    if !dry_run && crate::token::active()?.is_none() {
+        client.put_manifest(&manifest).await?;
+    }
+
+    let mut summaries = Vec::new();
+    for branch_name in &branch_names {
+        crate::commands::print_branch_header(&branch_names, branch_name);
+        let branch_conflicts: Vec<String> = conflicts
+            .iter()
+            .filter(|(branch, _)| branch == branch_name)
+            .map(|(_, path)| path.clone())
+            .collect();
+        let (summary, rejected) = push_branch(
+            &client,
+            &repo_key,
+            &mut manifest,
+            branch_name,
+            &branch_conflicts,
+            &remotes,
+            PushMode { dry_run, force },
+        )
+        .await?;
+        // Someone pushed after the check above. Files already uploaded keep
+        // their new versions in the manifest.
+        if let Some(path) = rejected {
+            manifest.save(&manifest_path)?;
+            return Err(pull_first(&branch_names, &[(branch_name.clone(), path)]));
+        }
+        summaries.push(summary);
+    }
+    crate::commands::print_branch_summaries(&summaries);
+
+    if !dry_run {
+        manifest.save(&manifest_path)?;
+    }
+    if crate::output::is_json() {
+        crate::commands::emit_branch_summaries("push", dry_run, &summaries)?;
+    }
+    Ok(())
+}
+
+fn conflict_label(branch_names: &[String], branch_name: &str, path: &str) -> String {
+    if branch_names.len() > 1 {
+        format!("{}: {}", branch_name, path)
+    } else {
+        path.to_string()
+    }
+}
+
+fn pull_first(branch_names: &[String], conflicts: &[(String, String)]) -> MilieuError {
+    let mut message = String::from("remote has new changes:");
+    for (branch_name, path) in conflicts {
+        message.push_str(&format!("\n  - {}", conflict_label(branch_names, branch_name, path)));
+    }
+    MilieuError::Conflict(ApiError {
+        action: "push".to_string(),
+        code: "conflict".to_string(),
+        message,
+        hint: Some("run `milieu pull` first, or rerun with --force to overwrite them".to_string()),
+        retry_after: None,
+    })
+}
+
+// Latest remote object and its plaintext, keyed by (branch, path). Files with
+// no remote version are absent.
+type Remotes = HashMap<(String, String), (ObjectResponse, Vec<u8>)>;
+
+async fn fetch_remotes(
+    client: &ApiClient,
+    repo_key: &[u8; 32],
+    manifest: &Manifest,
+    branch_names: &[String],
+) -> Result<Remotes> {
+    let mut jobs = Vec::new();
+    for branch_name in branch_names {
+        let branch = manifest.find_branch(branch_name)?;
+        let mut paths = Vec::new();
+        let mut tags = Vec::new();
+        for entry in branch.files.iter().filter(|entry| !entry.is_pattern()) {
+            let path = entry.path();
+            validate_repo_path(path, &manifest.allowed_files)?;
+            if !Path::new(path).exists() {
+                if entry.matched_by.is_some() {
+                    continue;
                 }
+                return Err(MilieuError::CommandFailed(format!("missing file: {}", path)));
+            }
+            paths.push(path.to_string());
+            tags.push(entry.tag().map(str::to_string));
+        }
+        let objects = cache::latest_many(client, &manifest.repo_id, &branch.name, &paths).await?;
+        for ((path, tag), object) in paths.into_iter().zip(tags).zip(objects) {
+            if let Some(object) = object {
+                jobs.push((branch.name.clone(), path, tag, object));
             }
         }
     }
 
-    if !conflicts.is_empty() {
-        let mut message = String::from("remote has new changes; run `milieu pull` first:");
-        for path in conflicts {
-            message.push_str(&format!("\n  - {}", path));
+    let results = crate::commands::run_bounded(jobs, |(branch, path, tag, object)| async move {
+        let scope = ObjectScope {
+            repo_id: &manifest.repo_id,
+            branch: &branch,
+            path: &path,
+            tag: tag.as_deref(),
+        };
+        let plaintext = objects::open(client, repo_key, &scope, &object).await?;
+        Ok::<_, MilieuError>(((branch, path), (object, plaintext)))
+    })
+    .await;
+    results.into_iter().collect()
+}
+
+fn find_conflicts(manifest: &Manifest, branch_name: &str, remotes: &Remotes) -> Result<Vec<String>> {
+    let branch = manifest.find_branch(branch_name)?;
+    let mut conflicts = Vec::new();
+    for entry in &branch.files {
+        if entry.is_pattern() {
+            continue;
+        }
+        let path = entry.path();
+        let Some((_, plaintext)) = remotes.get(&(branch.name.clone(), path.to_string())) else {
+            continue;
+        };
+        let Ok(data) = fs::read(path) else {
+            continue;
+        };
+        let local_hash = blake3::hash(&data);
+        let remote_hash = blake3::hash(plaintext);
+        let base_hash = entry
+            .last_synced_hash
+            .as_deref()
+            .and_then(|hex| blake3::Hash::from_hex(hex).ok());
+
+        match base_hash {
+            None => {
+                if local_hash != remote_hash {
+                    conflicts.push(path.to_string());
+                }
+            }
+            Some(base) => {
+                if remote_hash != base {
+                    conflicts.push(path.to_string());
+                }
+            }
         }
-        return Err(MilieuError::CommandFailed(message));
     }
+    Ok(conflicts)
+}
 
-    client.put_manifest(&manifest).await?;
+#[derive(Clone, Copy)]
+struct PushMode {
+    dry_run: bool,
+    force: bool,
+}
 
-    let branch = manifest.find_branch_mut(&branch_name)?;
+async fn push_branch(
+    client: &ApiClient,
+    repo_key: &[u8; 32],
+    manifest: &mut Manifest,
+    branch_name: &str,
+    conflicts: &[String],
+    remotes: &Remotes,
+    mode: PushMode,
+) -> Result<(BranchSummary, Option<String>)> {
+    let PushMode { dry_run, force } = mode;
+    let repo_id = manifest.repo_id.clone();
+    let allowed = manifest.allowed_files.clone();
+    let branch = manifest.find_branch_mut(branch_name)?;
     let branch_label = branch.name.clone();
+    let mut summary = BranchSummary::new(&branch_label);
     for entry in &mut branch.files {
+        if entry.is_pattern() {
+            continue;
+        }
         let path = entry.path.clone();
-        validate_env_path(&path)?;
-        let data = fs::read(&path).map_err(|_| {
-            MilieuError::CommandFailed(format!("missing file: {}", path))
-        })?;
+        validate_repo_path(&path, &allowed)?;
+        let opaque = !is_env_file(&path);
+        if conflicts.contains(&path) {
+            crate::commands::print_planned("conflict", &path, Some("run `milieu pull` first"));
+            summary.record("conflict", &path);
+            continue;
+        }
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(_) if entry.matched_by.is_some() => {
+                say!(
+                    "{}",
+                    style::paint(style::SUBTEXT1, &format!("skipped {} (missing locally)", path))
+                );
+                summary.record("skipped", &path);
+                continue;
+            }
+            Err(_) => {
+                return Err(MilieuError::CommandFailed(format!("missing file: {}", path)));
+            }
+        };
 
-        let remote = client
-            .get_latest(&repo_id, &branch_label, &path)
-            .await?;
+        let remote = remotes.get(&(branch_label.clone(), path.clone()));
+        let scope = ObjectScope {
+            repo_id: &repo_id,
+            branch: &branch_label,
+            path: &path,
+            tag: entry.tag(),
+        };
 
         let (adds, dels, same_as_remote, remote_hash, remote_version) = match remote {
-            Some(ref obj) => {
-                let schema_version = obj.schema_version;
-                let aad = aad_for(schema_version, &repo_id, &branch_label, &path, entry.tag());
-                let plaintext = decrypt_bytes(&repo_key, &aad, &obj.nonce, &obj.ciphertext)?;
-                let remote_hash = blake3::hash(&plaintext);
-                let remote_text = String::from_utf8_lossy(&plaintext);
-                let local_text = String::from_utf8_lossy(&data);
-                let (adds, dels) = diff_stats(&remote_text, &local_text);
-                (adds, dels, adds == 0 && dels == 0, Some(remote_hash), obj.version)
+            Some((obj, plaintext)) => {
+                let remote_hash = blake3::hash(plaintext);
+                if opaque {
+                    let same = remote_hash == blake3::hash(&data);
+                    (0, 0, same, Some(remote_hash), obj.version)
+                } else {
+                    let remote_text = String::from_utf8_lossy(plaintext);
+                    let local_text = String::from_utf8_lossy(&data);
+                    let (adds, dels) = diff_stats(&remote_text, &local_text);
+                    (adds, dels, adds == 0 && dels == 0, Some(remote_hash), obj.version)
+                }
             }
+            None if opaque => (0, 0, false, None, None),
             None => {
                 let local_text = String::from_utf8_lossy(&data);
                 (local_text.lines().count() as i64, 0, false, None, None)
             }
         };
 
+        let detail = if opaque {
+            crate::commands::describe_blob(&data)
+        } else {
+            format!("+{} -{}", adds, dels)
+        };
+
+        if dry_run {
+            let action = if same_as_remote { "unchanged" } else { "would push" };
+            let detail = (!same_as_remote).then_some(detail.as_str());
+            crate::commands::print_planned(action, &path, detail);
+            summary.record(action, &path);
+            continue;
+        }
+
         if same_as_remote {
             if let Some(remote_hash) = remote_hash {
                 entry.set_synced(remote_hash.to_hex().to_string(), remote_version);
             }
-            println!(
+            say!(
                 "{}",
                 style::paint(style::SUBTEXT1, &format!("unchanged {}", path))
             );
+            summary.record("unchanged", &path);
             continue;
         }
 
-        let schema_version = 2;
-        let aad = aad_for(schema_version, &repo_id, &branch_label, &path, entry.tag());
-        let (nonce, ciphertext) = encrypt_bytes(&repo_key, &aad, &data)?;
-        let ciphertext_hash = blake3::hash(ciphertext.as_bytes()).to_hex().to_string();
-        let request = ObjectRequest {
-            path: path.to_string(),
-            nonce,
-            ciphertext,
-            aad: B64.encode(aad),
-            ciphertext_hash,
-            created_at: chrono::Utc::now().to_rfc3339(),
-            schema_version,
+        let parent_version = (!force).then(|| entry.last_synced_version.unwrap_or(0));
+        let response = match objects::upload(client, repo_key, &scope, &data, parent_version).await {
+            Err(MilieuError::Conflict(_)) => return Ok((summary, Some(path))),
+            result => result?,
         };
-        let response = client
-            .post_object(&repo_id, &branch_label, &request)
-            .await?;
-
-        let summary = format!("+{} -{}", adds, dels);
-        println!(
+        say!(
             "{}",
-            style::paint(style::GREEN, &format!("pushed {} ({})", path, summary))
+            style::paint(style::GREEN, &format!("pushed {} ({})", path, detail))
         );
+        summary.record("pushed", &path);
 
         entry.set_synced(
             blake3::hash(&data).to_hex().to_string(),
             response.version,
         );
     }
-
-    manifest.save(&manifest_path)?;
-    Ok(())
+    Ok((summary, None))
 }
 
 fn diff_stats(old_text: &str, new_text: &str) -> (i64, i64) {
@@ -176,28 +364,57 @@ fn diff_stats(old_text: &str, new_text: &str) -> (i64, i64) {
 
 // path validation centralized in repo::validate_env_path
 
-fn enforce_repo_size_limit(manifest: &Manifest) -> Result<()> {
+fn expand_patterns(manifest: &mut Manifest, branch_name: &str) -> Result<()> {
+    let allowed = manifest.allowed_files.clone();
+    let branch = manifest.find_branch_mut(branch_name)?;
+    for pattern in branch.patterns() {
+        let matches = expand_pattern(pattern.path(), &allowed)?;
+        branch.apply_pattern_matches(&pattern, &matches);
+    }
+    Ok(())
+}
+
+// The cap applies to compressed plaintext, which is what the server stores
+// (plus encryption overhead).
+fn enforce_repo_size_limit(manifest: &Manifest, max_bytes: u64) -> Result<()> {
     let mut seen = HashSet::new();
+    let mut sizes = Vec::new();
     let mut total: u64 = 0;
 
     for branch in &manifest.branches {
         for entry in &branch.files {
+            if entry.is_pattern() {
+                continue;
+            }
             let path = entry.path();
             if !seen.insert(path.to_string()) {
                 continue;
             }
-            validate_env_path(path)?;
-            if let Ok(meta) = fs::metadata(path) {
-                total += meta.len();
+            manifest.validate_path(path)?;
+            if let Ok(data) = fs::read(path) {
+                let compressed = compress(&data)?.len() as u64;
+                total += compressed;
+                sizes.push((path.to_string(), data.len() as u64, compressed));
             }
         }
     }
 
-    if total > MAX_REPO_BYTES {
-        return Err(MilieuError::CommandFailed(format!(
-            "repo size {} bytes exceeds 1MB cap",
-            total
-        )));
+    if total > max_bytes {
+        sizes.sort_by_key(|size| std::cmp::Reverse(size.2));
+        let mut message = format!(
+            "repo size {} bytes (compressed) exceeds cap of {} bytes:",
+            total, max_bytes
+        );
+        for (path, raw, compressed) in sizes {
+            message.push_str(&format!(
+                "\n  - {}: {} bytes ({} raw)",
+                path, compressed, raw
+            ));
+        }
+        message.push_str(
+            "\nraise `max_repo_bytes` in the profile config to allow more; the manifest can only lower it",
+        );
+        return Err(MilieuError::CommandFailed(message));
     }
 
     Ok(())
@@ -1,5 +1,5 @@
 use crate::api::{ApiClient, RegisterRequest};
-use crate::commands::{prompt, prompt_password};
+use crate::commands::{prompt_account_password, prompt_email};
 use crate::config::Config;
 use crate::error::Result;
 use crate::style;
@@ -9,9 +9,9 @@ pub async fn run(profile: &str) -> Result<()> {
     let config = Config::load()?;
     let base_url = config.base_url_for(profile)?;
 
-    let email = prompt("Email: ")?;
-    let password = prompt_password("Password: ")?;
-    println!(
+    let email = prompt_email()?;
+    let password = prompt_account_password("Password: ")?;
+    say!(
         "{}",
         style::paint(
             style::SUBTEXT1,
@@ -27,6 +27,6 @@ pub async fn run(profile: &str) -> Result<()> {
         })
         .await?;
 
-    println!("{}", style::paint(style::GREEN, &format!("registered {}", email)));
+    say!("{}", style::paint(style::GREEN, &format!("registered {}", email)));
     Ok(())
 }
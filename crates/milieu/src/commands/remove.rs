@@ -1,18 +1,20 @@
 use crate::error::{MilieuError, Result};
 use crate::manifest::Manifest;
-use crate::repo::{manifest_path, validate_env_path};
+use crate::repo::manifest_path;
 use crate::style;
 
 pub fn run(path: &str, branch_override: Option<String>) -> Result<()> {
-    validate_env_path(path)?;
     let manifest_path = manifest_path()?;
     let mut manifest = Manifest::load(&manifest_path)?;
+    manifest.validate_tracked(path)?;
     let branch_name = branch_override.unwrap_or_else(|| manifest.active_branch.clone());
     crate::commands::print_scope_branch(&manifest, &branch_name);
     let branch = manifest.find_branch_mut(&branch_name)?;
 
     let before = branch.files.len();
-    branch.files.retain(|entry| entry.path() != path);
+    branch
+        .files
+        .retain(|entry| entry.path() != path && entry.matched_by.as_deref() != Some(path));
     if branch.files.len() == before {
         return Err(MilieuError::CommandFailed(
             "file not tracked in branch".to_string(),
@@ -20,7 +22,7 @@ pub fn run(path: &str, branch_override: Option<String>) -> Result<()> {
     }
 
     manifest.save(&manifest_path)?;
-    println!(
+    say!(
         "{}",
         style::paint(style::PEACH, &format!("removed {} from {}", path, branch_name))
     );
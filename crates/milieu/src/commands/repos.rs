@@ -1,10 +1,11 @@
-use crate::api::{ApiClient, InviteInfo, RepoAccessEntry, RepoResponse};
+use crate::api::{ApiClient, InviteInfo, RepoAccessEntry, RepoResponse, ServiceTokenRequest};
 use crate::auth;
 use crate::config::Config;
-use crate::error::Result;
+use crate::crypto::generate_keypair;
+use crate::error::{MilieuError, Result};
 use crate::keys;
 use crate::style;
-use atty::Stream;
+use crate::token::ServiceToken;
 
 pub async fn list(profile: &str) -> Result<()> {
     crate::commands::print_scope_user(profile);
@@ -15,8 +16,11 @@ pub async fn list(profile: &str) -> Result<()> {
     let client = ApiClient::new(&base_url, Some(token))?;
 
     let repos = client.get_repos().await?;
+    if crate::output::is_json() {
+        return crate::output::emit(&repos);
+    }
     if repos.is_empty() {
-        println!(
+        say!(
             "{}",
             style::paint(style::YELLOW, "No repos linked yet.")
         );
@@ -40,7 +44,7 @@ pub async fn list(profile: &str) -> Result<()> {
         rows.push(repo);
     }
 
-    println!(
+    say!(
         "{}  {}  {}  {}  {}",
         style::bold(style::MAUVE, &format!("{:<width$}", "Repo ID", width = id_width)),
         style::bold(style::MAUVE, &format!("{:<width$}", "Name", width = name_width)),
@@ -48,7 +52,7 @@ pub async fn list(profile: &str) -> Result<()> {
         style::bold(style::MAUVE, &format!("{:<width$}", "Owner", width = owner_width)),
         style::bold(style::MAUVE, "Last Seen")
     );
-    println!(
+    say!(
         "{}  {}  {}  {}  {}",
         style::paint(style::SUBTEXT1, &format!("{:-<width$}", "", width = id_width)),
         style::paint(style::SUBTEXT1, &format!("{:-<width$}", "", width = name_width)),
@@ -57,7 +61,7 @@ pub async fn list(profile: &str) -> Result<()> {
         style::paint(style::SUBTEXT1, "---------")
     );
     for repo in rows {
-        println!(
+        say!(
             "{}  {}  {}  {}  {}",
             style::paint(style::TEXT, &format!("{:<width$}", repo.repo_id, width = id_width)),
             style::paint(style::TEXT, &format!("{:<width$}", repo.name, width = name_width)),
@@ -88,9 +92,12 @@ pub async fn manage_list(profile: &str, repo_name: &str) -> Result<()> {
     crate::commands::print_scope_user(profile);
     let (client, repo) = client_and_repo(profile, repo_name).await?;
     let entries = client.get_repo_access(&repo.repo_id).await?;
+    if crate::output::is_json() {
+        return crate::output::emit(&entries);
+    }
 
     if entries.is_empty() {
-        println!("{}", style::paint(style::SUBTEXT1, "no collaborators yet"));
+        say!("{}", style::paint(style::SUBTEXT1, "no collaborators yet"));
         return Ok(());
     }
 
@@ -102,7 +109,7 @@ pub async fn manage_add(profile: &str, repo_name: &str, email: &str, role: &str)
     crate::commands::print_scope_user(profile);
     let (client, repo) = client_and_repo(profile, repo_name).await?;
     client.invite_repo_access(&repo.repo_id, email, role).await?;
-    println!(
+    say!(
         "{}",
         style::paint(
             style::GREEN,
@@ -116,7 +123,7 @@ pub async fn manage_set(profile: &str, repo_name: &str, email: &str, role: &str)
     crate::commands::print_scope_user(profile);
     let (client, repo) = client_and_repo(profile, repo_name).await?;
     client.update_repo_access(&repo.repo_id, email, role).await?;
-    println!(
+    say!(
         "{}",
         style::paint(
             style::GREEN,
@@ -130,7 +137,7 @@ pub async fn manage_remove(profile: &str, repo_name: &str, email: &str) -> Resul
     crate::commands::print_scope_user(profile);
     let (client, repo) = client_and_repo(profile, repo_name).await?;
     client.revoke_repo_access(&repo.repo_id, email).await?;
-    println!(
+    say!(
         "{}",
         style::paint(
             style::PEACH,
@@ -144,26 +151,29 @@ pub async fn manage_delete(profile: &str, repo_name: &str) -> Result<()> {
     crate::commands::print_scope_user(profile);
     let (client, repo) = client_and_repo(profile, repo_name).await?;
 
-    let first = crate::commands::prompt(&format!(
-        "delete remote repo '{}' (this cannot be undone)? [y/N] ",
-        repo.name
-    ))?;
-    if first.to_lowercase().as_str() != "y" {
-        println!("{}", style::paint(style::SUBTEXT1, "aborted"));
-        return Ok(());
-    }
+    if !crate::commands::assume_yes() {
+        crate::commands::require_interactive("confirmation")?;
+        let first = crate::commands::prompt(&format!(
+            "delete remote repo '{}' (this cannot be undone)? [y/N] ",
+            repo.name
+        ))?;
+        if first.to_lowercase().as_str() != "y" {
+            say!("{}", style::paint(style::SUBTEXT1, "aborted"));
+            return Ok(());
+        }
 
-    let confirm = crate::commands::prompt(&format!(
-        "type repo name '{}' to confirm delete: ",
-        repo.name
-    ))?;
-    if confirm.trim() != repo.name {
-        println!("{}", style::paint(style::SUBTEXT1, "confirmation did not match; aborted"));
-        return Ok(());
+        let confirm = crate::commands::prompt(&format!(
+            "type repo name '{}' to confirm delete: ",
+            repo.name
+        ))?;
+        if confirm.trim() != repo.name {
+            say!("{}", style::paint(style::SUBTEXT1, "confirmation did not match; aborted"));
+            return Ok(());
+        }
     }
 
     client.delete_repo(&repo.repo_id).await?;
-    println!(
+    say!(
         "{}",
         style::paint(style::PEACH, &format!("deleted remote repo '{}'", repo.name))
     );
@@ -174,14 +184,17 @@ pub async fn manage_invites(profile: &str) -> Result<()> {
     crate::commands::print_scope_user(profile);
     let client = client_only(profile).await?;
     let invites = client.get_invites().await?;
+    if crate::output::is_json() {
+        return crate::output::emit(&invites);
+    }
     if invites.is_empty() {
-        println!("{}", style::paint(style::SUBTEXT1, "no pending invites"));
+        say!("{}", style::paint(style::SUBTEXT1, "no pending invites"));
         return Ok(());
     }
 
     print_invites(&invites);
 
-    if !atty::is(Stream::Stdin) {
+    if !crate::commands::is_interactive() {
         return Ok(());
     }
 
@@ -194,20 +207,20 @@ pub async fn manage_invites(profile: &str) -> Result<()> {
         match answer.to_lowercase().as_str() {
             "a" | "accept" => {
                 client.accept_invite(&invite.id).await?;
-                println!(
+                say!(
                     "{}",
                     style::paint(style::GREEN, &format!("accepted {}", invite.repo_name))
                 );
             }
             "r" | "reject" => {
                 client.reject_invite(&invite.id).await?;
-                println!(
+                say!(
                     "{}",
                     style::paint(style::PEACH, &format!("rejected {}", invite.repo_name))
                 );
             }
             _ => {
-                println!(
+                say!(
                     "{}",
                     style::paint(style::SUBTEXT1, "skipped")
                 );
@@ -225,7 +238,7 @@ pub async fn manage_share(profile: &str, repo_name: &str) -> Result<()> {
 
     let entries = client.get_repo_access(&repo.repo_id).await?;
     if entries.is_empty() {
-        println!("{}", style::paint(style::SUBTEXT1, "no collaborators yet"));
+        say!("{}", style::paint(style::SUBTEXT1, "no collaborators yet"));
         return Ok(());
     }
 
@@ -261,7 +274,7 @@ pub async fn manage_share(profile: &str, repo_name: &str) -> Result<()> {
         }
     }
 
-    println!(
+    say!(
         "{}",
         style::paint(
             style::GREEN,
@@ -273,7 +286,7 @@ pub async fn manage_share(profile: &str, repo_name: &str) -> Result<()> {
         for email in missing {
             message.push_str(&format!("\n  - {}", email));
         }
-        println!("{}", style::paint(style::YELLOW, &message));
+        say!("{}", style::paint(style::YELLOW, &message));
     }
     Ok(())
 }
@@ -282,7 +295,7 @@ pub async fn manage_accept(profile: &str, invite_id: &str) -> Result<()> {
     crate::commands::print_scope_user(profile);
     let client = client_only(profile).await?;
     client.accept_invite(invite_id).await?;
-    println!("{}", style::paint(style::GREEN, "invite accepted"));
+    say!("{}", style::paint(style::GREEN, "invite accepted"));
     Ok(())
 }
 
@@ -290,7 +303,7 @@ pub async fn manage_reject(profile: &str, invite_id: &str) -> Result<()> {
     crate::commands::print_scope_user(profile);
     let client = client_only(profile).await?;
     client.reject_invite(invite_id).await?;
-    println!("{}", style::paint(style::PEACH, "invite rejected"));
+    say!("{}", style::paint(style::PEACH, "invite rejected"));
     Ok(())
 }
 
@@ -309,14 +322,14 @@ fn print_access_table(entries: &[RepoAccessEntry]) {
         }
     }
 
-    println!(
+    say!(
         "{}  {}  {}  {}",
         style::bold(style::MAUVE, &format!("{:<width$}", "Email", width = email_width)),
         style::bold(style::MAUVE, &format!("{:<width$}", "Role", width = role_width)),
         style::bold(style::MAUVE, &format!("{:<width$}", "Status", width = status_width)),
         style::bold(style::MAUVE, &format!("{:<width$}", "Invited By", width = invited_width)),
     );
-    println!(
+    say!(
         "{}  {}  {}  {}",
         style::paint(style::SUBTEXT1, &format!("{:-<width$}", "", width = email_width)),
         style::paint(style::SUBTEXT1, &format!("{:-<width$}", "", width = role_width)),
@@ -342,7 +355,7 @@ fn print_access_table(entries: &[RepoAccessEntry]) {
         } else {
             style::TEXT
         };
-        println!("{}", style::paint(color, &line));
+        say!("{}", style::paint(color, &line));
     }
 }
 
@@ -356,14 +369,14 @@ fn print_invites(invites: &[InviteInfo]) {
         inviter_width = inviter_width.max(invite.invited_by.len());
     }
 
-    println!(
+    say!(
         "{}  {}  {}  {}",
         style::bold(style::MAUVE, &format!("{:<width$}", "Repo", width = repo_width)),
         style::bold(style::MAUVE, &format!("{:<width$}", "Role", width = role_width)),
         style::bold(style::MAUVE, &format!("{:<width$}", "Invited By", width = inviter_width)),
         style::bold(style::MAUVE, "Invite ID"),
     );
-    println!(
+    say!(
         "{}  {}  {}  {}",
         style::paint(style::SUBTEXT1, &format!("{:-<width$}", "", width = repo_width)),
         style::paint(style::SUBTEXT1, &format!("{:-<width$}", "", width = role_width)),
@@ -371,7 +384,7 @@ fn print_invites(invites: &[InviteInfo]) {
         style::paint(style::SUBTEXT1, "---------"),
     );
     for invite in invites {
-        println!(
+        say!(
             "{}  {}  {}  {}",
             style::paint(style::TEXT, &format!("{:<width$}", invite.repo_name, width = repo_width)),
             style::paint(style::TEXT, &format!("{:<width$}", invite.role, width = role_width)),
@@ -381,6 +394,133 @@ fn print_invites(invites: &[InviteInfo]) {
     }
 }
 
+pub async fn token_create(
+    profile: &str,
+    repo_name: &str,
+    branch: &str,
+    access: &str,
+    name: Option<String>,
+) -> Result<()> {
+    crate::commands::print_scope_user(profile);
+    if access != "read" && access != "write" {
+        return Err(MilieuError::Validation("access must be read or write".to_string()));
+    }
+    let (client, repo) = client_and_repo(profile, repo_name).await?;
+    let repo_key = keys::get_or_fetch_repo_key(profile, &client, &repo.repo_id).await?;
+
+    // The token gets its own keypair; only its copy of the repo key is stored.
+    let keypair = generate_keypair();
+    let wrapped_key = keys::wrap_repo_key_for_user(&keypair.public_key_b64, &repo_key).await?;
+    let created = client
+        .create_service_token(
+            &repo.repo_id,
+            &ServiceTokenRequest {
+                name,
+                branch: branch.to_string(),
+                access: access.to_string(),
+                wrapped_key,
+                algorithm: "x25519-hkdf-xchacha20poly1305".to_string(),
+            },
+        )
+        .await?;
+    let token = ServiceToken {
+        base_url: client.base_url().to_string(),
+        repo_id: repo.repo_id.clone(),
+        branch: branch.to_string(),
+        access: access.to_string(),
+        credential: created.token,
+        private_key: keypair.private_key_b64,
+    }
+    .encode()?;
+
+    if crate::output::is_json() {
+        return crate::output::emit(&serde_json::json!({
+            "id": created.id,
+            "name": created.name,
+            "branch": branch,
+            "access": access,
+            "token": token,
+        }));
+    }
+    say!(
+        "{}",
+        style::paint(
+            style::GREEN,
+            &format!(
+                "created token {} ({}) for {} -> {} with {} access",
+                created.name, created.id, repo.name, branch, access
+            )
+        )
+    );
+    say!(
+        "{}",
+        style::paint(
+            style::YELLOW,
+            "Set it as MILIEU_TOKEN on the deploy target. It is shown only once:"
+        )
+    );
+    say!("{}", style::bold(style::LAVENDER, &token));
+    Ok(())
+}
+
+pub async fn token_list(profile: &str, repo_name: &str) -> Result<()> {
+    crate::commands::print_scope_user(profile);
+    let (client, repo) = client_and_repo(profile, repo_name).await?;
+    let tokens = client.list_service_tokens(&repo.repo_id).await?;
+    if crate::output::is_json() {
+        return crate::output::emit(&tokens);
+    }
+    if tokens.is_empty() {
+        say!("{}", style::paint(style::SUBTEXT1, "no service tokens"));
+        return Ok(());
+    }
+
+    let id_width = tokens.iter().map(|t| t.id.len()).max().unwrap_or(0).max("ID".len());
+    let name_width = tokens.iter().map(|t| t.name.len()).max().unwrap_or(0).max("Name".len());
+    let branch_width = tokens
+        .iter()
+        .map(|t| t.branch.len())
+        .max()
+        .unwrap_or(0)
+        .max("Branch".len());
+    say!(
+        "{}  {}  {}  {}  {}",
+        style::bold(style::MAUVE, &format!("{:<width$}", "ID", width = id_width)),
+        style::bold(style::MAUVE, &format!("{:<width$}", "Name", width = name_width)),
+        style::bold(style::MAUVE, &format!("{:<width$}", "Branch", width = branch_width)),
+        style::bold(style::MAUVE, "Access"),
+        style::bold(style::MAUVE, "Last Used")
+    );
+    for token in tokens {
+        say!(
+            "{}  {}  {}  {}  {}",
+            style::paint(style::TEXT, &format!("{:<width$}", token.id, width = id_width)),
+            style::paint(style::TEXT, &format!("{:<width$}", token.name, width = name_width)),
+            style::paint(style::TEXT, &format!("{:<width$}", token.branch, width = branch_width)),
+            style::paint(style::TEXT, &format!("{:<6}", token.access)),
+            style::paint(
+                style::SUBTEXT1,
+                token.last_used_at.as_deref().unwrap_or("never")
+            )
+        );
+    }
+    Ok(())
+}
+
+pub async fn token_revoke(profile: &str, repo_name: &str, token_id: &str) -> Result<()> {
+    crate::commands::print_scope_user(profile);
+    let (client, repo) = client_and_repo(profile, repo_name).await?;
+    client.revoke_service_token(&repo.repo_id, token_id).await?;
+    say!(
+        "{}",
+        style::paint(
+            style::PEACH,
+            &format!("revoked token {} on {}", token_id, repo.name)
+        )
+    );
+    Ok(())
+}
+
 async fn client_only(profile: &str) -> Result<ApiClient> {
     let config = Config::load()?;
     let base_url = config.base_url_for(profile)?;
@@ -0,0 +1,216 @@
+use crate::api::ApiClient;
+use crate::auth;
+use crate::backup::Backup;
+use crate::cache;
+use crate::commands::pull::{merge_action, write_conflict, Merge};
+use crate::error::{MilieuError, Result};
+use crate::keys;
+use crate::manifest::Manifest;
+use crate::objects::{self, ObjectScope};
+use crate::repo::{is_env_file, manifest_path, write_secure};
+use crate::stash::{self, Stash};
+use crate::style;
+use std::fs;
+
+// Stashes files that status reports as modified_local or modified_both and
+// resets them to their last synced content, so a pull applies cleanly.
+pub async fn push(profile: &str, branch_override: Option<String>) -> Result<()> {
+    let manifest = Manifest::load(&manifest_path()?)?;
+    let branch_name = branch_override.unwrap_or_else(|| manifest.active_branch.clone());
+    let branch = manifest.find_branch(&branch_name)?;
+    crate::commands::print_scope_branch(&manifest, &branch_name);
+
+    let base_url = crate::commands::repo_base_url(profile, &manifest)?;
+
+    let token = auth::load_auth_token(profile)?;
+    let client = ApiClient::new(&base_url, Some(token))?;
+    let repo_key = keys::get_or_fetch_repo_key(profile, &client, &manifest.repo_id).await?;
+
+    let mut stash = Stash::new(&manifest.repo_id, &branch.name);
+    let mut resets = Vec::new();
+    for entry in &branch.files {
+        if entry.is_pattern() {
+            continue;
+        }
+        let path = entry.path();
+        manifest.validate_path(path)?;
+        let Ok(local) = fs::read(path) else {
+            continue;
+        };
+        let (Some(base_hex), Some(base_version)) =
+            (entry.last_synced_hash.as_deref(), entry.last_synced_version)
+        else {
+            continue;
+        };
+        let local_hash = blake3::hash(&local);
+        if local_hash.to_hex().as_str() == base_hex {
+            continue;
+        }
+
+        let scope = ObjectScope {
+            repo_id: &manifest.repo_id,
+            branch: &branch.name,
+            path,
+            tag: entry.tag(),
+        };
+        let latest = cache::latest(&client, &manifest.repo_id, &branch.name, path).await?;
+        if let Some(latest) = latest {
+            let remote = objects::open(&client, &repo_key, &scope, &latest).await?;
+            if blake3::hash(&remote) == local_hash {
+                continue;
+            }
+        }
+
+        let base_obj =
+            cache::version(&client, &manifest.repo_id, &branch.name, path, base_version).await?;
+        let base = objects::open(&client, &repo_key, &scope, &base_obj).await?;
+        if blake3::hash(&base).to_hex().as_str() != base_hex {
+            return Err(MilieuError::CommandFailed(format!(
+                "base v{} of {} no longer matches the manifest; run `milieu pull` first",
+                base_version, path
+            )));
+        }
+
+        stash.add(&repo_key, path, Some(base_version), base_hex, &local)?;
+        resets.push((path.to_string(), base));
+    }
+
+    if stash.files.is_empty() {
+        say!(
+            "{}",
+            style::paint(style::SUBTEXT1, "no local changes to stash")
+        );
+        return Ok(());
+    }
+
+    stash.save()?;
+    for (path, base) in resets {
+        write_secure(&path, &base)?;
+        say!(
+            "{}",
+            style::paint(style::GREEN, &format!("stashed {}", path))
+        );
+    }
+    Ok(())
+}
+
+// Reapplies a stash with pull's three-way merge: the stashed content is the
+// incoming side and the version it was stashed against is the base.
+pub async fn pop(profile: &str, index: usize) -> Result<()> {
+    let manifest = Manifest::load(&manifest_path()?)?;
+    crate::commands::print_scope_repo(&manifest);
+    let stash = stash::get(index)?;
+    if stash.repo_id != manifest.repo_id {
+        return Err(MilieuError::CommandFailed(
+            "stash belongs to a different repo".to_string(),
+        ));
+    }
+    let repo_key = crate::commands::local_repo_key(profile, &manifest).await?;
+
+    let mut backup = Backup::new("stash pop", &manifest.repo_id, &repo_key)?;
+    let mut conflicts = 0;
+    for file in &stash.files {
+        let stashed = stash.read(&repo_key, file)?;
+        let local = fs::read(&file.path).ok();
+        let base = blake3::Hash::from_hex(&file.base_hash).ok();
+        let action = merge_action(local.as_deref().map(blake3::hash), base, blake3::hash(&stashed));
+        match action {
+            Merge::Take => {
+                backup.save(&file.path)?;
+                write_secure(&file.path, &stashed)?;
+                say!(
+                    "{}",
+                    style::paint(style::GREEN, &format!("restored {}", file.path))
+                );
+            }
+            Merge::UpToDate | Merge::KeepLocal => {
+                say!(
+                    "{}",
+                    style::paint(style::SUBTEXT1, &format!("unchanged {}", file.path))
+                );
+            }
+            Merge::Conflict => {
+                let local = local.unwrap_or_default();
+                let opaque = !is_env_file(&file.path);
+                write_conflict(&mut backup, &file.path, &local, &stashed, "stash", opaque)?;
+                conflicts += 1;
+            }
+        }
+    }
+    backup.finish()?;
+
+    if conflicts > 0 {
+        say!(
+            "{}",
+            style::paint(
+                style::YELLOW,
+                &format!(
+                    "kept stash@{{{}}}; run `milieu stash drop {}` once conflicts are resolved",
+                    index, index
+                )
+            )
+        );
+    } else {
+        stash.drop()?;
+    }
+    Ok(())
+}
+
+pub fn list() -> Result<()> {
+    let stashes = stash::list()?;
+    if crate::output::is_json() {
+        let entries: Vec<_> = stashes
+            .iter()
+            .enumerate()
+            .map(|(index, stash)| {
+                let files: Vec<_> = stash
+                    .files
+                    .iter()
+                    .map(|file| {
+                        serde_json::json!({ "path": file.path, "base_version": file.base_version })
+                    })
+                    .collect();
+                serde_json::json!({
+                    "index": index,
+                    "branch": stash.branch,
+                    "created_at": stash.created_at,
+                    "files": files,
+                })
+            })
+            .collect();
+        return crate::output::emit(&entries);
+    }
+    if stashes.is_empty() {
+        say!("{}", style::paint(style::SUBTEXT1, "no stashes"));
+        return Ok(());
+    }
+    for (index, stash) in stashes.iter().enumerate() {
+        let files: Vec<String> = stash
+            .files
+            .iter()
+            .map(|file| match file.base_version {
+                Some(version) => format!("{}@v{}", file.path, version),
+                None => file.path.clone(),
+            })
+            .collect();
+        say!(
+            "{} {}",
+            style::bold(style::MAUVE, &format!("stash@{{{}}}", index)),
+            style::paint(
+                style::TEXT,
+                &format!("branch {} ({}): {}", stash.branch, stash.created_at, files.join(", "))
+            )
+        );
+    }
+    Ok(())
+}
+
+pub fn drop(index: usize) -> Result<()> {
+    let stash = stash::get(index)?;
+    stash.drop()?;
+    say!(
+        "{}",
+        style::paint(style::GREEN, &format!("dropped stash@{{{}}}", index))
+    );
+    Ok(())
+}
@@ -1,54 +1,83 @@
-use crate::api::ApiClient;
+use crate::api::{ApiClient, ObjectResponse};
 use crate::auth;
-use crate::config::Config;
-use crate::crypto::{aad_for, decrypt_bytes};
-use crate::error::Result;
+use crate::cache;
+use crate::error::{MilieuError, Result};
 use crate::keys;
-use crate::manifest::Manifest;
-use crate::repo::{manifest_path, project_root, validate_env_path};
+use crate::manifest::{Branch, FileEntry, Manifest};
+use crate::objects::{self, ObjectScope};
+use crate::repo::{
+    expand_pattern, is_allowed_file, is_env_file, manifest_path, pattern_matches, project_root,
+};
 use crate::style;
 use blake3::{Hash, Hasher};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::Path;
 
-pub async fn run(profile: &str, json: bool) -> Result<()> {
-    let manifest = Manifest::load(&manifest_path()?)?;
+// Returns whether every tracked file matches the remote, for `--exit-code`.
+pub async fn run(profile: &str, json: bool, offline: bool) -> Result<bool> {
+    let mut manifest = Manifest::load(&manifest_path()?)?;
     crate::commands::print_scope_repo(&manifest);
 
-    let config = Config::load()?;
-    let mut base_url = config.base_url_for(profile)?;
-    if let Some(remote) = &manifest.remote {
-        if let Some(url) = &remote.base_url {
-            base_url = url.clone();
-        }
-    }
+    let base_url = crate::commands::repo_base_url(profile, &manifest)?;
 
-    let token = auth::load_auth_token(profile)?;
-    let client = ApiClient::new(&base_url, Some(token))?;
+    let token = if offline {
+        auth::load_auth_token(profile).ok()
+    } else {
+        Some(auth::load_auth_token(profile)?)
+    };
+    let client = ApiClient::new(&base_url, token)?.with_offline(offline);
     let repo_key = keys::get_or_fetch_repo_key(profile, &client, &manifest.repo_id).await?;
 
     let tracked_all: HashSet<String> = manifest
         .branches
         .iter()
-        .flat_map(|branch| branch.files.iter().map(|entry| entry.path().to_string()))
+        .flat_map(|branch| branch.files.iter().filter(|entry| !entry.is_pattern()))
+        .map(|entry| entry.path().to_string())
+        .collect();
+    let patterns_all: Vec<String> = manifest
+        .branches
+        .iter()
+        .flat_map(|branch| branch.patterns())
+        .map(|entry| entry.path)
         .collect();
 
-    let untracked = find_untracked(&tracked_all)?;
-    let remote_manifest = match client.get_manifest(&manifest.repo_id).await {
-        Ok(value) => Some(value),
-        Err(err) => {
-            println!(
-                "{}",
-                style::paint(
-                    style::PEACH,
-                    &format!("warning: remote manifest unavailable ({})", err)
-                )
-            );
-            None
+    let mut untracked = find_untracked(&tracked_all, &manifest.allowed_files)?;
+    untracked.retain(|path| !patterns_all.iter().any(|pattern| pattern_matches(pattern, path)));
+    // A service token can only read its own branch.
+    if let Some(token) = crate::token::active()? {
+        manifest.branches.retain(|branch| branch.name == token.branch);
+    }
+    if offline && !json {
+        crate::commands::print_offline_notice();
+    }
+    let remote_manifest = if offline {
+        None
+    } else {
+        match client.get_manifest(&manifest.repo_id).await {
+            Ok(value) => Some(value),
+            // Every later request would fail the same way.
+            Err(err @ (MilieuError::SessionExpired(_) | MilieuError::Forbidden(_))) => {
+                return Err(err)
+            }
+            Err(err) => {
+                say!(
+                    "{}",
+                    style::paint(
+                        style::PEACH,
+                        &format!("warning: remote manifest unavailable ({})", err)
+                    )
+                );
+                None
+            }
         }
     };
 
+    // Remote-only files are listed in the table view only.
+    let remote_only = if json { None } else { remote_manifest.as_ref() };
+    let remotes = fetch_remotes(&client, &repo_key, &manifest, remote_only).await?;
+    let mut in_sync = true;
+
     if json {
         let mut entries = Vec::new();
         for branch in &manifest.branches {
@@ -62,41 +91,52 @@ pub async fn run(profile: &str, json: bool) -> Result<()> {
             }
             for entry in &branch.files {
                 let path = entry.path();
-                validate_env_path(path)?;
+                if entry.is_pattern() {
+                    entries.push(serde_json::json!({
+                        "branch": branch.name,
+                        "pattern": path,
+                        "tag": entry.tag(),
+                        "matches": pattern_file_matches(&manifest, branch, path)?,
+                    }));
+                    continue;
+                }
+                manifest.validate_path(path)?;
                 let local = local_status(path)?;
-                let remote = client
-                    .get_latest(&manifest.repo_id, &branch.name, path)
-                    .await?;
-                let diff = change_kind(
-                    &local,
-                    remote.as_ref(),
-                    &repo_key,
-                    &manifest,
-                    &branch.name,
-                    entry,
-                );
-                entries.push(serde_json::json!({
+                let remote = remotes.get(&(branch.name.clone(), path.to_string()));
+                let (remote, remote_plain) = split_remote(remote);
+                let diff = change_kind(&local, remote_plain, entry);
+                in_sync &= is_synced(diff);
+                let mut value = serde_json::json!({
                     "branch": branch.name,
                     "path": path,
                     "tag": entry.tag(),
                     "local": local.label(),
-                    "remote": remote.as_ref().map(|_| "present").unwrap_or("missing"),
+                    "remote": remote.map(|_| "present").unwrap_or("missing"),
                     "status": change_kind_str(diff),
                     "local_version": entry.last_synced_version,
-                    "remote_version": remote.as_ref().and_then(|obj| obj.version),
-                }));
+                    "remote_version": remote.and_then(|obj| obj.version),
+                });
+                if manifest.is_opaque(path) {
+                    let (local_blob, remote_blob) = blob_info(path, remote_plain);
+                    value["opaque"] = serde_json::json!(true);
+                    value["local_size"] = serde_json::json!(local_blob.as_ref().map(|b| b.size));
+                    value["local_hash"] = serde_json::json!(local_blob.map(|b| b.hash));
+                    value["remote_size"] = serde_json::json!(remote_blob.as_ref().map(|b| b.size));
+                    value["remote_hash"] = serde_json::json!(remote_blob.map(|b| b.hash));
+                }
+                entries.push(value);
             }
         }
-        println!("{}", serde_json::to_string_pretty(&entries)?);
-        return Ok(());
+        crate::output::emit(&entries)?;
+        return Ok(in_sync);
     }
 
     if !untracked.is_empty() {
-        println!("{}", style::bold(style::MAUVE, "untracked:"));
+        say!("{}", style::bold(style::MAUVE, "untracked:"));
         for path in &untracked {
-            println!("{}", style::paint(style::SKY, &format!("  ? {}", path)));
+            say!("{}", style::paint(style::SKY, &format!("  ? {}", path)));
         }
-        println!(
+        say!(
             "{}",
             style::paint(style::SUBTEXT1, "run `milieu add <file>` to track")
         );
@@ -104,57 +144,47 @@ pub async fn run(profile: &str, json: bool) -> Result<()> {
 
     for branch in &manifest.branches {
         let mut entries = Vec::new();
-        let mut remote_only_manifest = Vec::new();
-
-        if let Some(remote_manifest) = &remote_manifest {
-            if let Some(remote_branch) = remote_manifest
-                .branches
-                .iter()
-                .find(|b| b.name == branch.name)
-            {
-                for file in &remote_branch.files {
-                    let path = file.path().to_string();
-                    if !branch.files.iter().any(|f| f.path() == path) {
-                        remote_only_manifest.push(path);
-                    }
-                }
-            }
-        }
 
         for entry in &branch.files {
+            if entry.is_pattern() {
+                continue;
+            }
             let path = entry.path();
-            validate_env_path(path)?;
+            manifest.validate_path(path)?;
             let local = local_status(path)?;
-            let remote = client
-                .get_latest(&manifest.repo_id, &branch.name, path)
-                .await?;
-            let diff = change_kind(
-                &local,
-                remote.as_ref(),
-                &repo_key,
-                &manifest,
-                &branch.name,
-                entry,
-            );
+            let remote = remotes.get(&(branch.name.clone(), path.to_string()));
+            let (remote, remote_plain) = split_remote(remote);
+            let diff = change_kind(&local, remote_plain, entry);
+            let detail = if manifest.is_opaque(path) {
+                let (local_blob, remote_blob) = blob_info(path, remote_plain);
+                Some(format!(
+                    "local {} | remote {}",
+                    format_blob(local_blob),
+                    format_blob(remote_blob)
+                ))
+            } else {
+                None
+            };
             let entry_status = StatusEntry {
                 path: path.to_string(),
                 kind: diff,
                 local_version: entry.last_synced_version,
-                remote_version: remote.as_ref().and_then(|obj| obj.version),
+                remote_version: remote.and_then(|obj| obj.version),
+                detail,
             };
 
             entries.push(entry_status);
         }
 
-        for path in &remote_only_manifest {
-            let remote = client
-                .get_latest(&manifest.repo_id, &branch.name, path)
-                .await?;
+        for path in remote_only_paths(branch, remote_manifest.as_ref()) {
+            let remote = remotes.get(&(branch.name.clone(), path.clone()));
+            let (remote, _) = split_remote(remote);
             let entry_status = StatusEntry {
-                path: path.to_string(),
+                path,
                 kind: ChangeKind::NewRemote,
                 local_version: None,
-                remote_version: remote.as_ref().and_then(|obj| obj.version),
+                remote_version: remote.and_then(|obj| obj.version),
+                detail: None,
             };
             entries.push(entry_status);
         }
@@ -164,12 +194,26 @@ pub async fn run(profile: &str, json: bool) -> Result<()> {
         } else {
             format!("branch: {}", branch.name)
         };
-        println!("{}", style::bold(style::MAUVE, &label));
+        say!("{}", style::bold(style::MAUVE, &label));
+
+        for pattern in branch.patterns() {
+            let matches = pattern_file_matches(&manifest, branch, pattern.path())?;
+            let summary = if matches.is_empty() {
+                "(no matches)".to_string()
+            } else {
+                matches.join(", ")
+            };
+            say!(
+                "{}",
+                style::paint(style::SKY, &format!("  {} -> {}", pattern.path(), summary))
+            );
+        }
 
         if entries.is_empty() {
-            println!("{}", style::paint(style::SUBTEXT1, "  (no tracked files)"));
+            say!("{}", style::paint(style::SUBTEXT1, "  (no tracked files)"));
             continue;
         }
+        in_sync &= entries.iter().all(|entry| is_synced(entry.kind));
 
         let mut file_width = "File".len();
         let mut local_width = "Local".len();
@@ -190,14 +234,14 @@ pub async fn run(profile: &str, json: bool) -> Result<()> {
             })
             .collect();
 
-        println!(
+        say!(
             "  {}  {}  {}  {}",
             style::bold(style::MAUVE, &format!("{:<width$}", "File", width = file_width)),
             style::bold(style::MAUVE, &format!("{:<width$}", "Local", width = local_width)),
             style::bold(style::MAUVE, &format!("{:<width$}", "Remote", width = remote_width)),
             style::bold(style::MAUVE, &format!("{:<width$}", "Diff", width = diff_width)),
         );
-        println!(
+        say!(
             "  {}  {}  {}  {}",
             style::paint(style::SUBTEXT1, &format!("{:-<width$}", "", width = file_width)),
             style::paint(style::SUBTEXT1, &format!("{:-<width$}", "", width = local_width)),
@@ -218,11 +262,14 @@ pub async fn run(profile: &str, json: bool) -> Result<()> {
                 remote_width = remote_width,
                 diff_width = diff_width
             );
-            println!("{}", style::paint(color, &line));
+            say!("{}", style::paint(color, &line));
+            if let Some(detail) = &entry.detail {
+                say!("{}", style::paint(style::SUBTEXT1, &format!("    {}", detail)));
+            }
         }
 
         if entries.iter().any(|e| matches!(e.kind, ChangeKind::NewRemote | ChangeKind::ModifiedRemote | ChangeKind::ModifiedBoth)) {
-            println!(
+            say!(
                 "{}",
                 style::paint(
                     style::SUBTEXT1,
@@ -230,7 +277,7 @@ pub async fn run(profile: &str, json: bool) -> Result<()> {
                 )
             );
         }
-        println!(
+        say!(
             "{}",
             style::paint(
                 style::SUBTEXT1,
@@ -239,7 +286,120 @@ pub async fn run(profile: &str, json: bool) -> Result<()> {
         );
     }
 
-    Ok(())
+    Ok(in_sync)
+}
+
+// Concrete files a pattern covers: what it matches in the working tree plus
+// anything already recorded in the manifest from an earlier push or pull.
+fn pattern_file_matches(manifest: &Manifest, branch: &Branch, pattern: &str) -> Result<Vec<String>> {
+    let mut matches = expand_pattern(pattern, &manifest.allowed_files)?;
+    for path in branch.pattern_matches(pattern) {
+        if !matches.contains(&path) {
+            matches.push(path);
+        }
+    }
+    matches.sort();
+    Ok(matches)
+}
+
+fn remote_only_paths(branch: &Branch, remote_manifest: Option<&Manifest>) -> Vec<String> {
+    let Some(remote_branch) =
+        remote_manifest.and_then(|remote| remote.branches.iter().find(|b| b.name == branch.name))
+    else {
+        return Vec::new();
+    };
+    remote_branch
+        .files
+        .iter()
+        .map(|file| file.path().to_string())
+        .filter(|path| !branch.files.iter().any(|f| f.path() == path))
+        .collect()
+}
+
+struct RemoteState {
+    object: Option<ObjectResponse>,
+    // Decrypted content; the inner `None` means the object could not be opened.
+    plain: Option<Option<Vec<u8>>>,
+}
+
+type Remotes = HashMap<(String, String), RemoteState>;
+
+// Latest objects for every tracked file (and remote-only file), one batch
+// per branch, keyed by (branch, path). Decryption runs concurrently since
+// chunked objects need further requests.
+async fn fetch_remotes(
+    client: &ApiClient,
+    repo_key: &[u8; 32],
+    manifest: &Manifest,
+    remote_manifest: Option<&Manifest>,
+) -> Result<Remotes> {
+    let mut jobs = Vec::new();
+    for branch in &manifest.branches {
+        let mut paths = Vec::new();
+        let mut tags = Vec::new();
+        for entry in branch.files.iter().filter(|entry| !entry.is_pattern()) {
+            manifest.validate_path(entry.path())?;
+            paths.push(entry.path().to_string());
+            tags.push(Some(entry.tag().map(str::to_string)));
+        }
+        // Remote-only files are not decrypted; only their version is shown.
+        for path in remote_only_paths(branch, remote_manifest) {
+            paths.push(path);
+            tags.push(None);
+        }
+        let objects = cache::latest_many(client, &manifest.repo_id, &branch.name, &paths).await?;
+        for ((path, tag), object) in paths.into_iter().zip(tags).zip(objects) {
+            jobs.push((branch.name.clone(), path, tag, object));
+        }
+    }
+
+    let results = crate::commands::run_bounded(jobs, |(branch, path, tag, object)| async move {
+        let plain = match (&object, tag) {
+            (Some(obj), Some(tag)) => {
+                let scope = ObjectScope {
+                    repo_id: &manifest.repo_id,
+                    branch: &branch,
+                    path: &path,
+                    tag: tag.as_deref(),
+                };
+                Some(objects::open(client, repo_key, &scope, obj).await.ok())
+            }
+            _ => None,
+        };
+        ((branch, path), RemoteState { object, plain })
+    })
+    .await;
+    Ok(results.into_iter().collect())
+}
+
+fn split_remote(remote: Option<&RemoteState>) -> (Option<&ObjectResponse>, Option<Option<&[u8]>>) {
+    match remote {
+        Some(state) => (
+            state.object.as_ref(),
+            state.plain.as_ref().map(|data| data.as_deref()),
+        ),
+        None => (None, None),
+    }
+}
+
+fn blob_info(
+    path: &str,
+    remote: Option<Option<&[u8]>>,
+) -> (Option<BlobInfo>, Option<BlobInfo>) {
+    let describe = |data: &[u8]| BlobInfo {
+        size: data.len(),
+        hash: blake3::hash(data).to_hex().to_string(),
+    };
+    let local = fs::read(path).ok().map(|data| describe(&data));
+    let remote = remote.flatten().map(describe);
+    (local, remote)
+}
+
+fn format_blob(blob: Option<BlobInfo>) -> String {
+    match blob {
+        Some(blob) => format!("{} bytes, blake3 {}", blob.size, &blob.hash[..12]),
+        None => "-".to_string(),
+    }
 }
 
 fn local_status(path: &str) -> Result<LocalStatus> {
@@ -281,20 +441,23 @@ enum ChangeKind {
     None,
 }
 
+struct BlobInfo {
+    size: usize,
+    hash: String,
+}
+
 struct StatusEntry {
     path: String,
     kind: ChangeKind,
     local_version: Option<u32>,
     remote_version: Option<u32>,
+    detail: Option<String>,
 }
 
 fn change_kind(
     local: &LocalStatus,
-    remote: Option<&crate::api::ObjectResponse>,
-    repo_key: &[u8; 32],
-    manifest: &Manifest,
-    branch: &str,
-    entry: &crate::manifest::FileEntry,
+    remote: Option<Option<&[u8]>>,
+    entry: &FileEntry,
 ) -> ChangeKind {
     let base_hash = entry
         .last_synced_hash
@@ -305,13 +468,9 @@ fn change_kind(
         (LocalStatus::Missing, None) => ChangeKind::None,
         (LocalStatus::Missing, Some(_)) => ChangeKind::NewRemote,
         (LocalStatus::Present { .. }, None) => ChangeKind::NewLocal,
-        (LocalStatus::Present { hash: local_hash }, Some(remote_obj)) => {
-            let schema_version = remote_obj.schema_version;
-            let aad = aad_for(schema_version, &manifest.repo_id, branch, entry.path(), entry.tag());
-            let remote_hash = match decrypt_bytes(repo_key, &aad, &remote_obj.nonce, &remote_obj.ciphertext) {
-                Ok(plaintext) => blake3::hash(&plaintext),
-                Err(_) => return ChangeKind::ModifiedUnknown,
-            };
+        (LocalStatus::Present { .. }, Some(None)) => ChangeKind::ModifiedUnknown,
+        (LocalStatus::Present { hash: local_hash }, Some(Some(plaintext))) => {
+            let remote_hash = blake3::hash(plaintext);
 
             if let Some(base) = base_hash {
                 let local_changed = local_hash != &base;
@@ -338,6 +497,10 @@ fn change_kind(
     }
 }
 
+fn is_synced(kind: ChangeKind) -> bool {
+    matches!(kind, ChangeKind::Clean | ChangeKind::None)
+}
+
 fn change_kind_str(kind: ChangeKind) -> &'static str {
     match kind {
         ChangeKind::Clean => "no_change",
@@ -391,10 +554,10 @@ fn status_color(kind: ChangeKind) -> style::Rgb {
 
 // path validation centralized in repo::validate_env_path
 
-fn find_untracked(tracked: &HashSet<String>) -> Result<Vec<String>> {
+fn find_untracked(tracked: &HashSet<String>, allowed: &[String]) -> Result<Vec<String>> {
     let root = project_root()?;
     let mut out = Vec::new();
-    collect_env_files(&root, &root, tracked, &mut out)?;
+    collect_env_files(&root, &root, tracked, allowed, &mut out)?;
     out.sort();
     Ok(out)
 }
@@ -403,6 +566,7 @@ fn collect_env_files(
     root: &Path,
     dir: &Path,
     tracked: &HashSet<String>,
+    allowed: &[String],
     out: &mut Vec<String>,
 ) -> Result<()> {
     for entry in fs::read_dir(dir)? {
@@ -415,16 +579,16 @@ fn collect_env_files(
             if name == ".milieu" || name == ".git" || name == "target" || name == "node_modules" {
                 continue;
             }
-            collect_env_files(root, &path, tracked, out)?;
+            collect_env_files(root, &path, tracked, allowed, out)?;
             continue;
         }
 
-        if name == ".env" || name.starts_with(".env.") {
-            let rel = path.strip_prefix(root).unwrap_or(&path);
-            let rel_str = rel.to_string_lossy().to_string();
-            if !tracked.contains(&rel_str) {
-                out.push(rel_str);
-            }
+        let rel = path.strip_prefix(root).unwrap_or(&path);
+        let rel_str = rel.to_string_lossy().to_string();
+        if (is_env_file(&rel_str) || is_allowed_file(&rel_str, allowed))
+            && !tracked.contains(&rel_str)
+        {
+            out.push(rel_str);
         }
     }
     Ok(())
@@ -0,0 +1,54 @@
+use crate::backup;
+use crate::error::{MilieuError, Result};
+use crate::manifest::Manifest;
+use crate::repo::manifest_path;
+use crate::style;
+
+pub async fn run(profile: &str) -> Result<()> {
+    let manifest = Manifest::load(&manifest_path()?)?;
+    crate::commands::print_scope_repo(&manifest);
+
+    let index = backup::latest()?.ok_or_else(|| {
+        MilieuError::CommandFailed("nothing to undo; no pull or checkout backups found".to_string())
+    })?;
+    if index.repo_id != manifest.repo_id {
+        return Err(MilieuError::CommandFailed(
+            "latest backup belongs to a different repo".to_string(),
+        ));
+    }
+
+    let repo_key = crate::commands::local_repo_key(profile, &manifest).await?;
+
+    backup::restore(&index, &repo_key)?;
+
+    for file in &index.files {
+        let action = if file.blob.is_some() { "restored" } else { "removed" };
+        say!(
+            "{}",
+            style::paint(style::GREEN, &format!("{} {}", action, file.path))
+        );
+    }
+    say!(
+        "{}",
+        style::paint(
+            style::SUBTEXT1,
+            &format!("undid {} from {}", index.command, index.created_at)
+        )
+    );
+    if crate::output::is_json() {
+        let files: Vec<_> = index
+            .files
+            .iter()
+            .map(|file| {
+                let action = if file.blob.is_some() { "restored" } else { "removed" };
+                serde_json::json!({ "path": file.path, "action": action })
+            })
+            .collect();
+        crate::output::emit(&serde_json::json!({
+            "command": index.command,
+            "created_at": index.created_at,
+            "files": files,
+        }))?;
+    }
+    Ok(())
+}
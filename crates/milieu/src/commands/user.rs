@@ -1,11 +1,13 @@
-use crate::api::{ApiClient, UmkRequest};
+use crate::api::{
+    AccountDeleteRequest, ApiClient, PasswordChangeRequest, RepoTransfer, UmkRequest,
+};
 use crate::auth;
-use crate::commands::prompt;
+use crate::commands::confirm;
 use crate::config::Config;
 use crate::crypto::{
     derive_key, derive_keypair_from_umk, encode_key, encrypt_umk_blob, generate_umk, KdfParams,
 };
-use crate::error::Result;
+use crate::error::{MilieuError, Result};
 use crate::keys;
 use crate::style;
 use bip39::{Language, Mnemonic};
@@ -13,6 +15,8 @@ use chrono::{DateTime, Utc};
 use rand_core::{OsRng, RngCore};
 
 const ROTATE_DAYS: i64 = 90;
+const QUIZ_WORDS: usize = 3;
+const QUIZ_ATTEMPTS: usize = 3;
 
 fn age_days(updated_at: &str) -> Option<i64> {
     let parsed = DateTime::parse_from_rfc3339(updated_at).ok()?;
@@ -24,7 +28,7 @@ fn warn_if_old(updated_at: Option<&str>) {
     let Some(updated_at) = updated_at else { return };
     if let Some(days) = age_days(updated_at) {
         if days >= ROTATE_DAYS {
-            println!(
+            say!(
                 "{}",
                 style::paint(
                     style::YELLOW,
@@ -48,29 +52,46 @@ pub async fn info(profile: &str) -> Result<()> {
     let email = auth::load_email(profile)?.unwrap_or_else(|| "-".to_string());
     let user_id = auth::load_user_id(profile).unwrap_or_else(|_| "-".to_string());
 
-    let repos = client.get_repos().await.unwrap_or_default();
+    let repos = match client.get_repos().await {
+        Ok(repos) => repos,
+        Err(err @ MilieuError::SessionExpired(_)) => return Err(err),
+        Err(_) => Vec::new(),
+    };
     let sessions = client.get_sessions().await.unwrap_or_default();
     let active_sessions = sessions.iter().filter(|s| s.active).count();
     let user_key = client.get_user_key().await.ok().flatten();
     let umk = client.get_umk().await.ok().flatten();
 
-    println!("{}", style::bold(style::MAUVE, "User"));
-    println!("{} {}", style::paint(style::SUBTEXT1, "Email:"), email);
-    println!("{} {}", style::paint(style::SUBTEXT1, "User ID:"), user_id);
-    println!(
+    say!("{}", style::bold(style::MAUVE, "User"));
+    say!("{} {}", style::paint(style::SUBTEXT1, "Email:"), email);
+    say!("{} {}", style::paint(style::SUBTEXT1, "User ID:"), user_id);
+    say!(
         "{} {}",
         style::paint(style::SUBTEXT1, "Repos:"),
         repos.len()
     );
-    println!(
+    say!(
         "{} {}",
         style::paint(style::SUBTEXT1, "Active sessions:"),
         active_sessions
     );
+    if let Some(expires_at) = auth::load_expires_at(profile)? {
+        let remaining = expires_at - Utc::now();
+        let value = if remaining > chrono::Duration::zero() {
+            format!(
+                "expires in {} ({})",
+                crate::commands::login::format_remaining(remaining),
+                expires_at.format("%Y-%m-%d %H:%M UTC")
+            )
+        } else {
+            "expired".to_string()
+        };
+        say!("{} {}", style::paint(style::SUBTEXT1, "This session:"), value);
+    }
 
     if let Some(user_key) = &user_key {
         if let Some(days) = age_days(&user_key.updated_at) {
-            println!(
+            say!(
                 "{} {} days (rotate every {})",
                 style::paint(style::SUBTEXT1, "User key age:"),
                 days,
@@ -81,7 +102,7 @@ pub async fn info(profile: &str) -> Result<()> {
     if let Some(umk) = &umk {
         if let Some(updated_at) = umk.updated_at.as_deref() {
             if let Some(days) = age_days(updated_at) {
-                println!(
+                say!(
                     "{} {} days",
                     style::paint(style::SUBTEXT1, "Recovery key age:"),
                     days
@@ -91,20 +112,34 @@ pub async fn info(profile: &str) -> Result<()> {
     }
 
     warn_if_old(user_key.as_ref().map(|k| k.updated_at.as_str()));
+    if crate::output::is_json() {
+        crate::output::emit(&serde_json::json!({
+            "email": email,
+            "user_id": user_id,
+            "repos": repos.len(),
+            "active_sessions": active_sessions,
+            "session_expires_at": auth::load_expires_at(profile)?.map(|at| at.to_rfc3339()),
+            "user_key_age_days": user_key.as_ref().and_then(|k| age_days(&k.updated_at)),
+            "recovery_key_age_days": umk
+                .as_ref()
+                .and_then(|umk| umk.updated_at.as_deref())
+                .and_then(age_days),
+        }))?;
+    }
     Ok(())
 }
 
-pub async fn rotate_keys(profile: &str) -> Result<()> {
+pub async fn rotate_keys(profile: &str, skip_verify: bool) -> Result<()> {
     crate::commands::print_scope_user(profile);
-    let confirm = prompt(
-        "Rotate recovery phrase + user keys? This will rewrap repo keys. [y/N] ",
-    )?;
-    let confirm = confirm.trim().to_lowercase();
-    if confirm != "y" && confirm != "yes" {
-        println!("{}", style::paint(style::SUBTEXT1, "aborted"));
+    require_phrase_quiz(skip_verify)?;
+    if !confirm("Rotate recovery phrase + user keys? This will rewrap repo keys. [y/N] ")? {
+        say!("{}", style::paint(style::SUBTEXT1, "aborted"));
         return Ok(());
     }
+    rotate_keys_now(profile, skip_verify).await
+}
 
+async fn rotate_keys_now(profile: &str, skip_verify: bool) -> Result<()> {
     let config = Config::load()?;
     let base_url = config.base_url_for(profile)?;
     let token = auth::load_auth_token(profile)?;
@@ -132,7 +167,7 @@ pub async fn rotate_keys(profile: &str) -> Result<()> {
         version: 1,
     };
     client.put_umk(&request).await?;
-    auth::store_phrase(profile, &phrase)?;
+    auth::store_phrase(profile, config.store_phrase.then_some(phrase.as_str()))?;
     auth::store_umk(profile, &encode_key(&umk))?;
 
     let keypair = derive_keypair_from_umk(&umk)?;
@@ -147,15 +182,16 @@ pub async fn rotate_keys(profile: &str) -> Result<()> {
             .await?;
     }
 
-    println!(
+    say!(
         "{}",
         style::paint(
             style::YELLOW,
             "New recovery phrase (save this for new devices):"
         )
     );
-    println!("{}", style::bold(style::LAVENDER, &phrase));
-    println!("{}", style::paint(style::GREEN, "Rotated keys successfully."));
+    say!("{}", style::bold(style::LAVENDER, &phrase));
+    quiz_phrase(profile, &phrase, skip_verify)?;
+    say!("{}", style::paint(style::GREEN, "Rotated keys successfully."));
     Ok(())
 }
 
@@ -166,72 +202,487 @@ pub async fn sessions(profile: &str) -> Result<()> {
     let token = auth::load_auth_token(profile)?;
     let client = ApiClient::new(&base_url, Some(token))?;
     let sessions = client.get_sessions().await?;
+    if crate::output::is_json() {
+        return crate::output::emit(&sessions);
+    }
     if sessions.is_empty() {
-        println!("{}", style::paint(style::SUBTEXT1, "no active sessions"));
+        say!("{}", style::paint(style::SUBTEXT1, "no active sessions"));
         return Ok(());
     }
 
     let mut host_width = "Host".len();
+    let mut token_width = "Token".len();
     let mut active_width = "Active".len();
     let mut created_width = "Created".len();
-    let mut expires_width = "Expires".len();
     for session in &sessions {
-        host_width = host_width.max(session.host.len());
+        host_width = host_width.max(session.host.len() + if session.current { 2 } else { 0 });
+        token_width = token_width.max(session.token_suffix.len());
         active_width = active_width.max(if session.active { 3 } else { 2 });
         created_width = created_width.max(session.created_at.len());
-        expires_width = expires_width.max(session.expires_at.len());
     }
 
-    println!(
-        "{}  {}  {}  {}",
+    say!(
+        "{}  {}  {}  {}  {}",
         style::bold(style::MAUVE, &format!("{:<width$}", "Host", width = host_width)),
+        style::bold(style::MAUVE, &format!("{:<width$}", "Token", width = token_width)),
         style::bold(style::MAUVE, &format!("{:<width$}", "Active", width = active_width)),
         style::bold(style::MAUVE, &format!("{:<width$}", "Created", width = created_width)),
-        style::bold(style::MAUVE, &format!("{:<width$}", "Expires", width = expires_width)),
+        style::bold(style::MAUVE, "Expires"),
     );
-    println!(
-        "{}  {}  {}  {}",
+    say!(
+        "{}  {}  {}  {}  {}",
         style::paint(style::SUBTEXT1, &format!("{:-<width$}", "", width = host_width)),
+        style::paint(style::SUBTEXT1, &format!("{:-<width$}", "", width = token_width)),
         style::paint(style::SUBTEXT1, &format!("{:-<width$}", "", width = active_width)),
         style::paint(style::SUBTEXT1, &format!("{:-<width$}", "", width = created_width)),
-        style::paint(style::SUBTEXT1, &format!("{:-<width$}", "", width = expires_width)),
+        style::paint(style::SUBTEXT1, "-------"),
     );
     for session in sessions {
         let active = if session.active { "yes" } else { "no" };
-        println!(
-            "{}  {}  {}  {}",
-            style::paint(style::TEXT, &format!("{:<width$}", session.host, width = host_width)),
+        // "*" marks the session running this command.
+        let host = if session.current {
+            format!("{} *", session.host)
+        } else {
+            session.host
+        };
+        say!(
+            "{}  {}  {}  {}  {}",
+            style::paint(style::TEXT, &format!("{:<width$}", host, width = host_width)),
+            style::paint(
+                style::TEXT,
+                &format!("{:<width$}", session.token_suffix, width = token_width)
+            ),
             style::paint(style::TEXT, &format!("{:<width$}", active, width = active_width)),
             style::paint(
                 style::SUBTEXT1,
                 &format!("{:<width$}", session.created_at, width = created_width)
             ),
+            style::paint(style::SUBTEXT1, &session.expires_at),
+        );
+    }
+    Ok(())
+}
+
+pub async fn revoke_sessions(
+    profile: &str,
+    suffix: Option<String>,
+    all_others: bool,
+    rotate: bool,
+    skip_verify: bool,
+) -> Result<()> {
+    crate::commands::print_scope_user(profile);
+    if rotate {
+        require_phrase_quiz(skip_verify)?;
+    }
+    let config = Config::load()?;
+    let base_url = config.base_url_for(profile)?;
+    let token = auth::load_auth_token(profile)?;
+    let client = ApiClient::new(&base_url, Some(token))?;
+
+    let revoked = match (&suffix, all_others) {
+        (Some(suffix), false) => client.revoke_session(suffix).await?,
+        _ => client.revoke_other_sessions().await?,
+    };
+    say!(
+        "{}",
+        style::paint(
+            style::PEACH,
+            &format!(
+                "revoked {} session{}",
+                revoked,
+                if revoked == 1 { "" } else { "s" }
+            )
+        )
+    );
+
+    // A revoked device may still hold the UMK and recovery phrase.
+    let rotate = rotate
+        || (crate::commands::is_interactive()
+            && crate::commands::prompt(
+                "A lost device may still hold your keys. Rotate recovery phrase + user keys now? [y/N] ",
+            )?
+            .eq_ignore_ascii_case("y"));
+    if rotate {
+        rotate_keys_now(profile, skip_verify).await?;
+    }
+    if crate::output::is_json() {
+        crate::output::emit(&serde_json::json!({ "revoked": revoked, "rotated_keys": rotate }))?;
+    }
+    Ok(())
+}
+
+pub async fn change_password(profile: &str, revoke_other_sessions: bool) -> Result<()> {
+    crate::commands::print_scope_user(profile);
+    let config = Config::load()?;
+    let base_url = config.base_url_for(profile)?;
+    let token = auth::load_auth_token(profile)?;
+    let client = ApiClient::new(&base_url, Some(token))?;
+
+    let current_password = crate::commands::prompt_account_password("Current password: ")?;
+    let new_password = crate::commands::prompt_new_password()?;
+    let revoke_other_sessions = revoke_other_sessions
+        || (crate::commands::is_interactive()
+            && crate::commands::prompt("Sign out all other sessions too? [y/N] ")?
+                .eq_ignore_ascii_case("y"));
+
+    let revoked = client
+        .change_password(&PasswordChangeRequest {
+            current_password,
+            new_password,
+            revoke_other_sessions,
+        })
+        .await?;
+    say!("{}", style::paint(style::GREEN, "Password changed."));
+    if revoke_other_sessions {
+        say!(
+            "{}",
+            style::paint(
+                style::PEACH,
+                &format!(
+                    "signed out {} other session{}",
+                    revoked,
+                    if revoked == 1 { "" } else { "s" }
+                )
+            )
+        );
+    }
+    say!(
+        "{}",
+        style::paint(
+            style::SUBTEXT1,
+            "Your recovery phrase and keys are unchanged."
+        )
+    );
+    if crate::output::is_json() {
+        crate::output::emit(&serde_json::json!({ "ok": true, "revoked": revoked }))?;
+    }
+    Ok(())
+}
+
+pub async fn export(profile: &str, file: Option<&str>) -> Result<()> {
+    crate::commands::print_scope_user(profile);
+    let config = Config::load()?;
+    let base_url = config.base_url_for(profile)?;
+    let token = auth::load_auth_token(profile)?;
+    let client = ApiClient::new(&base_url, Some(token))?;
+
+    let document = account_export(profile, &client).await?;
+    match file {
+        Some(path) => {
+            write_export(path, &document)?;
+            if crate::output::is_json() {
+                crate::output::emit(&serde_json::json!({ "file": path }))?;
+            }
+            Ok(())
+        }
+        None => crate::output::emit(&document),
+    }
+}
+
+// Metadata only: repo names, who has access and which devices are signed in.
+// No keys, phrases or secret contents leave the server.
+async fn account_export(profile: &str, client: &ApiClient) -> Result<serde_json::Value> {
+    let repos = client.get_repos().await?;
+    let mut repo_entries = Vec::new();
+    for repo in &repos {
+        let mut entry = serde_json::to_value(repo)?;
+        if repo.access.as_deref() == Some("owner") {
+            let access = client.get_repo_access(&repo.repo_id).await?;
+            entry["collaborators"] = serde_json::to_value(access)?;
+        }
+        repo_entries.push(entry);
+    }
+    let user_key = client.get_user_key().await?;
+    let umk = client.get_umk().await?;
+    Ok(serde_json::json!({
+        "exported_at": Utc::now().to_rfc3339(),
+        "email": auth::load_email(profile)?,
+        "user_id": auth::load_user_id(profile)?,
+        "repos": repo_entries,
+        "sessions": client.get_sessions().await?,
+        "invites": client.get_invites().await?,
+        "keys": {
+            "public_key": user_key.as_ref().map(|key| &key.public_key),
+            "public_key_updated_at": user_key.as_ref().map(|key| &key.updated_at),
+            "umk_version": umk.as_ref().map(|umk| umk.version),
+            "umk_updated_at": umk.as_ref().and_then(|umk| umk.updated_at.as_ref()),
+        },
+    }))
+}
+
+fn write_export(path: &str, document: &serde_json::Value) -> Result<()> {
+    let mut data = serde_json::to_string_pretty(document)?;
+    data.push('\n');
+    crate::repo::write_secure(path, data.as_bytes())?;
+    say!(
+        "{}",
+        style::paint(style::GREEN, &format!("wrote account export to {}", path))
+    );
+    Ok(())
+}
+
+enum OwnedRepoPlan {
+    Transfer(String),
+    Delete,
+}
+
+pub async fn delete(
+    profile: &str,
+    transfer: Vec<String>,
+    delete_owned_repos: bool,
+    skip_export: bool,
+) -> Result<()> {
+    crate::commands::print_scope_user(profile);
+    let config = Config::load()?;
+    let base_url = config.base_url_for(profile)?;
+    let token = auth::load_auth_token(profile)?;
+    let client = ApiClient::new(&base_url, Some(token))?;
+    let email = auth::load_email(profile)?.ok_or(MilieuError::AuthMissing)?;
+
+    let mut transfers = Vec::new();
+    for value in &transfer {
+        let (repo, target) = value.split_once('=').ok_or_else(|| {
+            MilieuError::Validation(format!("--transfer expects REPO=EMAIL, got '{}'", value))
+        })?;
+        transfers.push((repo.trim().to_string(), target.trim().to_string()));
+    }
+
+    let repos = client.get_repos().await?;
+    let owned: Vec<_> = repos
+        .iter()
+        .filter(|repo| repo.access.as_deref() == Some("owner"))
+        .collect();
+    for (repo, _) in &transfers {
+        if !owned.iter().any(|owned| &owned.name == repo) {
+            return Err(MilieuError::Validation(format!(
+                "--transfer names '{}', which you do not own",
+                repo
+            )));
+        }
+    }
+
+    // Every owned repo needs a new owner or an explicit delete; the server
+    // will not leave repos without an owner.
+    let mut plan = Vec::new();
+    if !owned.is_empty() {
+        say!(
+            "{}",
+            style::bold(style::MAUVE, "Owned repos that would be orphaned")
+        );
+    }
+    for repo in &owned {
+        // Only collaborators who accepted and hold the repo key can take over;
+        // anyone else would be left without a way to decrypt it.
+        let collaborators: Vec<String> = client
+            .get_repo_access(&repo.repo_id)
+            .await?
+            .into_iter()
+            .filter(|entry| entry.status == "active" && entry.has_repo_key != Some(false))
+            .map(|entry| entry.email)
+            .collect();
+        say!(
+            "  {} {}",
+            style::paint(style::TEXT, &repo.name),
             style::paint(
                 style::SUBTEXT1,
-                &format!("{:<width$}", session.expires_at, width = expires_width)
-            ),
+                &if collaborators.is_empty() {
+                    "(no collaborator holds the repo key)".to_string()
+                } else {
+                    format!("(can take over: {})", collaborators.join(", "))
+                }
+            )
         );
+        let action = match transfers.iter().find(|(name, _)| name == &repo.name) {
+            Some((_, target)) if !collaborators.contains(&target.to_lowercase()) => {
+                return Err(MilieuError::Validation(format!(
+                    "{} cannot take over '{}': not a collaborator holding the repo key",
+                    target, repo.name
+                )));
+            }
+            Some((_, target)) => OwnedRepoPlan::Transfer(target.to_lowercase()),
+            None if delete_owned_repos => OwnedRepoPlan::Delete,
+            None => match ask_owned_repo_plan(&repo.name, &collaborators)? {
+                Some(action) => action,
+                None => {
+                    say!("{}", style::paint(style::SUBTEXT1, "aborted"));
+                    return Ok(());
+                }
+            },
+        };
+        plan.push((*repo, action));
+    }
+
+    if !crate::commands::assume_yes() {
+        crate::commands::require_interactive("confirmation")?;
+        let typed = crate::commands::prompt(&format!(
+            "delete account {} (this cannot be undone)? type your email to confirm: ",
+            email
+        ))?;
+        if typed.trim() != email {
+            say!("{}", style::paint(style::SUBTEXT1, "confirmation did not match; aborted"));
+            return Ok(());
+        }
+    }
+    let password = crate::commands::prompt_account_password("Password: ")?;
+
+    if !skip_export {
+        let document = account_export(profile, &client).await?;
+        let path = format!("milieu-account-{}.json", Utc::now().format("%Y%m%d%H%M%S"));
+        write_export(&path, &document)?;
+    }
+
+    // One request: a wrong password leaves every repo untouched.
+    let mut request = AccountDeleteRequest {
+        password,
+        transfers: Vec::new(),
+        delete_repos: Vec::new(),
+    };
+    for (repo, action) in &plan {
+        match action {
+            OwnedRepoPlan::Transfer(target) => request.transfers.push(RepoTransfer {
+                repo_id: repo.repo_id.clone(),
+                email: target.clone(),
+            }),
+            OwnedRepoPlan::Delete => request.delete_repos.push(repo.repo_id.clone()),
+        }
+    }
+    client.delete_account(&request).await?;
+
+    for (repo, action) in &plan {
+        match action {
+            OwnedRepoPlan::Transfer(target) => {
+                say!(
+                    "{}",
+                    style::paint(
+                        style::GREEN,
+                        &format!("transferred '{}' to {}", repo.name, target)
+                    )
+                );
+            }
+            OwnedRepoPlan::Delete => {
+                say!(
+                    "{}",
+                    style::paint(style::PEACH, &format!("deleted remote repo '{}'", repo.name))
+                );
+            }
+        }
+    }
+
+    for repo in &repos {
+        keys::delete_repo_key(profile, &repo.repo_id)?;
+    }
+    auth::delete_session(profile)?;
+    say!(
+        "{}",
+        style::paint(style::PEACH, &format!("deleted account {}", email))
+    );
+    if crate::output::is_json() {
+        let transferred: Vec<_> = plan
+            .iter()
+            .filter_map(|(repo, action)| match action {
+                OwnedRepoPlan::Transfer(target) => {
+                    Some(serde_json::json!({ "repo": repo.name, "to": target }))
+                }
+                OwnedRepoPlan::Delete => None,
+            })
+            .collect();
+        let deleted: Vec<_> = plan
+            .iter()
+            .filter(|(_, action)| matches!(action, OwnedRepoPlan::Delete))
+            .map(|(repo, _)| &repo.name)
+            .collect();
+        crate::output::emit(&serde_json::json!({
+            "deleted_account": email,
+            "transferred_repos": transferred,
+            "deleted_repos": deleted,
+        }))?;
     }
     Ok(())
 }
 
+// None means the user chose to abort.
+fn ask_owned_repo_plan(repo: &str, collaborators: &[String]) -> Result<Option<OwnedRepoPlan>> {
+    if !crate::commands::is_interactive() {
+        return Err(MilieuError::InputRequired(format!(
+            "--transfer or --delete-owned-repos for '{}'",
+            repo
+        )));
+    }
+    loop {
+        let question = if collaborators.is_empty() {
+            format!("'{}' has no collaborators to take it over. [d]elete or [a]bort? ", repo)
+        } else {
+            format!("[t]ransfer, [d]elete or [a]bort '{}'? ", repo)
+        };
+        let answer = crate::commands::prompt(&question)?;
+        match answer.to_lowercase().as_str() {
+            "t" | "transfer" if !collaborators.is_empty() => {
+                let target = if collaborators.len() == 1 {
+                    collaborators[0].clone()
+                } else {
+                    crate::commands::prompt("new owner email: ")?.trim().to_lowercase()
+                };
+                if !collaborators.contains(&target) {
+                    say!(
+                        "{}",
+                        style::paint(style::YELLOW, "new owner must be a current collaborator")
+                    );
+                    continue;
+                }
+                return Ok(Some(OwnedRepoPlan::Transfer(target)));
+            }
+            "d" | "delete" => return Ok(Some(OwnedRepoPlan::Delete)),
+            "a" | "abort" | "" => return Ok(None),
+            _ => continue,
+        }
+    }
+}
+
 pub fn doctor(profile: &str) -> Result<()> {
     crate::commands::print_scope_user(profile);
+    let mut checks = Vec::new();
     let has_session = auth::load_user_id(profile).is_ok();
     if has_session {
         match auth::load_umk(profile) {
-            Ok(_) => println!("{}", style::paint(style::GREEN, "umk: ok")),
-            Err(_) => println!(
-                "{}",
-                style::paint(style::YELLOW, "umk: missing (run `milieu login`)")
-            ),
+            Ok(_) => {
+                say!("{}", style::paint(style::GREEN, "umk: ok"));
+                checks.push(serde_json::json!({ "check": "umk", "ok": true }));
+            }
+            Err(_) => {
+                say!(
+                    "{}",
+                    style::paint(style::YELLOW, "umk: missing (run `milieu login`)")
+                );
+                checks.push(serde_json::json!({
+                    "check": "umk",
+                    "ok": false,
+                    "hint": "run `milieu login`",
+                }));
+            }
         }
-        println!("{}", style::paint(style::GREEN, "keychain: ok"));
+        let backend = Config::load()?.secret_store_for(profile)?;
+        say!(
+            "{}",
+            style::paint(style::GREEN, &format!("secret store ({}): ok", backend.name()))
+        );
+        checks.push(serde_json::json!({
+            "check": "secret_store",
+            "ok": true,
+            "backend": backend.name(),
+        }));
     } else {
-        println!(
+        say!(
             "{}",
             style::paint(style::YELLOW, "auth: missing (run `milieu login`)")
         );
+        checks.push(serde_json::json!({
+            "check": "auth",
+            "ok": false,
+            "hint": "run `milieu login`",
+        }));
+    }
+    if crate::output::is_json() {
+        crate::output::emit(&serde_json::json!({ "checks": checks }))?;
     }
     Ok(())
 }
@@ -240,14 +691,14 @@ pub fn phrase_show(profile: &str) -> Result<()> {
     crate::commands::print_scope_user(profile);
     match auth::load_phrase(profile)? {
         Some(phrase) => {
-            println!(
+            say!(
                 "{}",
                 style::paint(
                     style::YELLOW,
                     "Recovery phrase (store this somewhere safe):"
                 )
             );
-            println!("{}", style::bold(style::LAVENDER, &phrase));
+            say!("{}", style::bold(style::LAVENDER, &phrase));
             Ok(())
         }
         None => Err(crate::error::MilieuError::CommandFailed(
@@ -256,17 +707,155 @@ pub fn phrase_show(profile: &str) -> Result<()> {
     }
 }
 
+pub fn phrase_forget(profile: &str) -> Result<()> {
+    crate::commands::print_scope_user(profile);
+    if auth::load_phrase(profile)?.is_none() {
+        say!(
+            "{}",
+            style::paint(style::SUBTEXT1, "no recovery phrase stored in keychain")
+        );
+        return Ok(());
+    }
+    if auth::load_phrase_verified_at(profile)?.is_none()
+        && !confirm(
+            "This phrase was never confirmed. Forget it anyway (you need it for new devices)? [y/N] ",
+        )?
+    {
+        say!("{}", style::paint(style::SUBTEXT1, "aborted"));
+        return Ok(());
+    }
+    auth::forget_phrase(profile)?;
+    say!(
+        "{}",
+        style::paint(style::PEACH, "Removed the recovery phrase from the keychain.")
+    );
+    say!(
+        "{}",
+        style::paint(
+            style::SUBTEXT1,
+            "The cached UMK stays, so this machine keeps working. Set `store_phrase = false` in config.toml to keep new phrases out too."
+        )
+    );
+    Ok(())
+}
+
 pub fn phrase_status(profile: &str) -> Result<()> {
     crate::commands::print_scope_user(profile);
     let exists = auth::load_phrase(profile)?.is_some();
+    let verified_at = auth::load_phrase_verified_at(profile)?;
     if exists {
-        println!("{}", style::paint(style::GREEN, "phrase: present"));
+        say!("{}", style::paint(style::GREEN, "phrase: present"));
     } else {
-        println!("{}", style::paint(style::YELLOW, "phrase: missing"));
+        say!("{}", style::paint(style::YELLOW, "phrase: missing"));
+    }
+    match verified_at {
+        Some(at) => say!(
+            "{}",
+            style::paint(
+                style::GREEN,
+                &format!("verified: yes ({})", at.format("%Y-%m-%d %H:%M UTC"))
+            )
+        ),
+        None => say!(
+            "{}",
+            style::paint(
+                style::YELLOW,
+                "verified: no (run `milieu user rotate-keys` for a new phrase and confirm it)"
+            )
+        ),
+    }
+    if crate::output::is_json() {
+        crate::output::emit(&serde_json::json!({
+            "present": exists,
+            "verified": verified_at.is_some(),
+            "verified_at": verified_at.map(|at| at.to_rfc3339()),
+        }))?;
     }
     Ok(())
 }
 
+// A fresh phrase is quizzed before the command finishes. Runs that cannot
+// answer must opt out up front, before a new phrase is generated.
+pub fn require_phrase_quiz(skip_verify: bool) -> Result<()> {
+    if skip_verify || crate::commands::is_interactive() {
+        return Ok(());
+    }
+    Err(MilieuError::InputRequired(
+        "recovery phrase confirmation (or --skip-verify)".to_string(),
+    ))
+}
+
+// Asks for a few words by position after the phrase is cleared from the
+// screen, so a phrase nobody wrote down is caught while it can still be shown.
+pub fn quiz_phrase(profile: &str, phrase: &str, skip_verify: bool) -> Result<()> {
+    if skip_verify {
+        say!(
+            "{}",
+            style::paint(
+                style::SUBTEXT1,
+                "recovery phrase not confirmed (--skip-verify); see `milieu user phrase-status`"
+            )
+        );
+        return Ok(());
+    }
+    let words: Vec<&str> = phrase.split_whitespace().collect();
+    for attempt in 1..=QUIZ_ATTEMPTS {
+        crate::commands::prompt("Press Enter once the phrase is written down. ")?;
+        // Clear the screen and its scrollback.
+        say!("\x1b[2J\x1b[3J\x1b[H");
+        let mut correct = true;
+        for position in quiz_positions(words.len()) {
+            let answer = crate::commands::prompt(&format!("Word #{}: ", position + 1))?;
+            correct &= answer.to_lowercase() == words[position];
+        }
+        if correct {
+            auth::mark_phrase_verified(profile)?;
+            say!("{}", style::paint(style::GREEN, "Recovery phrase confirmed."));
+            return Ok(());
+        }
+        if attempt < QUIZ_ATTEMPTS {
+            say!(
+                "{}",
+                style::paint(style::YELLOW, "That does not match. Here is the phrase again:")
+            );
+            say!("{}", style::bold(style::LAVENDER, phrase));
+        }
+    }
+    if auth::load_phrase(profile)?.is_some() {
+        say!(
+            "{}",
+            style::paint(
+                style::YELLOW,
+                "Recovery phrase not confirmed. Show it with `milieu user phrase-show` and keep it safe."
+            )
+        );
+    } else {
+        // Not in the keychain either, so this is the last time it can be shown.
+        say!(
+            "{}",
+            style::paint(
+                style::YELLOW,
+                "Recovery phrase not confirmed and not kept in the keychain. Write it down now:"
+            )
+        );
+        say!("{}", style::bold(style::LAVENDER, phrase));
+    }
+    Ok(())
+}
+
+// Distinct word indexes in ascending order.
+fn quiz_positions(len: usize) -> Vec<usize> {
+    let mut positions = Vec::new();
+    while positions.len() < QUIZ_WORDS.min(len) {
+        let position = OsRng.next_u32() as usize % len;
+        if !positions.contains(&position) {
+            positions.push(position);
+        }
+    }
+    positions.sort_unstable();
+    positions
+}
+
 pub fn warn_login_key_age(user_key_updated_at: Option<&str>) {
     warn_if_old(user_key_updated_at);
 }
@@ -1,10 +1,12 @@
 use crate::error::{MilieuError, Result};
+use crate::keychain::Backend;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs;
 use std::path::PathBuf;
 
 const DEFAULT_BASE_URL: &str = "https://milieu.sh";
+const DEFAULT_MAX_REPO_BYTES: u64 = 5 * 1024 * 1024;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -12,11 +14,35 @@ pub struct Config {
     pub profiles: HashMap<String, Profile>,
     #[serde(default = "default_history_limit")]
     pub history_limit: u32,
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    // With `store_phrase = false` the recovery phrase is shown once and never
+    // written to the keychain; only the UMK derived from it is cached.
+    #[serde(default = "default_store_phrase")]
+    pub store_phrase: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Profile {
     pub base_url: String,
+    #[serde(default)]
+    pub max_repo_bytes: Option<u64>,
+    #[serde(default)]
+    pub secret_store: Option<Backend>,
+}
+
+impl Profile {
+    fn new(base_url: String) -> Self {
+        Self {
+            base_url,
+            max_repo_bytes: None,
+            secret_store: None,
+        }
+    }
 }
 
 impl Default for Config {
@@ -24,14 +50,16 @@ impl Default for Config {
         let mut profiles = HashMap::new();
         profiles.insert(
             "default".to_string(),
-            Profile {
-                base_url: DEFAULT_BASE_URL.to_string(),
-            },
+            Profile::new(DEFAULT_BASE_URL.to_string()),
         );
         Self {
             active_profile: "default".to_string(),
             profiles,
             history_limit: default_history_limit(),
+            connect_timeout_secs: default_connect_timeout_secs(),
+            request_timeout_secs: default_request_timeout_secs(),
+            max_retries: default_max_retries(),
+            store_phrase: default_store_phrase(),
         }
     }
 }
@@ -40,6 +68,22 @@ fn default_history_limit() -> u32 {
     12
 }
 
+fn default_connect_timeout_secs() -> u64 {
+    10
+}
+
+fn default_request_timeout_secs() -> u64 {
+    30
+}
+
+fn default_max_retries() -> u32 {
+    3
+}
+
+fn default_store_phrase() -> bool {
+    true
+}
+
 impl Config {
     pub fn load() -> Result<Self> {
         let path = config_path()?;
@@ -56,12 +100,12 @@ impl Config {
         if config.profiles.is_empty() {
             config
                 .profiles
-                .insert("default".to_string(), Profile { base_url: DEFAULT_BASE_URL.to_string() });
+                .insert("default".to_string(), Profile::new(DEFAULT_BASE_URL.to_string()));
             config.save()?;
         } else if !config.profiles.contains_key(&config.active_profile) {
             config
                 .profiles
-                .insert(config.active_profile.clone(), Profile { base_url: DEFAULT_BASE_URL.to_string() });
+                .insert(config.active_profile.clone(), Profile::new(DEFAULT_BASE_URL.to_string()));
             config.save()?;
         }
         Ok(config)
@@ -78,6 +122,9 @@ impl Config {
     }
 
     pub fn base_url_for(&self, profile: &str) -> Result<String> {
+        if let Some(token) = crate::token::active()? {
+            return Ok(token.base_url.clone());
+        }
         let name = if profile.is_empty() {
             self.active_profile.as_str()
         } else {
@@ -96,7 +143,41 @@ impl Config {
 
     pub fn set_base_url(&mut self, profile: &str, base_url: String) {
         self.profiles
-            .insert(profile.to_string(), Profile { base_url });
+            .entry(profile.to_string())
+            .and_modify(|entry| entry.base_url = base_url.clone())
+            .or_insert_with(|| Profile::new(base_url));
+    }
+
+    pub fn max_repo_bytes_for(&self, profile: &str) -> u64 {
+        let name = if profile.is_empty() {
+            self.active_profile.as_str()
+        } else {
+            profile
+        };
+        self.profiles
+            .get(name)
+            .and_then(|entry| entry.max_repo_bytes)
+            .unwrap_or(DEFAULT_MAX_REPO_BYTES)
+    }
+
+    // MILIEU_SECRET_STORE wins, so containers can pick a store without a
+    // config file.
+    pub fn secret_store_for(&self, profile: &str) -> Result<Backend> {
+        if let Ok(value) = std::env::var("MILIEU_SECRET_STORE") {
+            if !value.trim().is_empty() {
+                return Backend::parse(&value);
+            }
+        }
+        let name = if profile.is_empty() {
+            self.active_profile.as_str()
+        } else {
+            profile
+        };
+        Ok(self
+            .profiles
+            .get(name)
+            .and_then(|entry| entry.secret_store)
+            .unwrap_or_default())
     }
 }
 
@@ -4,13 +4,22 @@ use base64::engine::general_purpose::STANDARD as B64;
 use base64::Engine;
 use chacha20poly1305::aead::{Aead, KeyInit, Payload};
 use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 use hkdf::Hkdf;
 use rand_core::{OsRng, RngCore};
 use serde::{Deserialize, Serialize};
 use sha2::Sha256;
+use std::io::{Read, Write};
 use x25519_dalek::{PublicKey, StaticSecret};
 
 pub const UMK_LEN: usize = 32;
+// v3 objects carry deflate-compressed plaintext; v2 and older are raw.
+pub const SCHEMA_VERSION: u32 = 3;
+// Far above any repo cap, but stops a small payload from inflating without
+// bound.
+const MAX_DECOMPRESSED_BYTES: u64 = 64 * 1024 * 1024;
 const REPO_KEY_AAD: &[u8] = b"milieu:repo-key:v1";
 const REPO_KEY_WRAP_INFO: &[u8] = b"milieu:repo-key-wrap";
 const USER_KEYPAIR_INFO: &[u8] = b"milieu:user-keypair:v1";
@@ -78,6 +87,16 @@ pub fn derive_keypair_from_umk(umk: &[u8; UMK_LEN]) -> Result<KeyPair> {
     })
 }
 
+// A random keypair with no UMK behind it, for service tokens.
+pub fn generate_keypair() -> KeyPair {
+    let secret = StaticSecret::random_from_rng(OsRng);
+    let public = PublicKey::from(&secret);
+    KeyPair {
+        private_key_b64: B64.encode(secret.to_bytes()),
+        public_key_b64: B64.encode(public.to_bytes()),
+    }
+}
+
 pub fn wrap_repo_key_for_public_key(
     repo_key: &[u8; UMK_LEN],
     recipient_public_b64: &str,
@@ -203,6 +222,41 @@ pub fn decrypt_bytes(
     Ok(plaintext)
 }
 
+pub fn compress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data)?;
+    Ok(encoder.finish()?)
+}
+
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    let mut decoder = DeflateDecoder::new(data).take(MAX_DECOMPRESSED_BYTES + 1);
+    let mut out = Vec::new();
+    decoder
+        .read_to_end(&mut out)
+        .map_err(|e| MilieuError::Crypto(format!("decompress: {}", e)))?;
+    if out.len() as u64 > MAX_DECOMPRESSED_BYTES {
+        return Err(MilieuError::Crypto(format!(
+            "decompress: payload expands past {} bytes",
+            MAX_DECOMPRESSED_BYTES
+        )));
+    }
+    Ok(out)
+}
+
+pub fn decrypt_payload(
+    key: &[u8; UMK_LEN],
+    schema_version: u32,
+    aad: &[u8],
+    nonce_b64: &str,
+    ciphertext_b64: &str,
+) -> Result<Vec<u8>> {
+    let plaintext = decrypt_bytes(key, aad, nonce_b64, ciphertext_b64)?;
+    if schema_version >= SCHEMA_VERSION {
+        return decompress(&plaintext);
+    }
+    Ok(plaintext)
+}
+
 pub fn aad_for(
     schema_version: u32,
     repo_id: &str,
@@ -257,3 +311,61 @@ pub fn decrypt_umk_blob(pdk: &[u8; UMK_LEN], blob: &str) -> Result<[u8; UMK_LEN]
     umk.copy_from_slice(&bytes);
     Ok(umk)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_round_trips() {
+        let data = b"API_KEY=secret\nDATABASE_URL=postgres://localhost/app\n".repeat(100);
+        let compressed = compress(&data).unwrap();
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress(&compressed).unwrap(), data);
+        assert_eq!(decompress(&compress(b"").unwrap()).unwrap(), b"");
+    }
+
+    #[test]
+    fn decompress_stops_at_the_cap() {
+        let bomb = compress(&vec![0u8; MAX_DECOMPRESSED_BYTES as usize + 1]).unwrap();
+        assert!(bomb.len() < 1024 * 1024);
+        let err = decompress(&bomb).unwrap_err();
+        assert!(err.to_string().contains("expands past"), "{}", err);
+    }
+
+    #[test]
+    fn decompress_rejects_garbage() {
+        assert!(decompress(b"not deflate at all").is_err());
+    }
+
+    #[test]
+    fn payload_round_trips_and_detects_tampering() {
+        let key = generate_umk();
+        let aad = aad_for(SCHEMA_VERSION, "repo", "dev", ".env", None);
+        let data = b"TOKEN=abc\n".to_vec();
+        let (nonce, ciphertext) = encrypt_bytes(&key, &aad, &compress(&data).unwrap()).unwrap();
+        assert_eq!(
+            decrypt_payload(&key, SCHEMA_VERSION, &aad, &nonce, &ciphertext).unwrap(),
+            data
+        );
+
+        let other = aad_for(SCHEMA_VERSION, "repo", "prod", ".env", None);
+        assert!(decrypt_payload(&key, SCHEMA_VERSION, &other, &nonce, &ciphertext).is_err());
+
+        let mut bytes = B64.decode(&ciphertext).unwrap();
+        bytes[0] ^= 1;
+        let tampered = B64.encode(bytes);
+        assert!(decrypt_payload(&key, SCHEMA_VERSION, &aad, &nonce, &tampered).is_err());
+    }
+
+    #[test]
+    fn old_payloads_are_not_decompressed() {
+        let key = generate_umk();
+        let aad = aad_for(2, "repo", "dev", ".env", None);
+        let (nonce, ciphertext) = encrypt_bytes(&key, &aad, b"RAW=1\n").unwrap();
+        assert_eq!(
+            decrypt_payload(&key, 2, &aad, &nonce, &ciphertext).unwrap(),
+            b"RAW=1\n"
+        );
+    }
+}
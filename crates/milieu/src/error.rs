@@ -23,7 +23,6 @@ pub enum MilieuError {
     #[error("crypto error: {0}")]
     Crypto(String),
 
-
     #[error("repo not initialized; run `milieu init` or `milieu clone`")]
     RepoNotInitialized,
 
@@ -42,8 +41,147 @@ pub enum MilieuError {
     #[error("missing UMK; run `milieu login`")]
     UmkMissing,
 
+    #[error("{0}")]
+    NotFound(ApiError),
+
+    #[error("{0}")]
+    Forbidden(ApiError),
+
+    #[error("{0}")]
+    Conflict(ApiError),
+
+    #[error("{0}")]
+    RateLimited(ApiError),
+
+    #[error("{0}")]
+    SessionExpired(ApiError),
+
+    #[error("{0}")]
+    ServerError(ApiError),
+
+    #[error("{0}")]
+    BadRequest(ApiError),
+
+    #[error("{0}")]
+    Validation(String),
+
+    #[error("{0}")]
+    Network(String),
+
     #[error("command failed: {0}")]
     CommandFailed(String),
+
+    #[error("{0} required, but milieu is running non-interactively")]
+    InputRequired(String),
 }
 
 pub type Result<T> = std::result::Result<T, MilieuError>;
+
+/// Process exit codes. These are part of the CLI's interface for scripts;
+/// see "Exit codes" in the README before changing any of them.
+pub mod exit {
+    pub const FAILURE: u8 = 1;
+    // 2 is clap's code for invalid command-line usage.
+    pub const OUT_OF_SYNC: u8 = 3;
+    pub const AUTH: u8 = 4;
+    pub const CONFLICT: u8 = 5;
+    pub const VALIDATION: u8 = 6;
+    pub const NETWORK: u8 = 7;
+    pub const DECRYPTION: u8 = 8;
+    pub const NOT_FOUND: u8 = 9;
+    pub const FORBIDDEN: u8 = 10;
+}
+
+/// Error details from the server's `{ error, message, hint }` body.
+#[derive(Debug)]
+pub struct ApiError {
+    // What the client was doing, e.g. "get repo key".
+    pub action: String,
+    pub code: String,
+    pub message: String,
+    pub hint: Option<String>,
+    pub retry_after: Option<u64>,
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} failed: {}", self.action, self.message)
+    }
+}
+
+impl MilieuError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            MilieuError::AuthMissing
+            | MilieuError::UserIdMissing
+            | MilieuError::UmkMissing
+            | MilieuError::SessionExpired(_) => exit::AUTH,
+            MilieuError::Conflict(_) => exit::CONFLICT,
+            MilieuError::TomlDe(_)
+            | MilieuError::TomlSer(_)
+            | MilieuError::Json(_)
+            | MilieuError::Validation(_)
+            | MilieuError::InputRequired(_)
+            | MilieuError::BadRequest(_) => exit::VALIDATION,
+            MilieuError::Http(_)
+            | MilieuError::Network(_)
+            | MilieuError::RateLimited(_)
+            | MilieuError::ServerError(_) => exit::NETWORK,
+            MilieuError::Crypto(_) => exit::DECRYPTION,
+            MilieuError::NotFound(_)
+            | MilieuError::BranchNotFound(_)
+            | MilieuError::RepoNotInitialized => exit::NOT_FOUND,
+            MilieuError::Forbidden(_) => exit::FORBIDDEN,
+            MilieuError::Io(_)
+            | MilieuError::Keyring(_)
+            | MilieuError::ConfigMissing
+            | MilieuError::CommandFailed(_) => exit::FAILURE,
+        }
+    }
+
+    pub fn api(&self) -> Option<&ApiError> {
+        match self {
+            MilieuError::NotFound(err)
+            | MilieuError::Forbidden(err)
+            | MilieuError::Conflict(err)
+            | MilieuError::RateLimited(err)
+            | MilieuError::SessionExpired(err)
+            | MilieuError::ServerError(err)
+            | MilieuError::BadRequest(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    /// What the user can do about the error, preferring the server's hint.
+    pub fn hint(&self) -> Option<String> {
+        if let MilieuError::RateLimited(ApiError {
+            retry_after: Some(seconds),
+            ..
+        }) = self
+        {
+            return Some(format!("try again in {} seconds", seconds));
+        }
+        if let Some(hint) = self.api().and_then(|err| err.hint.clone()) {
+            return Some(hint);
+        }
+        match self {
+            MilieuError::SessionExpired(_) => Some("run `milieu login`".to_string()),
+            MilieuError::RateLimited(_) => Some("wait a moment and try again".to_string()),
+            MilieuError::Forbidden(_) => Some("ask the repo owner for access".to_string()),
+            MilieuError::ServerError(_) => {
+                Some("the server had a problem; try again shortly".to_string())
+            }
+            MilieuError::Keyring(_) => Some(
+                "without an OS keychain, set `secret_store = \"file\"` for this profile in \
+                 config.toml or export MILIEU_SECRET_STORE=file"
+                    .to_string(),
+            ),
+            MilieuError::InputRequired(_) => Some(
+                "set MILIEU_EMAIL, MILIEU_PASSWORD and MILIEU_PHRASE, pipe secrets with \
+                 --password-stdin or --phrase-stdin, and pass --yes to confirm"
+                    .to_string(),
+            ),
+            _ => None,
+        }
+    }
+}
@@ -1,28 +1,433 @@
-use crate::error::{Result, MilieuError};
+use crate::config::config_dir;
+use crate::crypto::{
+    decode_key, decrypt_bytes, derive_key, encode_key, encrypt_bytes, generate_umk, KdfParams,
+    UMK_LEN,
+};
+use crate::error::{MilieuError, Result};
 use keyring::Entry;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
 
 const SERVICE: &str = "milieu";
+const FILE_STORE_NAME: &str = "secrets.enc";
+const FILE_STORE_KEY_NAME: &str = "secrets.key";
+const FILE_STORE_AAD: &[u8] = b"milieu:secret-store:v1";
+const PASSPHRASE_ENV: &str = "MILIEU_SECRETS_PASSPHRASE";
+const ENV_PREFIX: &str = "MILIEU_SECRET_";
 
-pub fn set_secret(key: &str, value: &str) -> Result<()> {
-    let entry = Entry::new(SERVICE, key)?;
-    entry.set_password(value)?;
+/// Where session data and cached repo keys live, chosen per profile with
+/// `secret_store` in config.toml.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    Keyring,
+    File,
+    Env,
+    Memory,
+}
+
+impl Backend {
+    pub fn name(self) -> &'static str {
+        match self {
+            Backend::Keyring => "keyring",
+            Backend::File => "file",
+            Backend::Env => "env",
+            Backend::Memory => "memory",
+        }
+    }
+
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "keyring" => Ok(Backend::Keyring),
+            "file" => Ok(Backend::File),
+            "env" => Ok(Backend::Env),
+            "memory" => Ok(Backend::Memory),
+            other => Err(MilieuError::Validation(format!(
+                "unknown secret store '{}'; use keyring, file, env or memory",
+                other
+            ))),
+        }
+    }
+}
+
+pub trait SecretStore: Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<String>>;
+    fn set(&self, key: &str, value: &str) -> Result<()>;
+    fn delete(&self, key: &str) -> Result<()>;
+}
+
+static STORE: OnceLock<Box<dyn SecretStore>> = OnceLock::new();
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+pub fn configure(backend: Backend) -> Result<()> {
+    let _ = STORE.set(open(backend)?);
     Ok(())
 }
 
-pub fn get_secret(key: &str) -> Result<Option<String>> {
-    let entry = Entry::new(SERVICE, key)?;
-    match entry.get_password() {
-        Ok(value) => Ok(Some(value)),
-        Err(keyring::Error::NoEntry) => Ok(None),
-        Err(err) => Err(MilieuError::Keyring(err)),
+fn open(backend: Backend) -> Result<Box<dyn SecretStore>> {
+    Ok(match backend {
+        Backend::Keyring => Box::new(KeyringStore),
+        Backend::File => Box::new(FileStore::new(
+            config_dir()?,
+            std::env::var(PASSPHRASE_ENV).ok(),
+        )),
+        Backend::Env => Box::new(EnvStore::default()),
+        Backend::Memory => Box::new(MemoryStore::default()),
+    })
+}
+
+fn store() -> &'static dyn SecretStore {
+    STORE.get_or_init(|| Box::new(KeyringStore)).as_ref()
+}
+
+// Dry runs read secrets but never change the store.
+pub fn set_read_only() {
+    READ_ONLY.store(true, Ordering::Relaxed);
+}
+
+pub fn is_read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+pub fn set_secret(key: &str, value: &str) -> Result<()> {
+    if is_read_only() {
+        return Ok(());
     }
+    store().set(key, value)
+}
+
+pub fn get_secret(key: &str) -> Result<Option<String>> {
+    store().get(key)
 }
 
 pub fn delete_secret(key: &str) -> Result<()> {
-    let entry = Entry::new(SERVICE, key)?;
-    match entry.delete_password() {
-        Ok(()) => Ok(()),
-        Err(keyring::Error::NoEntry) => Ok(()),
-        Err(err) => Err(MilieuError::Keyring(err)),
+    if is_read_only() {
+        return Ok(());
+    }
+    store().delete(key)
+}
+
+// The OS keychain: macOS Keychain, Windows Credential Manager or the Linux
+// Secret Service.
+struct KeyringStore;
+
+impl SecretStore for KeyringStore {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        let entry = Entry::new(SERVICE, key)?;
+        match entry.get_password() {
+            Ok(value) => Ok(Some(value)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(err) => Err(MilieuError::Keyring(err)),
+        }
+    }
+
+    fn set(&self, key: &str, value: &str) -> Result<()> {
+        let entry = Entry::new(SERVICE, key)?;
+        entry.set_password(value)?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let entry = Entry::new(SERVICE, key)?;
+        match entry.delete_password() {
+            Ok(()) => Ok(()),
+            Err(keyring::Error::NoEntry) => Ok(()),
+            Err(err) => Err(MilieuError::Keyring(err)),
+        }
+    }
+}
+
+// All secrets in one encrypted file under the config dir. The key comes from
+// MILIEU_SECRETS_PASSPHRASE when set, otherwise from a random key file next
+// to it; either way both files are written owner-only.
+struct FileStore {
+    dir: PathBuf,
+    passphrase: Option<String>,
+    key: OnceLock<[u8; UMK_LEN]>,
+    entries: Mutex<Option<HashMap<String, String>>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SecretFile {
+    version: u32,
+    // Present when the key is derived from a passphrase.
+    #[serde(default)]
+    kdf: Option<KdfParams>,
+    nonce: String,
+    ciphertext: String,
+}
+
+impl FileStore {
+    fn new(dir: PathBuf, passphrase: Option<String>) -> Self {
+        Self {
+            dir,
+            passphrase,
+            key: OnceLock::new(),
+            entries: Mutex::new(None),
+        }
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir.join(FILE_STORE_NAME)
+    }
+
+    fn key(&self, kdf: Option<&KdfParams>) -> Result<[u8; UMK_LEN]> {
+        if let Some(key) = self.key.get() {
+            return Ok(*key);
+        }
+        let key = match (self.passphrase.as_deref(), kdf) {
+            (Some(passphrase), Some(kdf)) => derive_key(passphrase, kdf)?,
+            (None, Some(_)) => {
+                return Err(MilieuError::CommandFailed(format!(
+                    "{} is passphrase-protected; set {}",
+                    FILE_STORE_NAME, PASSPHRASE_ENV
+                )))
+            }
+            (_, None) => self.key_file()?,
+        };
+        Ok(*self.key.get_or_init(|| key))
+    }
+
+    fn with_entries<T>(&self, f: impl FnOnce(&mut HashMap<String, String>) -> T) -> Result<T> {
+        let mut guard = self
+            .entries
+            .lock()
+            .map_err(|_| MilieuError::CommandFailed("secret store lock poisoned".to_string()))?;
+        if guard.is_none() {
+            *guard = Some(self.load()?);
+        }
+        Ok(f(guard.get_or_insert_with(HashMap::new)))
+    }
+
+    fn load(&self) -> Result<HashMap<String, String>> {
+        let path = self.path();
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+        let file: SecretFile = serde_json::from_slice(&std::fs::read(&path)?)?;
+        let key = self.key(file.kdf.as_ref())?;
+        let plain = decrypt_bytes(&key, FILE_STORE_AAD, &file.nonce, &file.ciphertext)
+            .map_err(|_| {
+                MilieuError::Crypto(format!(
+                    "cannot decrypt {}; wrong {} or key file",
+                    FILE_STORE_NAME, PASSPHRASE_ENV
+                ))
+            })?;
+        Ok(serde_json::from_slice(&plain)?)
+    }
+
+    fn save(&self, entries: &HashMap<String, String>) -> Result<()> {
+        let path = self.path();
+        // Keep the salt of an existing passphrase file so the cached key stays valid.
+        let kdf = if path.exists() {
+            serde_json::from_slice::<SecretFile>(&std::fs::read(&path)?)?.kdf
+        } else if self.passphrase.is_some() {
+            Some(KdfParams::new_default())
+        } else {
+            None
+        };
+        let key = self.key(kdf.as_ref())?;
+        let plain = serde_json::to_vec(entries)?;
+        let (nonce, ciphertext) = encrypt_bytes(&key, FILE_STORE_AAD, &plain)?;
+        let file = SecretFile {
+            version: 1,
+            kdf,
+            nonce,
+            ciphertext,
+        };
+        crate::repo::write_secure(&path_str(&path)?, &serde_json::to_vec_pretty(&file)?)
+    }
+
+    fn key_file(&self) -> Result<[u8; UMK_LEN]> {
+        let path = self.dir.join(FILE_STORE_KEY_NAME);
+        if path.exists() {
+            return decode_key(std::fs::read_to_string(&path)?.trim());
+        }
+        let key = generate_umk();
+        crate::repo::write_secure(&path_str(&path)?, encode_key(&key).as_bytes())?;
+        Ok(key)
+    }
+}
+
+impl SecretStore for FileStore {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.with_entries(|entries| entries.get(key).cloned())
+    }
+
+    fn set(&self, key: &str, value: &str) -> Result<()> {
+        let entries = self.with_entries(|entries| {
+            entries.insert(key.to_string(), value.to_string());
+            entries.clone()
+        })?;
+        self.save(&entries)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let (removed, entries) =
+            self.with_entries(|entries| (entries.remove(key).is_some(), entries.clone()))?;
+        if removed {
+            self.save(&entries)?;
+        }
+        Ok(())
+    }
+}
+
+fn path_str(path: &std::path::Path) -> Result<String> {
+    path.to_str()
+        .map(str::to_string)
+        .ok_or_else(|| MilieuError::CommandFailed(format!("invalid path: {}", path.display())))
+}
+
+// Read-only from the environment: `session:ci` is MILIEU_SECRET_SESSION_CI.
+// Writes last for the current process only, so nothing touches the disk.
+#[derive(Default)]
+struct EnvStore {
+    overrides: MemoryStore,
+}
+
+fn env_name(key: &str) -> String {
+    let suffix: String = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+        .collect();
+    format!("{}{}", ENV_PREFIX, suffix)
+}
+
+impl SecretStore for EnvStore {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        if let Some(value) = self.overrides.entry(key)? {
+            return Ok(value);
+        }
+        Ok(std::env::var(env_name(key))
+            .ok()
+            .filter(|value| !value.is_empty()))
+    }
+
+    fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.overrides.set(key, value)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.overrides.put(key, None)
+    }
+}
+
+// Nothing outlives the process; for tests and throwaway runs.
+#[derive(Default)]
+struct MemoryStore {
+    // `None` records a delete, which the env store needs to hide a variable.
+    entries: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl MemoryStore {
+    fn entry(&self, key: &str) -> Result<Option<Option<String>>> {
+        Ok(self.lock()?.get(key).cloned())
+    }
+
+    fn put(&self, key: &str, value: Option<String>) -> Result<()> {
+        self.lock()?.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, HashMap<String, Option<String>>>> {
+        self.entries
+            .lock()
+            .map_err(|_| MilieuError::CommandFailed("secret store lock poisoned".to_string()))
+    }
+}
+
+impl SecretStore for MemoryStore {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.entry(key)?.flatten())
+    }
+
+    fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.put(key, Some(value.to_string()))
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        self.put(key, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the same calls against `store` and a MemoryStore and expects the
+    // same answers from both.
+    fn assert_round_trip(store: &dyn SecretStore) {
+        let reference = MemoryStore::default();
+        for backend in [store, &reference as &dyn SecretStore] {
+            backend.set("session:a", "one").unwrap();
+            backend.set("session:b", "two").unwrap();
+            backend.set("session:a", "three").unwrap();
+            backend.delete("session:b").unwrap();
+            backend.delete("session:missing").unwrap();
+        }
+        for key in ["session:a", "session:b", "session:missing"] {
+            assert_eq!(store.get(key).unwrap(), reference.get(key).unwrap(), "{}", key);
+        }
+        assert_eq!(store.get("session:a").unwrap().as_deref(), Some("three"));
+    }
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("milieu-secrets-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn file_store_round_trips_with_key_file() {
+        let dir = temp_dir();
+        assert_round_trip(&FileStore::new(dir.clone(), None));
+
+        let reopened = FileStore::new(dir.clone(), None);
+        assert_eq!(reopened.get("session:a").unwrap().as_deref(), Some("three"));
+        assert_eq!(reopened.get("session:b").unwrap(), None);
+        assert!(dir.join(FILE_STORE_KEY_NAME).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn file_store_round_trips_with_passphrase() {
+        let dir = temp_dir();
+        assert_round_trip(&FileStore::new(dir.clone(), Some("correct".to_string())));
+        assert!(!dir.join(FILE_STORE_KEY_NAME).exists());
+
+        let reopened = FileStore::new(dir.clone(), Some("correct".to_string()));
+        assert_eq!(reopened.get("session:a").unwrap().as_deref(), Some("three"));
+        assert!(FileStore::new(dir.clone(), Some("wrong".to_string()))
+            .get("session:a")
+            .is_err());
+        assert!(FileStore::new(dir.clone(), None).get("session:a").is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn env_store_round_trips() {
+        assert_round_trip(&EnvStore::default());
+    }
+
+    #[test]
+    fn env_store_reads_and_hides_variables() {
+        std::env::set_var("MILIEU_SECRET_SESSION_CI_TEST", "from-env");
+        let store = EnvStore::default();
+        assert_eq!(env_name("session:ci-test"), "MILIEU_SECRET_SESSION_CI_TEST");
+        assert_eq!(store.get("session:ci-test").unwrap().as_deref(), Some("from-env"));
+
+        store.set("session:ci-test", "override").unwrap();
+        assert_eq!(store.get("session:ci-test").unwrap().as_deref(), Some("override"));
+        store.delete("session:ci-test").unwrap();
+        assert_eq!(store.get("session:ci-test").unwrap(), None);
+        assert_eq!(
+            std::env::var("MILIEU_SECRET_SESSION_CI_TEST").as_deref(),
+            Ok("from-env")
+        );
+        std::env::remove_var("MILIEU_SECRET_SESSION_CI_TEST");
     }
 }
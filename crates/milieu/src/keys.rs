@@ -6,6 +6,7 @@ use crate::crypto::{
 };
 use crate::error::{MilieuError, Result};
 use crate::keychain;
+use crate::token::ServiceToken;
 
 const REPO_KEY_PREFIX: &str = "repo_key:";
 
@@ -55,6 +56,9 @@ pub fn store_repo_key(profile: &str, repo_id: &str, key: &[u8; UMK_LEN]) -> Resu
 }
 
 pub fn load_repo_key(profile: &str, repo_id: &str) -> Result<Option<[u8; UMK_LEN]>> {
+    if crate::token::active()?.is_some() {
+        return Ok(None);
+    }
     let email = profile_email(profile)?;
     let encoded = keychain::get_secret(&repo_key_key(&email, repo_id))?;
     match encoded {
@@ -63,11 +67,19 @@ pub fn load_repo_key(profile: &str, repo_id: &str) -> Result<Option<[u8; UMK_LEN
     }
 }
 
+pub fn delete_repo_key(profile: &str, repo_id: &str) -> Result<()> {
+    let email = profile_email(profile)?;
+    keychain::delete_secret(&repo_key_key(&email, repo_id))
+}
+
 pub async fn get_or_fetch_repo_key(
     profile: &str,
     client: &ApiClient,
     repo_id: &str,
 ) -> Result<[u8; UMK_LEN]> {
+    if let Some(token) = crate::token::active()? {
+        return fetch_service_repo_key(token, client, repo_id).await;
+    }
     if let Some(key) = load_repo_key(profile, repo_id)? {
         return Ok(key);
     }
@@ -92,9 +104,27 @@ pub async fn get_or_fetch_repo_key(
     Ok(repo_key)
 }
 
+// Service tokens keep nothing locally; the key is unwrapped on every run.
+async fn fetch_service_repo_key(
+    token: &ServiceToken,
+    client: &ApiClient,
+    repo_id: &str,
+) -> Result<[u8; UMK_LEN]> {
+    if token.repo_id != repo_id {
+        return Err(MilieuError::Validation(
+            "MILIEU_TOKEN belongs to a different repo".to_string(),
+        ));
+    }
+    let RepoKeyResponse { wrapped_key, .. } = client
+        .get_repo_key(repo_id)
+        .await?
+        .ok_or_else(|| MilieuError::CommandFailed("service token has no repo key".to_string()))?;
+    unwrap_repo_key_with_private_key(&token.private_key, &wrapped_key)
+}
+
 pub async fn wrap_repo_key_for_user(
     recipient_public_key: &str,
     repo_key: &[u8; UMK_LEN],
 ) -> Result<String> {
-    Ok(wrap_repo_key_for_public_key(repo_key, recipient_public_key)?)
+    wrap_repo_key_for_public_key(repo_key, recipient_public_key)
 }
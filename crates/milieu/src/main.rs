@@ -1,5 +1,9 @@
+#[macro_use]
+mod output;
 mod api;
 mod auth;
+mod backup;
+mod cache;
 mod commands;
 mod config;
 mod crypto;
@@ -7,12 +11,17 @@ mod keys;
 mod error;
 mod keychain;
 mod manifest;
+mod objects;
 mod repo;
+mod stash;
 mod style;
+mod token;
 
 use clap::{Parser, Subcommand};
 use config::Config;
 use error::Result;
+use std::process::ExitCode;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -29,6 +38,46 @@ struct Cli {
     #[arg(short, long, action = clap::ArgAction::Count)]
     verbose: u8,
 
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECONDS",
+        help = "per-request timeout in seconds (default from config, 30)"
+    )]
+    timeout: Option<u64>,
+
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value = "text",
+        help = "output format; json prints one machine-readable document on stdout"
+    )]
+    output: output::Format,
+
+    #[arg(
+        long,
+        short = 'y',
+        global = true,
+        visible_alias = "non-interactive",
+        help = "never prompt: answer yes to confirmations, fail when other input is missing"
+    )]
+    yes: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "read the account password from stdin (MILIEU_PASSWORD also works)"
+    )]
+    password_stdin: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "read the recovery phrase from stdin, after the password if both are piped (MILIEU_PHRASE also works)"
+    )]
+    phrase_stdin: bool,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -37,8 +86,14 @@ struct Cli {
 enum Commands {
     #[command(about = "create an account for milieu", after_help = "example: milieu register")]
     Register,
-    #[command(about = "login to your milieu account", after_help = "example: milieu login")]
-    Login,
+    #[command(
+        about = "login to your milieu account",
+        after_help = "examples:\n  milieu login\n  milieu login --skip-verify"
+    )]
+    Login {
+        #[arg(long, help = "do not quiz a newly created recovery phrase")]
+        skip_verify: bool,
+    },
     #[command(about = "remove local auth and UMK from your keychain", after_help = "example: milieu logout")]
     Logout,
     #[command(
@@ -96,7 +151,7 @@ enum Commands {
     },
     #[command(
         about = "checkout a specific version of a file",
-        after_help = "example: milieu checkout .env --version 3"
+        after_help = "examples:\n  milieu checkout .env --version 3\n  milieu checkout .env --version 3 --dry-run"
     )]
     Checkout {
         path: String,
@@ -104,10 +159,12 @@ enum Commands {
         version: u32,
         #[arg(long)]
         branch: Option<String>,
+        #[arg(long, help = "show what would change without writing files")]
+        dry_run: bool,
     },
     #[command(
         about = "show diffs for a file or all files",
-        after_help = "examples:\n  milieu changes\n  milieu changes .env\n  milieu changes .env --version 3\n  milieu changes --branch prod"
+        after_help = "examples:\n  milieu changes\n  milieu changes .env\n  milieu changes .env --version 3\n  milieu changes --branch prod\n  milieu changes --offline"
     )]
     Changes {
         path: Option<String>,
@@ -115,6 +172,8 @@ enum Commands {
         version: Option<u32>,
         #[arg(long)]
         branch: Option<String>,
+        #[arg(long, help = "compare against cached objects without contacting the server")]
+        offline: bool,
     },
     #[command(
         about = "list repos linked to your user",
@@ -133,20 +192,47 @@ enum Commands {
         #[command(subcommand)]
         command: BranchCommand,
     },
-    #[command(about = "push branch changes to the server", after_help = "example: milieu push --branch dev")]
+    #[command(about = "push branch changes to the server", after_help = "examples:\n  milieu push --branch dev\n  milieu push --branch prod --dry-run\n  milieu push --all-branches\n  milieu push --branch dev --force")]
     Push {
-        #[arg(long)]
-        branch: Option<String>,
+        #[arg(long, help = "branch to push (repeatable)")]
+        branch: Vec<String>,
+        #[arg(long, conflicts_with = "branch", help = "push every branch in the manifest")]
+        all_branches: bool,
+        #[arg(long, help = "show what would be pushed without uploading")]
+        dry_run: bool,
+        #[arg(long, help = "overwrite remote changes made since your last pull")]
+        force: bool,
     },
-    #[command(about = "download and decrypt dotenv files for a branch", after_help = "example: milieu pull --branch dev")]
+    #[command(about = "download and decrypt dotenv files for a branch", after_help = "examples:\n  milieu pull --branch dev\n  milieu pull --branch prod --dry-run\n  milieu pull --branch dev --branch staging")]
     Pull {
-        #[arg(long)]
-        branch: Option<String>,
+        #[arg(long, help = "branch to pull (repeatable)")]
+        branch: Vec<String>,
+        #[arg(long, conflicts_with = "branch", help = "pull every branch in the manifest")]
+        all_branches: bool,
+        #[arg(long, help = "show what would be pulled without writing files")]
+        dry_run: bool,
     },
-    #[command(about = "show local vs remote state for this repo", after_help = "example: milieu status")]
+    #[command(
+        about = "set aside local changes to tracked files",
+        after_help = "examples:\n  milieu stash push\n  milieu pull\n  milieu stash pop\n  milieu stash list"
+    )]
+    Stash {
+        #[command(subcommand)]
+        command: StashCommand,
+    },
+    #[command(
+        about = "restore local files from before the last pull or checkout",
+        after_help = "example: milieu undo"
+    )]
+    Undo,
+    #[command(about = "show local vs remote state for this repo", after_help = "examples:\n  milieu status\n  milieu status --offline\n  milieu status --exit-code")]
     Status {
         #[arg(long)]
         json: bool,
+        #[arg(long, help = "use cached objects without contacting the server")]
+        offline: bool,
+        #[arg(long, help = "exit with code 3 when any file is out of sync")]
+        exit_code: bool,
     },
 }
 
@@ -178,6 +264,33 @@ enum BranchCommand {
     Set { name: String },
 }
 
+#[derive(Subcommand, Debug)]
+enum StashCommand {
+    #[command(
+        about = "stash locally modified files and reset them to their synced version",
+        after_help = "example: milieu stash push --branch dev"
+    )]
+    Push {
+        #[arg(long)]
+        branch: Option<String>,
+    },
+    #[command(
+        about = "reapply a stash, merging with the current files",
+        after_help = "examples:\n  milieu stash pop\n  milieu stash pop 1"
+    )]
+    Pop {
+        #[arg(default_value_t = 0)]
+        index: usize,
+    },
+    #[command(about = "list stashes, newest first", after_help = "example: milieu stash list")]
+    List,
+    #[command(about = "delete a stash", after_help = "example: milieu stash drop 0")]
+    Drop {
+        #[arg(default_value_t = 0)]
+        index: usize,
+    },
+}
+
 #[derive(Subcommand, Debug)]
 enum ReposCommand {
     #[command(about = "list repos linked to this user", after_help = "example: milieu repos list")]
@@ -246,59 +359,230 @@ enum ManageCommand {
         #[arg(long)]
         repo: String,
     },
+    #[command(
+        about = "service tokens for CI and deploy targets",
+        after_help = "examples:\n  milieu repos manage token create --repo my-app --branch prod --access read\n  milieu repos manage token list --repo my-app\n  milieu repos manage token revoke --repo my-app <id>"
+    )]
+    Token {
+        #[command(subcommand)]
+        command: TokenCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TokenCommand {
+    #[command(
+        about = "create a token scoped to one branch; use it as MILIEU_TOKEN",
+        after_help = "example: milieu repos manage token create --repo my-app --branch prod --access read"
+    )]
+    Create {
+        #[arg(long)]
+        repo: String,
+        #[arg(long)]
+        branch: String,
+        #[arg(long, default_value = "read")]
+        access: String,
+        #[arg(long)]
+        name: Option<String>,
+    },
+    #[command(about = "list a repo's service tokens", after_help = "example: milieu repos manage token list --repo my-app")]
+    List {
+        #[arg(long)]
+        repo: String,
+    },
+    #[command(about = "revoke a service token by id", after_help = "example: milieu repos manage token revoke --repo my-app <id>")]
+    Revoke {
+        #[arg(long)]
+        repo: String,
+        id: String,
+    },
 }
 
 #[derive(Subcommand, Debug)]
 enum UserCommand {
     #[command(about = "show account overview (default)")]
     Info,
-    #[command(about = "list active sessions for this user")]
-    Sessions,
+    #[command(
+        about = "list active sessions for this user",
+        after_help = "examples:\n  milieu user sessions\n  milieu user sessions revoke Ab3x9Q\n  milieu user sessions revoke --all-others"
+    )]
+    Sessions {
+        #[command(subcommand)]
+        command: Option<SessionsCommand>,
+    },
+    #[command(
+        about = "change your login password (recovery phrase and keys are unchanged)",
+        after_help = "examples:\n  milieu user password\n  milieu user password --revoke-other-sessions\n  printf '%s\\n%s\\n' \"$OLD\" \"$NEW\" | milieu user password --password-stdin"
+    )]
+    Password {
+        #[arg(long, help = "sign out every other session")]
+        revoke_other_sessions: bool,
+    },
+    #[command(
+        about = "dump account metadata (repos, access, sessions) as JSON",
+        after_help = "examples:\n  milieu user export\n  milieu user export --file account.json"
+    )]
+    Export {
+        #[arg(long, help = "write to this file instead of stdout")]
+        file: Option<String>,
+    },
+    #[command(
+        about = "delete your account, its keys and local keychain state",
+        after_help = "examples:\n  milieu user delete\n  milieu user delete --transfer api=sam@example.com --delete-owned-repos\n\nowned repos must be transferred to a collaborator or deleted first"
+    )]
+    Delete {
+        #[arg(long, value_name = "REPO=EMAIL", help = "hand an owned repo to a collaborator")]
+        transfer: Vec<String>,
+        #[arg(long, help = "delete every owned repo not given to --transfer")]
+        delete_owned_repos: bool,
+        #[arg(long, help = "do not write an account export before deleting")]
+        skip_export: bool,
+    },
     #[command(about = "check system prerequisites and configuration")]
     Doctor,
     #[command(about = "rotate recovery phrase and user keys")]
-    RotateKeys,
+    RotateKeys {
+        #[arg(long, help = "do not quiz the new recovery phrase")]
+        skip_verify: bool,
+    },
     #[command(about = "show the recovery phrase from keychain")]
     PhraseShow,
-    #[command(about = "check if recovery phrase exists in keychain")]
+    #[command(about = "check if recovery phrase exists in keychain and was confirmed")]
     PhraseStatus,
+    #[command(
+        about = "remove the recovery phrase from keychain (the cached UMK stays)",
+        after_help = "example: milieu user phrase-forget\n\nset `store_phrase = false` in config.toml to never store new phrases"
+    )]
+    PhraseForget,
+}
+
+#[derive(Subcommand, Debug)]
+enum SessionsCommand {
+    #[command(
+        about = "sign out another device by its token suffix",
+        after_help = "examples:\n  milieu user sessions revoke Ab3x9Q\n  milieu user sessions revoke --all-others --rotate-keys"
+    )]
+    Revoke {
+        #[arg(required_unless_present = "all_others", conflicts_with = "all_others")]
+        suffix: Option<String>,
+        #[arg(long, help = "revoke every session except this one")]
+        all_others: bool,
+        #[arg(long, help = "rotate the recovery phrase and user keys afterwards")]
+        rotate_keys: bool,
+        #[arg(long, help = "do not quiz the new recovery phrase")]
+        skip_verify: bool,
+    },
 }
 
 #[tokio::main]
-async fn main() {
-    if let Err(err) = run().await {
-        let message = format!("Error: {}", err);
-        eprintln!("{}", style::paint(style::RED, &message));
-        std::process::exit(1);
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(code) => code,
+        Err(err) if output::is_json() => {
+            output::emit_error(&err);
+            ExitCode::from(err.exit_code())
+        }
+        Err(err) => {
+            let message = format!("Error: {}", err);
+            eprintln!("{}", style::paint(style::RED, &message));
+            if let Some(hint) = err.hint() {
+                eprintln!("{}", style::paint(style::SUBTEXT1, &format!("hint: {}", hint)));
+            }
+            ExitCode::from(err.exit_code())
+        }
     }
 }
 
-async fn run() -> Result<()> {
+async fn run() -> Result<ExitCode> {
     if std::env::args().len() == 1 {
         print_banner_and_help()?;
-        return Ok(());
+        return Ok(ExitCode::SUCCESS);
     }
 
     let cli = Cli::parse();
+    output::set_format(cli.output);
+    commands::configure_input(commands::InputSettings {
+        assume_yes: cli.yes,
+        password_stdin: cli.password_stdin,
+        phrase_stdin: cli.phrase_stdin,
+    });
     init_tracing(cli.verbose);
     let config = Config::load()?;
+    api::configure(api::NetworkSettings {
+        connect_timeout: Duration::from_secs(config.connect_timeout_secs),
+        request_timeout: Duration::from_secs(cli.timeout.unwrap_or(config.request_timeout_secs)),
+        max_retries: config.max_retries,
+    });
     let profile = cli
         .profile
         .clone()
         .unwrap_or_else(|| config.active_profile.clone());
+    // Login without --profile names the profile after the email it asks for,
+    // so it picks the store itself once that is known.
+    if !matches!(cli.command, Commands::Login { .. }) || cli.profile.is_some() {
+        keychain::configure(config.secret_store_for(&profile)?)?;
+    }
+
+    if is_dry_run(&cli.command) {
+        cache::set_read_only();
+        keychain::set_read_only();
+    }
+
+    if uses_session(&cli.command) {
+        commands::login::ensure_session(&profile, uses_manifest_remote(&cli.command)).await?;
+    }
 
     match cli.command {
         Commands::Register => commands::register::run(&profile).await?,
-        Commands::Login => commands::login::run(cli.profile.clone()).await?,
+        Commands::Login { skip_verify } => {
+            commands::login::run(cli.profile.clone(), skip_verify).await?
+        }
         Commands::Logout => commands::logout::run(&profile).await?,
         Commands::User { command } => {
             match command.unwrap_or(UserCommand::Info) {
                 UserCommand::Info => commands::user::info(&profile).await?,
-                UserCommand::Sessions => commands::user::sessions(&profile).await?,
+                UserCommand::Sessions { command: None } => {
+                    commands::user::sessions(&profile).await?
+                }
+                UserCommand::Sessions {
+                    command:
+                        Some(SessionsCommand::Revoke {
+                            suffix,
+                            all_others,
+                            rotate_keys,
+                            skip_verify,
+                        }),
+                } => {
+                    commands::user::revoke_sessions(
+                        &profile,
+                        suffix,
+                        all_others,
+                        rotate_keys,
+                        skip_verify,
+                    )
+                    .await?
+                }
+                UserCommand::Password {
+                    revoke_other_sessions,
+                } => commands::user::change_password(&profile, revoke_other_sessions).await?,
+                UserCommand::Export { file } => {
+                    commands::user::export(&profile, file.as_deref()).await?
+                }
+                UserCommand::Delete {
+                    transfer,
+                    delete_owned_repos,
+                    skip_export,
+                } => {
+                    commands::user::delete(&profile, transfer, delete_owned_repos, skip_export)
+                        .await?
+                }
                 UserCommand::Doctor => commands::user::doctor(&profile)?,
-                UserCommand::RotateKeys => commands::user::rotate_keys(&profile).await?,
+                UserCommand::RotateKeys { skip_verify } => {
+                    commands::user::rotate_keys(&profile, skip_verify).await?
+                }
                 UserCommand::PhraseShow => commands::user::phrase_show(&profile)?,
                 UserCommand::PhraseStatus => commands::user::phrase_status(&profile)?,
+                UserCommand::PhraseForget => commands::user::phrase_forget(&profile)?,
             }
         }
         Commands::Init { name } => commands::init::run(&profile, name).await?,
@@ -331,6 +615,23 @@ async fn run() -> Result<()> {
                 ManageCommand::Delete { repo } => {
                     commands::repos::manage_delete(&profile, &repo).await?
                 }
+                ManageCommand::Token { command } => match command {
+                    TokenCommand::Create {
+                        repo,
+                        branch,
+                        access,
+                        name,
+                    } => {
+                        commands::repos::token_create(&profile, &repo, &branch, &access, name)
+                            .await?
+                    }
+                    TokenCommand::List { repo } => {
+                        commands::repos::token_list(&profile, &repo).await?
+                    }
+                    TokenCommand::Revoke { repo, id } => {
+                        commands::repos::token_revoke(&profile, &repo, &id).await?
+                    }
+                },
             },
         },
         Commands::Branch { command } => match command {
@@ -358,18 +659,105 @@ async fn run() -> Result<()> {
             path,
             version,
             branch,
+            dry_run,
         } => {
-            commands::checkout::run(&profile, path, version, branch).await?
+            commands::checkout::run(&profile, path, version, branch, dry_run).await?
         }
-        Commands::Changes { path, branch, version } => {
-            commands::changes::run(&profile, path, branch, version).await?
+        Commands::Changes {
+            path,
+            branch,
+            version,
+            offline,
+        } => commands::changes::run(&profile, path, branch, version, offline).await?,
+        Commands::Push {
+            branch,
+            all_branches,
+            dry_run,
+            force,
+        } => commands::push::run(&profile, branch, all_branches, dry_run, force).await?,
+        Commands::Pull {
+            branch,
+            all_branches,
+            dry_run,
+        } => commands::pull::run(&profile, branch, all_branches, dry_run).await?,
+        Commands::Stash { command } => match command {
+            StashCommand::Push { branch } => commands::stash::push(&profile, branch).await?,
+            StashCommand::Pop { index } => commands::stash::pop(&profile, index).await?,
+            StashCommand::List => commands::stash::list()?,
+            StashCommand::Drop { index } => commands::stash::drop(index)?,
+        },
+        Commands::Undo => commands::undo::run(&profile).await?,
+        Commands::Status {
+            json,
+            offline,
+            exit_code,
+        } => {
+            let in_sync = commands::status::run(&profile, json || output::is_json(), offline).await?;
+            if exit_code && !in_sync {
+                output::finish()?;
+                return Ok(ExitCode::from(error::exit::OUT_OF_SYNC));
+            }
         }
-        Commands::Push { branch } => commands::push::run(&profile, branch).await?,
-        Commands::Pull { branch } => commands::pull::run(&profile, branch).await?,
-        Commands::Status { json } => commands::status::run(&profile, json).await?,
     }
 
-    Ok(())
+    output::finish()?;
+    Ok(ExitCode::SUCCESS)
+}
+
+// Commands that send the stored session token to the server.
+fn uses_session(command: &Commands) -> bool {
+    !matches!(
+        command,
+        Commands::Register
+            | Commands::Login { .. }
+            | Commands::Logout
+            | Commands::Add { .. }
+            | Commands::Remove { .. }
+            | Commands::Branch {
+                command: BranchCommand::List
+            }
+            | Commands::Stash {
+                command: StashCommand::List | StashCommand::Drop { .. }
+            }
+            | Commands::User {
+                command: Some(
+                    UserCommand::Doctor
+                        | UserCommand::PhraseShow
+                        | UserCommand::PhraseStatus
+                        | UserCommand::PhraseForget
+                )
+            }
+            | Commands::Status { offline: true, .. }
+            | Commands::Changes { offline: true, .. }
+    )
+}
+
+// Dry runs leave the working tree, the object cache and the secret store as
+// they were.
+fn is_dry_run(command: &Commands) -> bool {
+    matches!(
+        command,
+        Commands::Checkout { dry_run: true, .. }
+            | Commands::Push { dry_run: true, .. }
+            | Commands::Pull { dry_run: true, .. }
+    )
+}
+
+// Commands that call the server in the project manifest's `remote`.
+fn uses_manifest_remote(command: &Commands) -> bool {
+    matches!(
+        command,
+        Commands::Checkout { .. }
+            | Commands::Changes { .. }
+            | Commands::Log { .. }
+            | Commands::Push { .. }
+            | Commands::Pull { .. }
+            | Commands::Status { .. }
+            | Commands::Undo
+            | Commands::Stash {
+                command: StashCommand::Push { .. } | StashCommand::Pop { .. }
+            }
+    )
 }
 
 fn init_tracing(verbosity: u8) {
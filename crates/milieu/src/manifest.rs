@@ -9,6 +9,10 @@ pub struct Manifest {
     pub repo_id: String,
     pub repo_name: String,
     pub active_branch: String,
+    #[serde(default)]
+    pub allowed_files: Vec<String>,
+    #[serde(default)]
+    pub max_repo_bytes: Option<u64>,
     #[serde(rename = "branch")]
     pub branches: Vec<Branch>,
 
@@ -36,6 +40,8 @@ pub struct FileEntry {
     pub last_synced_hash: Option<String>,
     #[serde(default)]
     pub last_synced_version: Option<u32>,
+    #[serde(default)]
+    pub matched_by: Option<String>,
 }
 
 impl FileEntry {
@@ -45,9 +51,14 @@ impl FileEntry {
             tag,
             last_synced_hash: None,
             last_synced_version: None,
+            matched_by: None,
         }
     }
 
+    pub fn is_pattern(&self) -> bool {
+        crate::repo::is_glob_pattern(&self.path)
+    }
+
     pub fn path(&self) -> &str {
         &self.path
     }
@@ -62,6 +73,42 @@ impl FileEntry {
     }
 }
 
+impl Branch {
+    pub fn patterns(&self) -> Vec<FileEntry> {
+        self.files
+            .iter()
+            .filter(|entry| entry.is_pattern())
+            .cloned()
+            .collect()
+    }
+
+    pub fn pattern_matches(&self, pattern: &str) -> Vec<String> {
+        self.files
+            .iter()
+            .filter(|entry| entry.matched_by.as_deref() == Some(pattern))
+            .map(|entry| entry.path.clone())
+            .collect()
+    }
+
+    // Adds a tracked entry for every concrete path a pattern matched and drops
+    // the ones it added earlier that no longer match, such as deleted files.
+    // Paths tracked explicitly (or by another pattern) are left untouched.
+    pub fn apply_pattern_matches(&mut self, pattern: &FileEntry, matches: &[String]) {
+        self.files.retain(|entry| {
+            entry.matched_by.as_deref() != Some(pattern.path.as_str())
+                || matches.contains(&entry.path)
+        });
+        for path in matches {
+            if self.files.iter().any(|entry| &entry.path == path) {
+                continue;
+            }
+            let mut entry = FileEntry::new(path.clone(), pattern.tag.clone());
+            entry.matched_by = Some(pattern.path.clone());
+            self.files.push(entry);
+        }
+    }
+}
+
 impl Manifest {
     pub fn load(path: &Path) -> Result<Self> {
         if !path.exists() {
@@ -81,6 +128,20 @@ impl Manifest {
         Ok(())
     }
 
+    pub fn validate_path(&self, path: &str) -> Result<()> {
+        crate::repo::validate_repo_path(path, &self.allowed_files)
+    }
+
+    pub fn validate_tracked(&self, path: &str) -> Result<()> {
+        crate::repo::validate_tracked_path(path, &self.allowed_files)
+    }
+
+    // Files outside the .env* convention are synced as opaque blobs: no line
+    // diffs or merge markers, only hash and size reporting.
+    pub fn is_opaque(&self, path: &str) -> bool {
+        !crate::repo::is_env_file(path)
+    }
+
     pub fn find_branch(&self, name: &str) -> Result<&Branch> {
         self.branches
             .iter()
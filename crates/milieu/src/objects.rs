@@ -0,0 +1,338 @@
+use crate::api::{ApiClient, ChunkRequest, ChunkResponse, ObjectRequest, ObjectResponse};
+use crate::cache;
+use crate::crypto::{
+    aad_for, compress, decompress, decrypt_bytes, decrypt_payload, encrypt_bytes, SCHEMA_VERSION,
+    UMK_LEN,
+};
+use crate::error::{MilieuError, Result};
+use base64::engine::general_purpose::STANDARD as B64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+// Compressed payloads above this size are uploaded as separate chunks.
+pub const CHUNK_SIZE: usize = 512 * 1024;
+// v4 objects hold an encrypted chunk manifest instead of the payload itself.
+pub const CHUNKED_SCHEMA_VERSION: u32 = 4;
+
+/// Identifies the file an object belongs to; every AAD is derived from it.
+pub struct ObjectScope<'a> {
+    pub repo_id: &'a str,
+    pub branch: &'a str,
+    pub path: &'a str,
+    pub tag: Option<&'a str>,
+}
+
+impl ObjectScope<'_> {
+    pub fn aad(&self, schema_version: u32) -> Vec<u8> {
+        aad_for(
+            schema_version,
+            self.repo_id,
+            self.branch,
+            self.path,
+            self.tag,
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkManifest {
+    total: u32,
+    size: u64,
+    chunks: Vec<String>,
+}
+
+fn chunk_aad(base: &[u8], index: u32, total: u32) -> Vec<u8> {
+    let mut aad = base.to_vec();
+    aad.extend_from_slice(format!("|chunk|{}/{}", index, total).as_bytes());
+    aad
+}
+
+/// Compresses, encrypts and uploads a file. Large payloads are split into
+/// chunks whose AAD binds their index and the total count; the object itself
+/// then carries an encrypted manifest listing each chunk's ciphertext hash.
+/// With `parent_version` set, the server rejects the write with a conflict
+/// unless that version is still the latest.
+pub async fn upload(
+    client: &ApiClient,
+    repo_key: &[u8; UMK_LEN],
+    scope: &ObjectScope<'_>,
+    data: &[u8],
+    parent_version: Option<u32>,
+) -> Result<ObjectResponse> {
+    let compressed = compress(data)?;
+    if compressed.len() <= CHUNK_SIZE {
+        let aad = scope.aad(SCHEMA_VERSION);
+        let (nonce, ciphertext) = encrypt_bytes(repo_key, &aad, &compressed)?;
+        let mut request = object_request(scope.path, &aad, nonce, ciphertext, SCHEMA_VERSION);
+        request.parent_version = parent_version;
+        let response = client
+            .post_object(scope.repo_id, scope.branch, &request)
+            .await?;
+        cache::store_object(scope.repo_id, scope.branch, &response)?;
+        return Ok(response);
+    }
+
+    let aad = scope.aad(CHUNKED_SCHEMA_VERSION);
+    let upload_id = uuid::Uuid::new_v4().to_string();
+    let (chunks, manifest) = seal_chunks(repo_key, &aad, &upload_id, scope.path, &compressed)?;
+    let total = manifest.total;
+    for request in &chunks {
+        client
+            .post_chunk(scope.repo_id, scope.branch, request)
+            .await?;
+    }
+
+    let (nonce, ciphertext) = encrypt_bytes(repo_key, &aad, &serde_json::to_vec(&manifest)?)?;
+    let mut request = object_request(scope.path, &aad, nonce, ciphertext, CHUNKED_SCHEMA_VERSION);
+    request.upload_id = Some(upload_id);
+    request.chunk_count = Some(total);
+    request.parent_version = parent_version;
+    let response = client
+        .post_object(scope.repo_id, scope.branch, &request)
+        .await?;
+    if let Some(version) = response.version {
+        for chunk in chunks {
+            let chunk = ChunkResponse {
+                index: chunk.index,
+                total: chunk.total,
+                nonce: chunk.nonce,
+                ciphertext: chunk.ciphertext,
+                ciphertext_hash: chunk.ciphertext_hash,
+            };
+            cache::store_chunk(scope.repo_id, scope.branch, scope.path, version, &chunk)?;
+        }
+    }
+    cache::store_object(scope.repo_id, scope.branch, &response)?;
+    Ok(response)
+}
+
+fn seal_chunks(
+    repo_key: &[u8; UMK_LEN],
+    aad: &[u8],
+    upload_id: &str,
+    path: &str,
+    compressed: &[u8],
+) -> Result<(Vec<ChunkRequest>, ChunkManifest)> {
+    let total = compressed.len().div_ceil(CHUNK_SIZE) as u32;
+    let mut chunks = Vec::new();
+    for (index, chunk) in compressed.chunks(CHUNK_SIZE).enumerate() {
+        let index = index as u32;
+        let (nonce, ciphertext) = encrypt_bytes(repo_key, &chunk_aad(aad, index, total), chunk)?;
+        let ciphertext_hash = blake3::hash(ciphertext.as_bytes()).to_hex().to_string();
+        chunks.push(ChunkRequest {
+            upload_id: upload_id.to_string(),
+            path: path.to_string(),
+            index,
+            total,
+            nonce,
+            ciphertext,
+            ciphertext_hash,
+        });
+    }
+    let manifest = ChunkManifest {
+        total,
+        size: compressed.len() as u64,
+        chunks: chunks.iter().map(|chunk| chunk.ciphertext_hash.clone()).collect(),
+    };
+    Ok((chunks, manifest))
+}
+
+/// Decrypts an object, fetching (or reading cached) chunks for chunked objects.
+/// A missing, reordered or swapped chunk fails authentication.
+pub async fn open(
+    client: &ApiClient,
+    repo_key: &[u8; UMK_LEN],
+    scope: &ObjectScope<'_>,
+    object: &ObjectResponse,
+) -> Result<Vec<u8>> {
+    let aad = scope.aad(object.schema_version);
+    if object.schema_version < CHUNKED_SCHEMA_VERSION {
+        return decrypt_payload(
+            repo_key,
+            object.schema_version,
+            &aad,
+            &object.nonce,
+            &object.ciphertext,
+        );
+    }
+
+    let manifest_bytes = decrypt_bytes(repo_key, &aad, &object.nonce, &object.ciphertext)?;
+    let manifest: ChunkManifest = serde_json::from_slice(&manifest_bytes)
+        .map_err(|e| MilieuError::Crypto(format!("invalid chunk manifest: {}", e)))?;
+    if manifest.chunks.len() != manifest.total as usize {
+        return Err(MilieuError::Crypto(
+            "chunk manifest count mismatch".to_string(),
+        ));
+    }
+    let version = object
+        .version
+        .ok_or_else(|| MilieuError::Crypto("chunked object missing version".to_string()))?;
+
+    let mut compressed = Vec::new();
+    for index in 0..manifest.total {
+        let chunk =
+            cache::chunk(client, scope.repo_id, scope.branch, scope.path, version, index).await?;
+        compressed.extend(open_chunk(repo_key, &aad, &manifest, index, &chunk, scope.path)?);
+    }
+    join_chunks(&compressed, &manifest, scope.path)
+}
+
+fn open_chunk(
+    repo_key: &[u8; UMK_LEN],
+    aad: &[u8],
+    manifest: &ChunkManifest,
+    index: u32,
+    chunk: &ChunkResponse,
+    path: &str,
+) -> Result<Vec<u8>> {
+    let actual_hash = blake3::hash(chunk.ciphertext.as_bytes())
+        .to_hex()
+        .to_string();
+    if manifest.chunks.get(index as usize) != Some(&actual_hash) {
+        return Err(MilieuError::Crypto(format!(
+            "chunk {} of {} does not match manifest",
+            index, path
+        )));
+    }
+    decrypt_bytes(
+        repo_key,
+        &chunk_aad(aad, index, manifest.total),
+        &chunk.nonce,
+        &chunk.ciphertext,
+    )
+}
+
+fn join_chunks(compressed: &[u8], manifest: &ChunkManifest, path: &str) -> Result<Vec<u8>> {
+    if compressed.len() as u64 != manifest.size {
+        return Err(MilieuError::Crypto(format!(
+            "chunked object {} is truncated",
+            path
+        )));
+    }
+    decompress(compressed)
+}
+
+fn object_request(
+    path: &str,
+    aad: &[u8],
+    nonce: String,
+    ciphertext: String,
+    schema_version: u32,
+) -> ObjectRequest {
+    let ciphertext_hash = blake3::hash(ciphertext.as_bytes()).to_hex().to_string();
+    ObjectRequest {
+        path: path.to_string(),
+        nonce,
+        ciphertext,
+        aad: B64.encode(aad),
+        ciphertext_hash,
+        created_at: chrono::Utc::now().to_rfc3339(),
+        schema_version,
+        upload_id: None,
+        chunk_count: None,
+        parent_version: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::generate_umk;
+    use rand_core::{OsRng, RngCore};
+
+    const PATH: &str = "certs/bundle.pem";
+
+    fn aad() -> Vec<u8> {
+        aad_for(CHUNKED_SCHEMA_VERSION, "repo", "dev", PATH, None)
+    }
+
+    fn response(chunk: &ChunkRequest) -> ChunkResponse {
+        ChunkResponse {
+            index: chunk.index,
+            total: chunk.total,
+            nonce: chunk.nonce.clone(),
+            ciphertext: chunk.ciphertext.clone(),
+            ciphertext_hash: chunk.ciphertext_hash.clone(),
+        }
+    }
+
+    // Random bytes do not compress, so this always spans several chunks.
+    fn sealed() -> ([u8; UMK_LEN], Vec<u8>, Vec<ChunkRequest>, ChunkManifest) {
+        let key = generate_umk();
+        let mut data = vec![0u8; CHUNK_SIZE * 2 + 1000];
+        OsRng.fill_bytes(&mut data);
+        let compressed = compress(&data).unwrap();
+        let (chunks, manifest) = seal_chunks(&key, &aad(), "upload", PATH, &compressed).unwrap();
+        (key, data, chunks, manifest)
+    }
+
+    fn open_all(
+        key: &[u8; UMK_LEN],
+        manifest: &ChunkManifest,
+        chunks: &[ChunkResponse],
+    ) -> Result<Vec<u8>> {
+        let mut compressed = Vec::new();
+        for (index, chunk) in chunks.iter().enumerate() {
+            compressed.extend(open_chunk(key, &aad(), manifest, index as u32, chunk, PATH)?);
+        }
+        join_chunks(&compressed, manifest, PATH)
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let (key, data, chunks, manifest) = sealed();
+        assert_eq!(manifest.total, 3);
+        assert_eq!(manifest.chunks.len(), 3);
+        let responses: Vec<_> = chunks.iter().map(response).collect();
+        assert_eq!(open_all(&key, &manifest, &responses).unwrap(), data);
+    }
+
+    #[test]
+    fn reordered_chunks_fail() {
+        let (key, _, chunks, mut manifest) = sealed();
+        let mut responses: Vec<_> = chunks.iter().map(response).collect();
+        responses.swap(0, 1);
+        assert!(open_all(&key, &manifest, &responses).is_err());
+
+        // Even with a manifest listing them in the new order, the AAD still
+        // binds each chunk to its original index.
+        manifest.chunks.swap(0, 1);
+        assert!(open_all(&key, &manifest, &responses).is_err());
+    }
+
+    #[test]
+    fn tampered_chunk_fails() {
+        let (key, _, chunks, mut manifest) = sealed();
+        let mut responses: Vec<_> = chunks.iter().map(response).collect();
+        let mut bytes = B64.decode(&responses[1].ciphertext).unwrap();
+        bytes[10] ^= 1;
+        responses[1].ciphertext = B64.encode(bytes);
+        assert!(open_all(&key, &manifest, &responses).is_err());
+
+        manifest.chunks[1] = blake3::hash(responses[1].ciphertext.as_bytes())
+            .to_hex()
+            .to_string();
+        assert!(open_all(&key, &manifest, &responses).is_err());
+    }
+
+    #[test]
+    fn chunks_are_bound_to_their_path() {
+        let (key, _, chunks, manifest) = sealed();
+        let other = aad_for(CHUNKED_SCHEMA_VERSION, "repo", "dev", "other.pem", None);
+        let chunk = response(&chunks[0]);
+        assert!(open_chunk(&key, &other, &manifest, 0, &chunk, PATH).is_err());
+    }
+
+    #[test]
+    fn truncated_object_fails() {
+        let (key, _, chunks, manifest) = sealed();
+        let mut compressed = Vec::new();
+        for (index, chunk) in chunks.iter().take(2).enumerate() {
+            compressed.extend(
+                open_chunk(&key, &aad(), &manifest, index as u32, &response(chunk), PATH).unwrap(),
+            );
+        }
+        let err = join_chunks(&compressed, &manifest, PATH).unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{}", err);
+    }
+}
@@ -0,0 +1,85 @@
+use crate::error::{MilieuError, Result};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    #[default]
+    Text,
+    Json,
+}
+
+static FORMAT: OnceLock<Format> = OnceLock::new();
+static EMITTED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_format(format: Format) {
+    let _ = FORMAT.set(format);
+}
+
+pub fn is_json() -> bool {
+    FORMAT.get().copied().unwrap_or_default() == Format::Json
+}
+
+// Human-readable lines. With `--output json` they move to stderr so stdout
+// carries nothing but the JSON document.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::output::is_json() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Prints a command's JSON document on stdout. Commands call this once, and
+/// only in JSON mode.
+pub fn emit<T: Serialize>(value: &T) -> Result<()> {
+    EMITTED.store(true, Ordering::Relaxed);
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+// Commands without a document of their own report plain success.
+pub fn finish() -> Result<()> {
+    if is_json() && !EMITTED.load(Ordering::Relaxed) {
+        emit(&serde_json::json!({ "ok": true }))?;
+    }
+    Ok(())
+}
+
+pub fn emit_error(err: &MilieuError) {
+    let mut error = serde_json::json!({
+        "code": error_code(err),
+        "message": err.to_string(),
+    });
+    if let Some(hint) = err.hint() {
+        error["hint"] = serde_json::json!(hint);
+    }
+    println!("{}", serde_json::json!({ "error": error }));
+}
+
+// Server errors keep the server's code; local ones are named by exit class.
+fn error_code(err: &MilieuError) -> String {
+    if let Some(api) = err.api() {
+        return api.code.clone();
+    }
+    let code = match err {
+        MilieuError::AuthMissing | MilieuError::UserIdMissing | MilieuError::UmkMissing => {
+            "not_logged_in"
+        }
+        MilieuError::RepoNotInitialized => "repo_not_initialized",
+        MilieuError::BranchNotFound(_) => "branch_not_found",
+        MilieuError::TomlDe(_) | MilieuError::TomlSer(_) | MilieuError::Json(_) => "invalid_data",
+        MilieuError::Validation(_) => "validation_failed",
+        MilieuError::Http(_) | MilieuError::Network(_) => "network_error",
+        MilieuError::Crypto(_) => "decryption_failed",
+        MilieuError::Io(_) => "io_error",
+        MilieuError::Keyring(_) => "keyring_error",
+        MilieuError::ConfigMissing => "config_missing",
+        MilieuError::InputRequired(_) => "input_required",
+        _ => "failed",
+    };
+    code.to_string()
+}
@@ -1,8 +1,11 @@
 use crate::error::{MilieuError, Result};
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
 use std::path::{Component, Path, PathBuf};
 
 pub fn project_root() -> Result<PathBuf> {
-    std::env::current_dir().map_err(|e| MilieuError::Io(e))
+    std::env::current_dir().map_err(MilieuError::Io)
 }
 
 pub fn manifest_path() -> Result<PathBuf> {
@@ -13,6 +16,40 @@ pub fn milieu_dir() -> Result<PathBuf> {
     Ok(project_root()?.join(".milieu"))
 }
 
+// Writes to a temp file next to `path`, fsyncs it and renames it into place,
+// so a crash leaves either the old or the new content, never a partial file.
+pub fn write_secure(path: &str, data: &[u8]) -> Result<()> {
+    let target = Path::new(path);
+    let parent = match target.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(parent)?;
+    let file_name = target
+        .file_name()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| MilieuError::CommandFailed(format!("invalid file path: {}", path)))?;
+    let temp = parent.join(format!(".{}.milieu-{}", file_name, uuid::Uuid::new_v4()));
+
+    let result = (|| -> Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&temp)?;
+        file.write_all(data)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.sync_all()?;
+        fs::rename(&temp, target)?;
+        fs::File::open(parent)?.sync_all()?;
+        Ok(())
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
 pub fn folder_name() -> Result<String> {
     let root = project_root()?;
     let name = root
@@ -29,39 +66,306 @@ pub fn is_valid_repo_name(name: &str) -> bool {
             .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_')
 }
 
-pub fn validate_env_path(path: &str) -> Result<()> {
+pub fn is_glob_pattern(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+pub fn validate_tracked_path(path: &str, allowed: &[String]) -> Result<()> {
+    if is_glob_pattern(path) {
+        validate_env_pattern(path, allowed)
+    } else {
+        validate_repo_path(path, allowed)
+    }
+}
+
+pub fn validate_env_pattern(pattern: &str, allowed: &[String]) -> Result<()> {
+    glob::Pattern::new(pattern)
+        .map_err(|e| MilieuError::Validation(format!("invalid pattern {}: {}", pattern, e)))?;
+    validate_relative(pattern)?;
+    let filename = pattern.rsplit('/').next().unwrap_or(pattern);
+    if filename.starts_with(".env") {
+        return Ok(());
+    }
+    // Read as a path, the pattern must itself fall under an allowlist entry
+    // (`certs/*.pem` under `*.pem`), so it can never reach beyond the list.
+    if allowed.iter().any(|entry| entry == pattern) || is_allowed_file(pattern, allowed) {
+        return Ok(());
+    }
+    Err(MilieuError::Validation(format!(
+        "pattern {} must end in a .env* filename or match an allowed_files entry",
+        pattern
+    )))
+}
+
+// Expands a tracked pattern against the working tree. Every match still goes
+// through validate_repo_path so a pattern can never widen what is syncable.
+pub fn expand_pattern(pattern: &str, allowed: &[String]) -> Result<Vec<String>> {
+    expand_pattern_in(&project_root()?, pattern, allowed)
+}
+
+fn expand_pattern_in(root: &Path, pattern: &str, allowed: &[String]) -> Result<Vec<String>> {
+    validate_env_pattern(pattern, allowed)?;
+    let root_str = root
+        .to_str()
+        .ok_or_else(|| MilieuError::Validation("invalid pattern path".to_string()))?;
+    // Brackets or stars in the checkout path must match literally.
+    let full = Path::new(&glob::Pattern::escape(root_str)).join(pattern);
+    let full = full
+        .to_str()
+        .ok_or_else(|| MilieuError::Validation("invalid pattern path".to_string()))?;
+    let paths = glob::glob_with(full, match_options())
+        .map_err(|e| MilieuError::Validation(format!("invalid pattern {}: {}", pattern, e)))?;
+
+    let mut out = Vec::new();
+    for path in paths.flatten() {
+        if !path.is_file() {
+            continue;
+        }
+        let rel = path.strip_prefix(root).unwrap_or(&path);
+        let rel_str = rel.to_string_lossy().to_string();
+        if validate_repo_path(&rel_str, allowed).is_ok() {
+            out.push(rel_str);
+        }
+    }
+    out.sort();
+    Ok(out)
+}
+
+pub fn is_env_file(path: &str) -> bool {
+    let filename = path.rsplit('/').next().unwrap_or(path);
+    filename == ".env" || filename.starts_with(".env.")
+}
+
+// Allowlist entries without a '/' match the filename anywhere in the repo;
+// entries with one match the full repo-relative path.
+pub fn is_allowed_file(path: &str, allowed: &[String]) -> bool {
+    let filename = path.rsplit('/').next().unwrap_or(path);
+    allowed.iter().any(|pattern| {
+        if pattern.contains('/') {
+            pattern_matches(pattern, path)
+        } else {
+            pattern_matches(pattern, filename)
+        }
+    })
+}
+
+// Like validate_env_path, but also accepts files matching the manifest's
+// allowed_files list. The traversal checks apply to both.
+pub fn validate_repo_path(path: &str, allowed: &[String]) -> Result<()> {
+    let result = validate_env_path(path);
+    if result.is_ok() || is_glob_pattern(path) || !is_allowed_file(path, allowed) {
+        return result;
+    }
+    validate_relative(path)?;
+    if Path::new(path).file_name().is_none() {
+        return Err(MilieuError::Validation("invalid file path".to_string()));
+    }
+    Ok(())
+}
+
+pub fn pattern_matches(pattern: &str, path: &str) -> bool {
+    glob::Pattern::new(pattern)
+        .map(|compiled| compiled.matches_with(path, match_options()))
+        .unwrap_or(false)
+}
+
+fn match_options() -> glob::MatchOptions {
+    glob::MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    }
+}
+
+// Writing under either of these runs code or rewrites sync state on the next
+// git or milieu command, so no allowlist entry can reach them. Compared
+// without case for case-insensitive filesystems.
+const PROTECTED_DIRS: &[&str] = &[".git", ".milieu"];
+
+fn validate_relative(path: &str) -> Result<()> {
     let candidate = Path::new(path);
     if candidate.as_os_str().is_empty() {
-        return Err(MilieuError::CommandFailed("invalid file path".to_string()));
+        return Err(MilieuError::Validation("invalid file path".to_string()));
     }
     if candidate.is_absolute() {
-        return Err(MilieuError::CommandFailed(
+        return Err(MilieuError::Validation(
             "only repo-relative .env* paths are allowed".to_string(),
         ));
     }
     for component in candidate.components() {
         match component {
             Component::ParentDir => {
-                return Err(MilieuError::CommandFailed(
+                return Err(MilieuError::Validation(
                     "path cannot contain '..'".to_string(),
                 ));
             }
             Component::Prefix(_) | Component::RootDir => {
-                return Err(MilieuError::CommandFailed(
+                return Err(MilieuError::Validation(
                     "only repo-relative .env* paths are allowed".to_string(),
                 ));
             }
-            Component::CurDir | Component::Normal(_) => {}
+            Component::Normal(name) => {
+                let name = name.to_string_lossy();
+                if PROTECTED_DIRS.iter().any(|dir| name.eq_ignore_ascii_case(dir)) {
+                    return Err(MilieuError::Validation(format!(
+                        "path cannot be inside {}",
+                        name
+                    )));
+                }
+            }
+            Component::CurDir => {}
         }
     }
+    Ok(())
+}
+
+pub fn validate_env_path(path: &str) -> Result<()> {
+    if is_glob_pattern(path) {
+        return Err(MilieuError::Validation(format!(
+            "expected a file path, got pattern: {}",
+            path
+        )));
+    }
+    validate_relative(path)?;
+    let candidate = Path::new(path);
     let filename = candidate
         .file_name()
         .and_then(|s| s.to_str())
-        .ok_or_else(|| MilieuError::CommandFailed("invalid file path".to_string()))?;
+        .ok_or_else(|| MilieuError::Validation("invalid file path".to_string()))?;
     if filename == ".env" || filename.starts_with(".env.") {
         return Ok(());
     }
-    Err(MilieuError::CommandFailed(
+    Err(MilieuError::Validation(
         "only .env* files are allowed".to_string(),
     ))
 }
+
+// Backups, stashes and pulls resolve paths against the working directory, so
+// tests that touch them take turns inside a throwaway project.
+#[cfg(test)]
+pub(crate) fn in_temp_project<T>(run: impl FnOnce() -> T) -> T {
+    static CWD: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    let _guard = CWD.lock().unwrap_or_else(|err| err.into_inner());
+    let root = std::env::temp_dir().join(format!("milieu-project-{}", uuid::Uuid::new_v4()));
+    fs::create_dir_all(root.join(".milieu")).unwrap();
+    let previous = std::env::current_dir().unwrap();
+    std::env::set_current_dir(&root).unwrap();
+    let result = run();
+    std::env::set_current_dir(previous).unwrap();
+    let _ = fs::remove_dir_all(&root);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir()
+            .join(format!("milieu-repo-{}", uuid::Uuid::new_v4()))
+            .join(name);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn touch(root: &Path, path: &str) {
+        let full = root.join(path);
+        fs::create_dir_all(full.parent().unwrap()).unwrap();
+        fs::write(full, b"x").unwrap();
+    }
+
+    #[test]
+    fn pattern_matches_keeps_star_within_one_component() {
+        assert!(pattern_matches("*.pem", "server.pem"));
+        assert!(!pattern_matches("*.pem", "certs/server.pem"));
+        assert!(pattern_matches("certs/*.pem", "certs/server.pem"));
+        assert!(pattern_matches("**/.env", "apps/web/.env"));
+        assert!(pattern_matches(".env.[ps]*", ".env.prod"));
+        assert!(!pattern_matches(".env.[ps]*", ".env.dev"));
+        assert!(pattern_matches("*", ".npmrc"));
+        assert!(!pattern_matches("[", "["));
+    }
+
+    #[test]
+    fn allowlist_entries_match_filename_or_full_path() {
+        let list = allowed(&["*.pem", "config/service.json"]);
+        assert!(is_allowed_file("certs/deep/ca.pem", &list));
+        assert!(is_allowed_file("config/service.json", &list));
+        assert!(!is_allowed_file("other/service.json", &list));
+        assert!(!is_allowed_file("certs/ca.key", &list));
+    }
+
+    #[test]
+    fn expands_patterns_against_the_tree() {
+        let root = temp_root("app");
+        touch(&root, ".env");
+        touch(&root, "apps/web/.env.local");
+        touch(&root, "apps/web/.env.example");
+        touch(&root, "certs/ca.pem");
+        touch(&root, "certs/ca.key");
+        touch(&root, ".git/.env");
+        fs::create_dir_all(root.join("apps/.env.dir")).unwrap();
+        let list = allowed(&["*.pem"]);
+
+        assert_eq!(
+            expand_pattern_in(&root, "**/.env*", &list).unwrap(),
+            vec![".env", "apps/web/.env.example", "apps/web/.env.local"]
+        );
+        assert_eq!(
+            expand_pattern_in(&root, "certs/*.pem", &list).unwrap(),
+            vec!["certs/ca.pem"]
+        );
+        assert!(expand_pattern_in(&root, "certs/*", &list).is_err());
+        fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn expansion_escapes_glob_characters_in_the_root() {
+        let root = temp_root("proj[1]*");
+        touch(&root, ".env");
+        touch(&root, ".env.prod");
+        // Would match "proj[1]*" read as a pattern, but is not the root.
+        let sibling = root.parent().unwrap().join("proj1x");
+        touch(&sibling, ".env.other");
+
+        assert_eq!(
+            expand_pattern_in(&root, ".env*", &[]).unwrap(),
+            vec![".env", ".env.prod"]
+        );
+        fs::remove_dir_all(root.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn rejects_git_and_milieu_paths_even_when_allowed() {
+        let list = allowed(&["*", ".git/hooks/*", ".milieu/*"]);
+        for path in [
+            ".git/hooks/pre-commit",
+            ".milieu/manifest.toml",
+            "sub/.git/config",
+            ".GIT/hooks/post-checkout",
+            ".git/.env",
+            ".milieu/.env.local",
+        ] {
+            assert!(validate_repo_path(path, &list).is_err(), "{}", path);
+        }
+        for pattern in [".git/hooks/*", ".milieu/*", "app/.git/*"] {
+            assert!(validate_tracked_path(pattern, &list).is_err(), "{}", pattern);
+        }
+    }
+
+    #[test]
+    fn accepts_env_files_and_allowed_paths_outside_protected_dirs() {
+        let list = allowed(&["*.pem"]);
+        assert!(validate_repo_path(".env", &list).is_ok());
+        assert!(validate_repo_path("app/.env.local", &list).is_ok());
+        assert!(validate_repo_path("certs/server.pem", &list).is_ok());
+        assert!(validate_repo_path(".github/.env", &list).is_ok());
+        assert!(validate_repo_path("certs/server.key", &list).is_err());
+        assert!(validate_repo_path("../.env", &list).is_err());
+        assert!(validate_repo_path("/etc/.env", &list).is_err());
+    }
+}
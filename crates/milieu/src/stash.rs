@@ -0,0 +1,168 @@
+use crate::backup::{read_sealed, write_sealed};
+use crate::crypto::UMK_LEN;
+use crate::error::{MilieuError, Result};
+use crate::repo::{milieu_dir, write_secure};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+const INDEX_FILE: &str = "index.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stash {
+    pub id: String,
+    pub repo_id: String,
+    pub branch: String,
+    pub created_at: String,
+    pub files: Vec<StashFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StashFile {
+    pub path: String,
+    pub base_version: Option<u32>,
+    pub base_hash: String,
+    pub blob: String,
+}
+
+impl Stash {
+    pub fn new(repo_id: &str, branch: &str) -> Self {
+        let now = chrono::Utc::now();
+        Self {
+            id: now.format("%Y%m%dT%H%M%S%.3fZ").to_string(),
+            repo_id: repo_id.to_string(),
+            branch: branch.to_string(),
+            created_at: now.to_rfc3339(),
+            files: Vec::new(),
+        }
+    }
+
+    pub fn add(
+        &mut self,
+        repo_key: &[u8; UMK_LEN],
+        path: &str,
+        base_version: Option<u32>,
+        base_hash: &str,
+        data: &[u8],
+    ) -> Result<()> {
+        let dir = stash_dir()?.join(&self.id);
+        fs::create_dir_all(&dir)?;
+        let blob = format!("{}.json", self.files.len());
+        write_sealed(&dir.join(&blob), repo_key, &self.aad(path), data)?;
+        self.files.push(StashFile {
+            path: path.to_string(),
+            base_version,
+            base_hash: base_hash.to_string(),
+            blob,
+        });
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let dir = stash_dir()?.join(&self.id);
+        fs::create_dir_all(&dir)?;
+        let data = serde_json::to_vec_pretty(self)?;
+        write_secure(&dir.join(INDEX_FILE).to_string_lossy(), &data)
+    }
+
+    pub fn read(&self, repo_key: &[u8; UMK_LEN], file: &StashFile) -> Result<Vec<u8>> {
+        let path = stash_dir()?.join(&self.id).join(&file.blob);
+        read_sealed(&path, repo_key, &self.aad(&file.path))
+    }
+
+    pub fn drop(&self) -> Result<()> {
+        fs::remove_dir_all(stash_dir()?.join(&self.id))?;
+        Ok(())
+    }
+
+    fn aad(&self, path: &str) -> Vec<u8> {
+        format!("stash|{}|{}|{}|{}", self.repo_id, self.branch, self.id, path).into_bytes()
+    }
+}
+
+fn stash_dir() -> Result<PathBuf> {
+    Ok(milieu_dir()?.join("stash"))
+}
+
+// Newest first, so index 0 is the most recent stash.
+pub fn list() -> Result<Vec<Stash>> {
+    let mut stashes = Vec::new();
+    let entries = match fs::read_dir(stash_dir()?) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(stashes),
+    };
+    for entry in entries.flatten() {
+        let index = entry.path().join(INDEX_FILE);
+        if let Ok(data) = fs::read(&index) {
+            stashes.push(serde_json::from_slice::<Stash>(&data)?);
+        }
+    }
+    stashes.sort_by(|a, b| b.id.cmp(&a.id));
+    Ok(stashes)
+}
+
+pub fn get(index: usize) -> Result<Stash> {
+    list()?
+        .into_iter()
+        .nth(index)
+        .ok_or_else(|| MilieuError::CommandFailed(format!("no stash at index {}", index)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::in_temp_project;
+
+    const KEY: [u8; UMK_LEN] = [7; UMK_LEN];
+
+    fn stash_with(branch: &str, path: &str, data: &[u8]) -> Stash {
+        let mut stash = Stash::new("repo", branch);
+        stash.add(&KEY, path, Some(2), "hash", data).unwrap();
+        stash.save().unwrap();
+        stash
+    }
+
+    #[test]
+    fn stashed_files_round_trip() {
+        in_temp_project(|| {
+            stash_with("dev", ".env", b"A=1\n");
+            let stash = get(0).unwrap();
+            assert_eq!(stash.branch, "dev");
+            assert_eq!(stash.files.len(), 1);
+            let file = &stash.files[0];
+            assert_eq!((file.path.as_str(), file.base_version), (".env", Some(2)));
+            assert_eq!(stash.read(&KEY, file).unwrap(), b"A=1\n");
+        });
+    }
+
+    #[test]
+    fn list_is_newest_first_and_drop_removes() {
+        in_temp_project(|| {
+            let older = stash_with("dev", ".env", b"old");
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            let newer = stash_with("dev", ".env", b"new");
+            let ids: Vec<String> = list().unwrap().into_iter().map(|stash| stash.id).collect();
+            assert_eq!(ids, vec![newer.id.clone(), older.id.clone()]);
+
+            newer.drop().unwrap();
+            assert_eq!(get(0).unwrap().id, older.id);
+            assert!(get(1).is_err());
+        });
+    }
+
+    #[test]
+    fn stashed_blobs_are_bound_to_their_stash() {
+        in_temp_project(|| {
+            let stash = stash_with("dev", ".env", b"A=1\n");
+            assert!(stash.read(&[8; UMK_LEN], &stash.files[0]).is_err());
+
+            // A blob read back under another branch or path fails to open.
+            let mut moved = get(0).unwrap();
+            moved.branch = "prod".to_string();
+            assert!(moved.read(&KEY, &moved.files[0]).is_err());
+            let mut renamed = get(0).unwrap();
+            renamed.files[0].path = ".env.prod".to_string();
+            assert!(renamed.read(&KEY, &renamed.files[0]).is_err());
+        });
+    }
+}
@@ -0,0 +1,48 @@
+use crate::error::{MilieuError, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as B64URL;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+const PREFIX: &str = "milieu_st1_";
+
+/// A service token as handed to CI: the server credential, plus the private
+/// key that the token's copy of the repo key is wrapped for. Nothing else is
+/// needed, so deploy targets run without a keychain or recovery phrase.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceToken {
+    pub base_url: String,
+    pub repo_id: String,
+    pub branch: String,
+    pub access: String,
+    pub credential: String,
+    pub private_key: String,
+}
+
+impl ServiceToken {
+    pub fn encode(&self) -> Result<String> {
+        Ok(format!("{}{}", PREFIX, B64URL.encode(serde_json::to_vec(self)?)))
+    }
+
+    pub fn decode(value: &str) -> Result<Self> {
+        let invalid = || MilieuError::Validation("MILIEU_TOKEN is not a milieu service token".to_string());
+        let body = value.trim().strip_prefix(PREFIX).ok_or_else(invalid)?;
+        let bytes = B64URL.decode(body).map_err(|_| invalid())?;
+        serde_json::from_slice(&bytes).map_err(|_| invalid())
+    }
+}
+
+static ACTIVE: OnceLock<Option<ServiceToken>> = OnceLock::new();
+
+// The token from MILIEU_TOKEN. A malformed token is an error rather than a
+// silent fall back to the keychain.
+pub fn active() -> Result<Option<&'static ServiceToken>> {
+    if let Some(token) = ACTIVE.get() {
+        return Ok(token.as_ref());
+    }
+    let token = match std::env::var("MILIEU_TOKEN") {
+        Ok(value) if !value.trim().is_empty() => Some(ServiceToken::decode(&value)?),
+        _ => None,
+    };
+    Ok(ACTIVE.get_or_init(|| token).as_ref())
+}
@@ -18,6 +18,9 @@ CREATE TABLE IF NOT EXISTS repos (
   FOREIGN KEY (owner_user_id) REFERENCES users(id)
 );
 
+-- Existing databases need:
+--   ALTER TABLE sessions ADD COLUMN refresh_token TEXT;
+--   ALTER TABLE sessions ADD COLUMN refresh_expires_at TEXT;
 CREATE TABLE IF NOT EXISTS sessions (
   token TEXT PRIMARY KEY,
   token_suffix TEXT NOT NULL DEFAULT '',
@@ -25,9 +28,36 @@ CREATE TABLE IF NOT EXISTS sessions (
   host TEXT NOT NULL,
   created_at TEXT NOT NULL,
   expires_at TEXT NOT NULL,
+  refresh_token TEXT,
+  refresh_expires_at TEXT,
   FOREIGN KEY (user_id) REFERENCES users(id)
 );
 
+CREATE INDEX IF NOT EXISTS sessions_refresh
+  ON sessions (refresh_token);
+
+-- Service tokens: repo- and branch-scoped credentials for CI and deploy
+-- targets, acting on behalf of the repo owner who created them
+CREATE TABLE IF NOT EXISTS service_tokens (
+  id TEXT PRIMARY KEY,
+  token TEXT NOT NULL UNIQUE,
+  token_suffix TEXT NOT NULL,
+  repo_id TEXT NOT NULL,
+  branch TEXT NOT NULL,
+  access TEXT NOT NULL,
+  name TEXT NOT NULL,
+  wrapped_key TEXT NOT NULL,
+  algorithm TEXT NOT NULL,
+  created_by_user_id TEXT NOT NULL,
+  created_at TEXT NOT NULL,
+  last_used_at TEXT,
+  FOREIGN KEY (repo_id) REFERENCES repos(id),
+  FOREIGN KEY (created_by_user_id) REFERENCES users(id)
+);
+
+CREATE INDEX IF NOT EXISTS service_tokens_repo
+  ON service_tokens (repo_id, created_at DESC);
+
 -- Login rate limiting
 CREATE TABLE IF NOT EXISTS login_attempts (
   key TEXT PRIMARY KEY,
@@ -106,6 +136,25 @@ CREATE TABLE IF NOT EXISTS env_objects (
 CREATE INDEX IF NOT EXISTS env_objects_lookup
   ON env_objects (repo_id, branch, path, created_at DESC);
 
+-- Chunks of large env objects; version is set once the object is committed
+CREATE TABLE IF NOT EXISTS env_object_chunks (
+  upload_id TEXT NOT NULL,
+  repo_id TEXT NOT NULL,
+  branch TEXT NOT NULL,
+  path TEXT NOT NULL,
+  idx INTEGER NOT NULL,
+  total INTEGER NOT NULL,
+  nonce TEXT NOT NULL,
+  ciphertext TEXT NOT NULL,
+  ciphertext_hash TEXT NOT NULL,
+  version INTEGER,
+  created_at TEXT NOT NULL,
+  PRIMARY KEY (upload_id, idx)
+);
+
+CREATE INDEX IF NOT EXISTS env_object_chunks_lookup
+  ON env_object_chunks (repo_id, branch, path, version, idx);
+
 CREATE TABLE IF NOT EXISTS repo_links (
   user_id TEXT NOT NULL,
   repo_id TEXT NOT NULL,
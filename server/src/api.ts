@@ -13,15 +13,29 @@ type RegisterRequest = LoginRequest;
 type LoginResponse = {
   access_token: string;
   user_id: string;
+  expires_at: string;
+  refresh_token: string;
+  refresh_expires_at: string;
   warning?: string;
 };
 
+type PasswordChangeRequest = {
+  current_password: string;
+  new_password: string;
+  revoke_other_sessions?: boolean;
+};
+
+type RefreshRequest = {
+  refresh_token: string;
+};
+
 type SessionInfo = {
   host: string;
   created_at: string;
   expires_at: string;
   token_suffix: string;
   active: boolean;
+  current: boolean;
 };
 
 type RepoCreateRequest = {
@@ -38,7 +52,12 @@ type RepoManifest = {
   repo_id: string;
   repo_name: string;
   active_branch: string;
-  branches: { name: string; files: { path: string; tag?: string }[] }[];
+  allowed_files?: string[];
+  max_repo_bytes?: number;
+  branches: {
+    name: string;
+    files: { path: string; tag?: string; matched_by?: string }[];
+  }[];
 };
 
 type RegisterResponse = {
@@ -68,6 +87,29 @@ type ObjectRequest = {
   version?: number;
   created_at: string;
   schema_version: number;
+  upload_id?: string;
+  chunk_count?: number;
+  // Version the client last synced (0 for a new file). When present, the
+  // write is rejected unless it is still the latest version.
+  parent_version?: number | null;
+};
+
+type ChunkRequest = {
+  upload_id: string;
+  path: string;
+  index: number;
+  total: number;
+  nonce: string;
+  ciphertext: string;
+  ciphertext_hash: string;
+};
+
+type LatestBatchRequest = {
+  paths: {
+    path: string;
+    known_hash?: string | null;
+    since_version?: number | null;
+  }[];
 };
 
 type HistoryEntry = {
@@ -84,6 +126,8 @@ type RepoAccessEntry = {
   created_at: string;
   public_key?: string;
   key_algorithm?: string;
+  // Whether the owner has shared a wrapped repo key with this collaborator.
+  has_repo_key?: boolean;
 };
 
 type InviteInfo = {
@@ -118,8 +162,44 @@ type RepoKeyResponse = {
   updated_at: string;
 };
 
+type ServiceTokenRequest = {
+  name?: string;
+  branch: string;
+  access: string;
+  wrapped_key: string;
+  algorithm: string;
+};
+
+type ServiceTokenInfo = {
+  id: string;
+  name: string;
+  branch: string;
+  access: string;
+  token_suffix: string;
+  created_at: string;
+  last_used_at: string | null;
+};
+
+type ServiceToken = {
+  id: string;
+  repo_id: string;
+  branch: string;
+  access: string;
+  wrapped_key: string;
+  algorithm: string;
+  created_at: string;
+  created_by_user_id: string;
+};
+
 const encoder = new TextEncoder();
-const MAX_REPO_BYTES = 1 * 1024 * 1024;
+const DEFAULT_MAX_REPO_BYTES = 5 * 1024 * 1024;
+// Clients send 512KB chunks; this leaves room for the tag and any slack.
+const MAX_CHUNK_BYTES = 1024 * 1024;
+// Chunks never committed by an object POST are dropped after this long.
+const PENDING_CHUNK_TTL_HOURS = 24;
+const MAX_BATCH_PATHS = 100;
+// Distinguishes service tokens from session tokens in the Authorization header.
+const SERVICE_TOKEN_PREFIX = "mst_";
 
 export async function handleApiRequest(
   request: Request,
@@ -131,6 +211,9 @@ export async function handleApiRequest(
   if (pathname === "/v1/auth/login" && request.method === "POST") {
     return handleLogin(request, env);
   }
+  if (pathname === "/v1/auth/refresh" && request.method === "POST") {
+    return handleRefresh(request, env);
+  }
 
     if (pathname === "/v1/auth/register" && request.method === "POST") {
       return handleRegister(request, env);
@@ -204,6 +287,24 @@ export async function handleApiRequest(
       });
     }
 
+    if (pathname === "/v1/users/me") {
+      return withAuth(request, env, async (userId) => {
+        if (request.method === "DELETE") {
+          return handleDeleteAccount(request, env, userId);
+        }
+        return json({ error: "method_not_allowed" }, 405);
+      });
+    }
+
+    if (pathname === "/v1/users/me/password") {
+      return withAuth(request, env, async (userId) => {
+        if (request.method === "PUT") {
+          return handleChangePassword(request, env, userId);
+        }
+        return json({ error: "method_not_allowed" }, 405);
+      });
+    }
+
     if (pathname === "/v1/users/me/repos") {
       return withAuth(request, env, async (userId) => {
         if (request.method === "GET") {
@@ -213,10 +314,23 @@ export async function handleApiRequest(
       });
     }
 
-    if (pathname === "/v1/users/me/sessions") {
+    const sessionsMatch = pathname.match(
+      /^\/v1\/users\/me\/sessions(?:\/([^/]+))?$/,
+    );
+    if (sessionsMatch) {
+      // Suffixes are base64 and may contain an encoded "/".
+      const suffix =
+        sessionsMatch[1] === undefined
+          ? undefined
+          : decodeURIComponent(sessionsMatch[1]);
       return withAuth(request, env, async (userId) => {
-        if (request.method === "GET") {
-          return handleGetSessions(userId, env);
+        const token = getBearerToken(request.headers.get("Authorization"));
+        const currentHash = token ? await hashToken(token) : "";
+        if (!suffix && request.method === "GET") {
+          return handleGetSessions(userId, env, currentHash);
+        }
+        if (request.method === "DELETE") {
+          return handleRevokeSessions(env, userId, currentHash, suffix ?? null);
         }
         return json({ error: "method_not_allowed" }, 405);
       });
@@ -256,12 +370,53 @@ export async function handleApiRequest(
         if (request.method === "POST" && !isLatest) {
           return handlePostObject(request, env, userId, repoId, branch);
         }
+        if (request.method === "POST" && isLatest) {
+          return handleGetLatestBatch(request, env, userId, repoId, branch);
+        }
         if (request.method === "GET" && isLatest) {
           const path = url.searchParams.get("path");
           if (!path) {
             return json({ error: "missing_path" }, 400);
           }
-          return handleGetLatest(env, userId, repoId, branch, path);
+          return handleGetLatest(
+            env,
+            userId,
+            repoId,
+            branch,
+            path,
+            request.headers.get("If-None-Match"),
+          );
+        }
+        return json({ error: "method_not_allowed" }, 405);
+      });
+    }
+
+    const chunkMatch = pathname.match(
+      /^\/v1\/repos\/([^/]+)\/branches\/([^/]+)\/objects\/chunks$/,
+    );
+    if (chunkMatch) {
+      const repoId = chunkMatch[1];
+      const branch = chunkMatch[2];
+      return withAuth(request, env, async (userId) => {
+        if (request.method === "POST") {
+          return handlePostChunk(request, env, userId, repoId, branch);
+        }
+        if (request.method === "GET") {
+          const path = url.searchParams.get("path");
+          const version = url.searchParams.get("version");
+          const index = url.searchParams.get("index");
+          if (!path || !version || !index) {
+            return json({ error: "missing_params" }, 400);
+          }
+          return handleGetChunk(
+            env,
+            userId,
+            repoId,
+            branch,
+            path,
+            Number(version),
+            Number(index),
+          );
         }
         return json({ error: "method_not_allowed" }, 405);
       });
@@ -314,6 +469,26 @@ export async function handleApiRequest(
       });
     }
 
+    const tokensMatch = pathname.match(
+      /^\/v1\/repos\/([^/]+)\/tokens(?:\/([^/]+))?$/,
+    );
+    if (tokensMatch) {
+      const repoId = tokensMatch[1];
+      const tokenId = tokensMatch[2];
+      return withAuth(request, env, async (userId) => {
+        if (!tokenId && request.method === "GET") {
+          return handleListServiceTokens(env, userId, repoId);
+        }
+        if (!tokenId && request.method === "POST") {
+          return handleCreateServiceToken(request, env, userId, repoId);
+        }
+        if (tokenId && request.method === "DELETE") {
+          return handleRevokeServiceToken(env, userId, repoId, tokenId);
+        }
+        return json({ error: "method_not_allowed" }, 405);
+      });
+    }
+
     const historyMatch = pathname.match(
       /^\/v1\/repos\/([^/]+)\/branches\/([^/]+)\/objects\/history$/,
     );
@@ -368,9 +543,11 @@ async function handleLogin(request: Request, env: Env): Promise<Response> {
     return json({ error: "invalid_request" }, 400);
   }
   const rateKey = `${ip}:${email}`;
-  const limited = await isLoginRateLimited(env, rateKey);
-  if (limited) {
-    return json({ error: "rate_limited" }, 429);
+  const retryAfter = await loginRetryAfter(env, rateKey);
+  if (retryAfter !== null) {
+    const response = json({ error: "rate_limited" }, 429);
+    response.headers.set("Retry-After", String(retryAfter));
+    return response;
   }
   const host =
     body.host && body.host.trim().length > 0 ? body.host.trim() : "unknown";
@@ -397,35 +574,85 @@ async function handleLogin(request: Request, env: Env): Promise<Response> {
     return json({ error: "invalid_credentials" }, 401);
   }
 
+  await expireHostSessions(env, row.id as string, host);
+  const response = await createSession(env, row.id as string, host);
+  await clearLoginAttempts(env, rateKey);
+  await pruneSessions(env, row.id as string, 12);
+
+  return json(
+    { ...response, warning: "Beta testing: use at your own risk." },
+    200,
+  );
+}
+
+// Trades a refresh token for a new session. Refresh tokens are single use:
+// the old session is removed, so a stolen copy stops working once either
+// side refreshes.
+async function handleRefresh(request: Request, env: Env): Promise<Response> {
+  const body = await request.json<RefreshRequest>().catch(() => null);
+  if (!body?.refresh_token) {
+    return json({ error: "invalid_request" }, 400);
+  }
+  const refreshHash = await hashToken(body.refresh_token);
+  const row = await env.DB.prepare(
+    "SELECT token, user_id, host, refresh_expires_at FROM sessions WHERE refresh_token = ?",
+  )
+    .bind(refreshHash)
+    .first<Record<string, string>>();
+  if (!row) {
+    return json({ error: "invalid_refresh_token" }, 401);
+  }
+  const refreshExpires = new Date(row.refresh_expires_at);
+  if (Number.isNaN(refreshExpires.getTime()) || refreshExpires < new Date()) {
+    return json({ error: "refresh_token_expired" }, 401);
+  }
+
+  await env.DB.prepare("DELETE FROM sessions WHERE token = ?")
+    .bind(row.token)
+    .run();
+  return json(await createSession(env, row.user_id, row.host), 200);
+}
+
+async function createSession(
+  env: Env,
+  userId: string,
+  host: string,
+): Promise<LoginResponse> {
   const token = await randomToken();
-  const tokenHash = await hashToken(token);
+  const refreshToken = await randomToken();
   const tokenSuffix = token.length > 6 ? token.slice(-6) : token;
   const now = new Date();
   const ttlHours = Number(env.SESSION_TTL_HOURS || "720");
+  const refreshTtlHours = Number(env.REFRESH_TTL_HOURS || "2160");
   const expiresAt = new Date(now.getTime() + ttlHours * 3600 * 1000);
+  const refreshExpiresAt = new Date(
+    now.getTime() + refreshTtlHours * 3600 * 1000,
+  );
 
-  await expireHostSessions(env, row.id as string, host);
   await env.DB.prepare(
-    "INSERT INTO sessions (token, token_suffix, user_id, host, created_at, expires_at) VALUES (?, ?, ?, ?, ?, ?)",
+    `INSERT INTO sessions
+       (token, token_suffix, user_id, host, created_at, expires_at, refresh_token, refresh_expires_at)
+     VALUES (?, ?, ?, ?, ?, ?, ?, ?)`,
   )
     .bind(
-      tokenHash,
+      await hashToken(token),
       tokenSuffix,
-      row.id as string,
+      userId,
       host,
       now.toISOString(),
       expiresAt.toISOString(),
+      await hashToken(refreshToken),
+      refreshExpiresAt.toISOString(),
     )
     .run();
-  await clearLoginAttempts(env, rateKey);
-  await pruneSessions(env, row.id as string, 12);
 
-  const response: LoginResponse = {
+  return {
     access_token: token,
-    user_id: row.id as string,
-    warning: "Beta testing: use at your own risk.",
+    user_id: userId,
+    expires_at: expiresAt.toISOString(),
+    refresh_token: refreshToken,
+    refresh_expires_at: refreshExpiresAt.toISOString(),
   };
-  return json(response, 200);
 }
 
 async function handleLogout(
@@ -438,20 +665,26 @@ async function handleLogout(
   const tokenHash = await hashToken(token);
   const now = new Date().toISOString();
   await env.DB.prepare(
-    "UPDATE sessions SET expires_at = ? WHERE token = ? AND user_id = ?",
+    "UPDATE sessions SET expires_at = ?, refresh_expires_at = ? WHERE token = ? AND user_id = ?",
   )
-    .bind(now, tokenHash, userId)
+    .bind(now, now, tokenHash, userId)
     .run();
   return json({ ok: true }, 200);
 }
 
+function maxRepoBytes(env: Env): number {
+  const value = Number(env.MAX_REPO_BYTES || DEFAULT_MAX_REPO_BYTES);
+  return Number.isFinite(value) && value > 0 ? value : DEFAULT_MAX_REPO_BYTES;
+}
+
 function rateConfig(env: Env): { limit: number; windowSeconds: number } {
   const limit = Number(env.LOGIN_RATE_LIMIT || "10");
   const windowSeconds = Number(env.LOGIN_RATE_WINDOW_SECONDS || "900");
   return { limit, windowSeconds };
 }
 
-async function isLoginRateLimited(env: Env, key: string): Promise<boolean> {
+// Seconds until the login window for `key` resets, or null when not limited.
+async function loginRetryAfter(env: Env, key: string): Promise<number | null> {
   const { limit, windowSeconds } = rateConfig(env);
   const row = await env.DB.prepare(
     "SELECT count, window_start FROM login_attempts WHERE key = ?",
@@ -459,7 +692,7 @@ async function isLoginRateLimited(env: Env, key: string): Promise<boolean> {
     .bind(key)
     .first<Record<string, string | number>>();
 
-  if (!row) return false;
+  if (!row) return null;
 
   const count = Number(row.count ?? 0);
   const windowStart = new Date(String(row.window_start));
@@ -468,10 +701,11 @@ async function isLoginRateLimited(env: Env, key: string): Promise<boolean> {
     await env.DB.prepare("DELETE FROM login_attempts WHERE key = ?")
       .bind(key)
       .run();
-    return false;
+    return null;
   }
 
-  return count >= limit;
+  if (count < limit) return null;
+  return Math.max(1, Math.ceil((windowEnd - Date.now()) / 1000));
 }
 
 async function recordLoginAttempt(env: Env, key: string): Promise<void> {
@@ -523,9 +757,9 @@ async function expireHostSessions(
 ): Promise<void> {
   const now = new Date().toISOString();
   await env.DB.prepare(
-    "UPDATE sessions SET expires_at = ? WHERE user_id = ? AND host = ?",
+    "UPDATE sessions SET expires_at = ?, refresh_expires_at = ? WHERE user_id = ? AND host = ?",
   )
-    .bind(now, userId, host)
+    .bind(now, now, userId, host)
     .run();
 }
 
@@ -588,9 +822,221 @@ async function handleRegister(request: Request, env: Env): Promise<Response> {
   }
 }
 
-async function handleGetSessions(userId: string, env: Env): Promise<Response> {
+// Only the login password changes; the UMK blob is encrypted with the
+// recovery phrase and is not touched.
+async function handleChangePassword(
+  request: Request,
+  env: Env,
+  userId: string,
+): Promise<Response> {
+  const body = await request.json<PasswordChangeRequest>().catch(() => null);
+  if (!body?.current_password || !body.new_password) {
+    return json({ error: "invalid_request" }, 400);
+  }
+  const rateKey = `password:${userId}`;
+  const retryAfter = await loginRetryAfter(env, rateKey);
+  if (retryAfter !== null) {
+    const response = json({ error: "rate_limited" }, 429);
+    response.headers.set("Retry-After", String(retryAfter));
+    return response;
+  }
+
+  const row = await env.DB.prepare(
+    "SELECT password_hash, password_salt, password_iters FROM users WHERE id = ?",
+  )
+    .bind(userId)
+    .first<Record<string, string | number>>();
+  if (!row) {
+    return json({ error: "user_not_found" }, 404);
+  }
+  const ok = await verifyPassword(
+    body.current_password,
+    row.password_salt as string,
+    row.password_iters as number,
+    row.password_hash as string,
+  );
+  if (!ok) {
+    await recordLoginAttempt(env, rateKey);
+    return json({ error: "invalid_password" }, 403);
+  }
+  await clearLoginAttempts(env, rateKey);
+
+  const { hash, salt, iters } = await hashPassword(body.new_password);
+  await env.DB.prepare(
+    "UPDATE users SET password_hash = ?, password_salt = ?, password_iters = ? WHERE id = ?",
+  )
+    .bind(hash, salt, iters, userId)
+    .run();
+
+  let revoked = 0;
+  if (body.revoke_other_sessions) {
+    const token = getBearerToken(request.headers.get("Authorization"));
+    const result = await env.DB.prepare(
+      "DELETE FROM sessions WHERE user_id = ? AND token != ?",
+    )
+      .bind(userId, token ? await hashToken(token) : "")
+      .run();
+    revoked = result.meta.changes ?? 0;
+  }
+  return json({ ok: true, revoked }, 200);
+}
+
+type AccountDeleteRequest = {
+  password?: string;
+  transfers?: { repo_id?: string; email?: string }[];
+  delete_repos?: string[];
+};
+
+// Removes the account and everything tied to it. Every owned repo must be
+// handed to a collaborator or deleted in the same request, so nothing is
+// touched until the password and the whole plan have been checked.
+async function handleDeleteAccount(
+  request: Request,
+  env: Env,
+  userId: string,
+): Promise<Response> {
+  const body = await request.json<AccountDeleteRequest>().catch(() => null);
+  if (!body?.password) return json({ error: "invalid_request" }, 400);
+
+  const rateKey = `password:${userId}`;
+  const retryAfter = await loginRetryAfter(env, rateKey);
+  if (retryAfter !== null) {
+    const response = json({ error: "rate_limited" }, 429);
+    response.headers.set("Retry-After", String(retryAfter));
+    return response;
+  }
+  const row = await env.DB.prepare(
+    "SELECT email, password_hash, password_salt, password_iters FROM users WHERE id = ?",
+  )
+    .bind(userId)
+    .first<Record<string, string | number>>();
+  if (!row) return json({ error: "user_not_found" }, 404);
+  const ok = await verifyPassword(
+    body.password,
+    row.password_salt as string,
+    row.password_iters as number,
+    row.password_hash as string,
+  );
+  if (!ok) {
+    await recordLoginAttempt(env, rateKey);
+    return json({ error: "invalid_password" }, 403);
+  }
+  await clearLoginAttempts(env, rateKey);
+
+  const owned = await env.DB.prepare(
+    "SELECT id, name FROM repos WHERE owner_user_id = ?",
+  )
+    .bind(userId)
+    .all<Record<string, string>>();
+  const ownedIds = new Set(owned.results.map((repo) => repo.id));
+  const transfers = body.transfers ?? [];
+  const deletes = body.delete_repos ?? [];
+  for (const repoId of [...transfers.map((t) => t.repo_id ?? ""), ...deletes]) {
+    if (!ownedIds.has(repoId)) return json({ error: "repo_not_found" }, 404);
+  }
+
+  const plannedTransfers: { repoId: string; targetId: string }[] = [];
+  for (const repo of owned.results) {
+    const transfer = transfers.find((t) => t.repo_id === repo.id);
+    if (transfer) {
+      const target = await resolveTransferTarget(env, repo, transfer.email);
+      if (target instanceof Response) return target;
+      plannedTransfers.push({ repoId: repo.id, targetId: target });
+    } else if (!deletes.includes(repo.id)) {
+      return json({ error: "owns_repos" }, 409);
+    }
+  }
+
+  for (const { repoId, targetId } of plannedTransfers) {
+    await transferRepo(env, repoId, targetId);
+  }
+  for (const repoId of deletes) {
+    await deleteRepoData(env, repoId);
+  }
+
+  const statements = [
+    "DELETE FROM repo_keys WHERE user_id = ?",
+    "DELETE FROM repo_access WHERE user_id = ?",
+    "DELETE FROM repo_links WHERE user_id = ?",
+    "DELETE FROM repo_key_events WHERE requester_user_id = ? OR target_user_id = ?",
+    "DELETE FROM repo_invites WHERE invited_by_user_id = ?",
+    "DELETE FROM umk_blobs WHERE user_id = ?",
+    "DELETE FROM user_keys WHERE user_id = ?",
+    "DELETE FROM sessions WHERE user_id = ?",
+    "DELETE FROM users WHERE id = ?",
+  ];
+  for (const sql of statements) {
+    const placeholders = sql.split("?").length - 1;
+    await env.DB.prepare(sql)
+      .bind(...Array(placeholders).fill(userId))
+      .run();
+  }
+  await env.DB.prepare("DELETE FROM repo_invites WHERE email = ? AND status = 'pending'")
+    .bind(row.email as string)
+    .run();
+  return json(
+    { ok: true, transferred: plannedTransfers.length, deleted: deletes.length },
+    200,
+  );
+}
+
+// A new owner must already be a collaborator (not a pending invitee) and hold
+// a wrapped copy of the repo key; otherwise the key would be lost with the
+// old owner's account.
+async function resolveTransferTarget(
+  env: Env,
+  repo: Record<string, string>,
+  rawEmail?: string,
+): Promise<string | Response> {
+  const email = normalizeEmail(rawEmail);
+  if (!email) return json({ error: "missing_email" }, 400);
+
+  const target = await env.DB.prepare(
+    `SELECT users.id as id, repo_keys.user_id as key_user_id
+     FROM repo_access
+     JOIN users ON users.id = repo_access.user_id
+     LEFT JOIN repo_keys
+       ON repo_keys.repo_id = repo_access.repo_id AND repo_keys.user_id = users.id
+     WHERE repo_access.repo_id = ? AND users.email = ?`,
+  )
+    .bind(repo.id, email)
+    .first<Record<string, string | null>>();
+  if (!target) return json({ error: "transfer_target_not_collaborator" }, 409);
+  if (!target.key_user_id) return json({ error: "transfer_target_no_key" }, 409);
+
+  const clash = await env.DB.prepare(
+    "SELECT id FROM repos WHERE owner_user_id = ? AND name = ?",
+  )
+    .bind(target.id, repo.name)
+    .first<Record<string, string>>();
+  if (clash) return json({ error: "repo_exists" }, 409);
+  return target.id as string;
+}
+
+// Service tokens keep working under the new owner.
+async function transferRepo(env: Env, repoId: string, targetId: string): Promise<void> {
+  await env.DB.prepare("UPDATE repos SET owner_user_id = ? WHERE id = ?")
+    .bind(targetId, repoId)
+    .run();
+  await env.DB.prepare(
+    "DELETE FROM repo_access WHERE repo_id = ? AND user_id = ?",
+  )
+    .bind(repoId, targetId)
+    .run();
+  await env.DB.prepare(
+    "UPDATE service_tokens SET created_by_user_id = ? WHERE repo_id = ?",
+  )
+    .bind(targetId, repoId)
+    .run();
+}
+
+async function handleGetSessions(
+  userId: string,
+  env: Env,
+  currentHash: string,
+): Promise<Response> {
   const rows = await env.DB.prepare(
-    `SELECT host, created_at, expires_at, token_suffix
+    `SELECT token, host, created_at, expires_at, token_suffix
      FROM sessions
      WHERE user_id = ?
      ORDER BY created_at DESC`,
@@ -609,12 +1055,50 @@ async function handleGetSessions(userId: string, env: Env): Promise<Response> {
       expires_at: row.expires_at ?? "",
       token_suffix: suffix,
       active,
+      current: row.token === currentHash,
     };
   });
 
   return json(sessions, 200);
 }
 
+// Deletes the session(s) ending in `suffix`, or with no suffix every session
+// except the caller's. The caller's own session is left to logout.
+async function handleRevokeSessions(
+  env: Env,
+  userId: string,
+  currentHash: string,
+  suffix: string | null,
+): Promise<Response> {
+  if (suffix !== null) {
+    const current = await env.DB.prepare(
+      "SELECT token_suffix FROM sessions WHERE token = ?",
+    )
+      .bind(currentHash)
+      .first<Record<string, string>>();
+    if (current?.token_suffix === suffix) {
+      return json({ error: "cannot_revoke_current" }, 400);
+    }
+  }
+  const result =
+    suffix === null
+      ? await env.DB.prepare(
+          "DELETE FROM sessions WHERE user_id = ? AND token != ?",
+        )
+          .bind(userId, currentHash)
+          .run()
+      : await env.DB.prepare(
+          "DELETE FROM sessions WHERE user_id = ? AND token_suffix = ? AND token != ?",
+        )
+          .bind(userId, suffix, currentHash)
+          .run();
+  const revoked = result.meta.changes ?? 0;
+  if (suffix !== null && revoked === 0) {
+    return json({ error: "session_not_found" }, 404);
+  }
+  return json({ revoked }, 200);
+}
+
 async function handleCreateRepo(
   request: Request,
   env: Env,
@@ -702,9 +1186,17 @@ async function handleDeleteRepo(
   const repo = await ensureRepoOwned(env, userId, repoId);
   if (!repo) return json({ error: "repo_not_found" }, 404);
 
+  await deleteRepoData(env, repoId);
+  return json({ ok: true }, 200);
+}
+
+async function deleteRepoData(env: Env, repoId: string): Promise<void> {
   await env.DB.prepare("DELETE FROM env_objects WHERE repo_id = ?")
     .bind(repoId)
     .run();
+  await env.DB.prepare("DELETE FROM env_object_chunks WHERE repo_id = ?")
+    .bind(repoId)
+    .run();
   await env.DB.prepare("DELETE FROM repo_keys WHERE repo_id = ?")
     .bind(repoId)
     .run();
@@ -717,11 +1209,12 @@ async function handleDeleteRepo(
   await env.DB.prepare("DELETE FROM repo_links WHERE repo_id = ?")
     .bind(repoId)
     .run();
+  await env.DB.prepare("DELETE FROM service_tokens WHERE repo_id = ?")
+    .bind(repoId)
+    .run();
   await env.DB.prepare("DELETE FROM repos WHERE id = ?")
     .bind(repoId)
     .run();
-
-  return json({ ok: true }, 200);
 }
 
 async function handleGetManifest(
@@ -765,6 +1258,16 @@ async function handlePutManifest(
   if (body.repo_id !== repoId) {
     return json({ error: "repo_id_mismatch" }, 400);
   }
+  // A manifest may lower the cap for its repo but never lift the server's.
+  if (
+    body.max_repo_bytes !== undefined &&
+    body.max_repo_bytes !== null &&
+    (!Number.isInteger(body.max_repo_bytes) ||
+      body.max_repo_bytes <= 0 ||
+      body.max_repo_bytes > maxRepoBytes(env))
+  ) {
+    return json({ error: "invalid_max_repo_bytes" }, 400);
+  }
 
   await env.DB.prepare("UPDATE repos SET manifest_json = ? WHERE id = ?")
     .bind(JSON.stringify(body), repoId)
@@ -989,8 +1492,16 @@ async function handlePostObject(
   ) {
     return json({ error: "invalid_request" }, 400);
   }
+  const hasParent =
+    body.parent_version !== undefined && body.parent_version !== null;
+  if (
+    hasParent &&
+    (!Number.isInteger(body.parent_version) || Number(body.parent_version) < 0)
+  ) {
+    return json({ error: "invalid_request" }, 400);
+  }
 
-  if (!isValidEnvPath(body.path)) {
+  if (!(await isAllowedRepoPath(env, repoId, body.path))) {
     return json({ error: "invalid_path" }, 400);
   }
 
@@ -1004,22 +1515,46 @@ async function handlePostObject(
   } catch {
     return json({ error: "invalid_ciphertext" }, 400);
   }
+
+  if (body.upload_id) {
+    const chunks = await env.DB.prepare(
+      `SELECT COUNT(*) AS count, MIN(total) AS total, SUM(length(ciphertext)) AS size
+       FROM env_object_chunks
+       WHERE upload_id = ? AND repo_id = ? AND branch = ? AND path = ? AND version IS NULL`,
+    )
+      .bind(body.upload_id, repoId, branch, body.path)
+      .first<Record<string, number>>();
+    const count = Number(chunks?.count ?? 0);
+    if (count === 0 || count !== body.chunk_count || count !== Number(chunks?.total)) {
+      return json({ error: "incomplete_upload" }, 400);
+    }
+    newSize += Number(chunks?.size ?? 0);
+  }
   const currentSize = await latestObjectSize(env, repoId, branch, body.path);
   const totalLatest = await totalLatestSize(env, repoId);
   const nextTotal = totalLatest - currentSize + newSize;
 
-  if (nextTotal > MAX_REPO_BYTES) {
+  if (nextTotal > maxRepoBytes(env)) {
     return json({ error: "repo_size_exceeded" }, 413);
   }
 
   const id = crypto.randomUUID();
   const createdAt = new Date().toISOString();
   const nextVersion = await nextVersionFor(env, repoId, branch, body.path);
+  if (hasParent && body.parent_version !== nextVersion - 1) {
+    return conflictResponse(nextVersion - 1);
+  }
 
-  await env.DB.prepare(
+  // The insert only succeeds while no row holds nextVersion, so two writers
+  // racing past the check above cannot both land on the same parent.
+  const inserted = await env.DB.prepare(
     `INSERT INTO env_objects
      (id, repo_id, branch, path, nonce, ciphertext, aad, ciphertext_hash, version, created_at, client_created_at, schema_version)
-     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)`,
+     SELECT ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?
+     WHERE NOT EXISTS (
+       SELECT 1 FROM env_objects
+       WHERE repo_id = ? AND branch = ? AND path = ? AND version >= ?
+     )`,
   )
     .bind(
       id,
@@ -1034,8 +1569,26 @@ async function handlePostObject(
       createdAt,
       body.created_at ?? null,
       body.schema_version,
+      repoId,
+      branch,
+      body.path,
+      nextVersion,
     )
     .run();
+  if (!inserted.meta.changes) {
+    return conflictResponse(
+      (await nextVersionFor(env, repoId, branch, body.path)) - 1,
+    );
+  }
+
+  if (body.upload_id) {
+    await env.DB.prepare(
+      `UPDATE env_object_chunks SET version = ?
+       WHERE upload_id = ? AND repo_id = ? AND branch = ? AND path = ? AND version IS NULL`,
+    )
+      .bind(nextVersion, body.upload_id, repoId, branch, body.path)
+      .run();
+  }
 
   await pruneHistory(env, repoId, branch, body.path, nextVersion);
 
@@ -1062,18 +1615,153 @@ async function handlePostObject(
   );
 }
 
+function conflictResponse(currentVersion: number): Response {
+  return json({ error: "conflict", current_version: currentVersion }, 409);
+}
+
+async function handlePostChunk(
+  request: Request,
+  env: Env,
+  userId: string,
+  repoId: string,
+  branch: string,
+): Promise<Response> {
+  const repo = await ensureRepoAccess(env, userId, repoId, "write");
+  if (!repo) {
+    return json({ error: "repo_not_found" }, 404);
+  }
+
+  const body = await request.json<ChunkRequest>().catch(() => null);
+  if (
+    !body ||
+    !body.upload_id ||
+    !body.path ||
+    !body.nonce ||
+    !body.ciphertext ||
+    !body.ciphertext_hash ||
+    !Number.isInteger(body.index) ||
+    !Number.isInteger(body.total) ||
+    body.index < 0 ||
+    body.index >= body.total
+  ) {
+    return json({ error: "invalid_request" }, 400);
+  }
+
+  if (!(await isAllowedRepoPath(env, repoId, body.path))) {
+    return json({ error: "invalid_path" }, 400);
+  }
+
+  let chunkSize = 0;
+  try {
+    chunkSize = base64ToBytes(body.ciphertext).length;
+  } catch {
+    return json({ error: "invalid_ciphertext" }, 400);
+  }
+  if (chunkSize > MAX_CHUNK_BYTES) {
+    return json({ error: "chunk_too_large" }, 413);
+  }
+
+  const cutoff = new Date(
+    Date.now() - PENDING_CHUNK_TTL_HOURS * 3600 * 1000,
+  ).toISOString();
+  await env.DB.prepare(
+    `DELETE FROM env_object_chunks
+     WHERE repo_id = ? AND version IS NULL AND created_at < ?`,
+  )
+    .bind(repoId, cutoff)
+    .run();
+
+  // Uploads still in flight count against the cap too, so chunks that are
+  // never committed cannot grow the repo past it.
+  const pending = await env.DB.prepare(
+    `SELECT COALESCE(SUM(length(ciphertext)), 0) AS size
+     FROM env_object_chunks
+     WHERE repo_id = ? AND version IS NULL`,
+  )
+    .bind(repoId)
+    .first<Record<string, number>>();
+  const nextTotal =
+    (await totalLatestSize(env, repoId)) +
+    Number(pending?.size ?? 0) +
+    body.ciphertext.length;
+  if (nextTotal > maxRepoBytes(env)) {
+    return json({ error: "repo_size_exceeded" }, 413);
+  }
+
+  await env.DB.prepare(
+    `INSERT INTO env_object_chunks
+     (upload_id, repo_id, branch, path, idx, total, nonce, ciphertext, ciphertext_hash, version, created_at)
+     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, NULL, ?)
+     ON CONFLICT(upload_id, idx) DO NOTHING`,
+  )
+    .bind(
+      body.upload_id,
+      repoId,
+      branch,
+      body.path,
+      body.index,
+      body.total,
+      body.nonce,
+      body.ciphertext,
+      body.ciphertext_hash,
+      new Date().toISOString(),
+    )
+    .run();
+
+  return json({ ok: true }, 200);
+}
+
+async function handleGetChunk(
+  env: Env,
+  userId: string,
+  repoId: string,
+  branch: string,
+  path: string,
+  version: number,
+  index: number,
+): Promise<Response> {
+  const repo = await ensureRepoAccess(env, userId, repoId, "read");
+  if (!repo) return json({ error: "repo_not_found" }, 404);
+  if (!(await isAllowedRepoPath(env, repoId, path))) {
+    return json({ error: "invalid_path" }, 400);
+  }
+
+  const row = await env.DB.prepare(
+    `SELECT idx, total, nonce, ciphertext, ciphertext_hash
+     FROM env_object_chunks
+     WHERE repo_id = ? AND branch = ? AND path = ? AND version = ? AND idx = ?
+     LIMIT 1`,
+  )
+    .bind(repoId, branch, path, version, index)
+    .first<Record<string, string | number>>();
+
+  if (!row) return json({ error: "not_found" }, 404);
+
+  return json(
+    {
+      index: Number(row.idx),
+      total: Number(row.total),
+      nonce: row.nonce,
+      ciphertext: row.ciphertext,
+      ciphertext_hash: row.ciphertext_hash,
+    },
+    200,
+  );
+}
+
 async function handleGetLatest(
   env: Env,
   _userId: string,
   repoId: string,
   branch: string,
   path: string,
+  ifNoneMatch: string | null,
 ): Promise<Response> {
   const repo = await ensureRepoAccess(env, _userId, repoId, "read");
   if (!repo) {
     return json({ error: "repo_not_found" }, 404);
   }
-  if (!isValidEnvPath(path)) {
+  if (!(await isAllowedRepoPath(env, repoId, path))) {
     return json({ error: "invalid_path" }, 400);
   }
 
@@ -1091,6 +1779,12 @@ async function handleGetLatest(
     return json({ error: "not_found" }, 404);
   }
 
+  // Clients send the ciphertext hash they already cache as an ETag.
+  const etag = `"${row.ciphertext_hash}"`;
+  if (ifNoneMatch && ifNoneMatch === etag) {
+    return new Response(null, { status: 304, headers: { ETag: etag } });
+  }
+
   return json(
     {
       path: row.path,
@@ -1106,6 +1800,95 @@ async function handleGetLatest(
   );
 }
 
+// Latest object for many paths in one request. Paths whose latest object
+// matches `known_hash`, or is no newer than `since_version`, come back as
+// metadata only.
+async function handleGetLatestBatch(
+  request: Request,
+  env: Env,
+  userId: string,
+  repoId: string,
+  branch: string,
+): Promise<Response> {
+  const repo = await ensureRepoAccess(env, userId, repoId, "read");
+  if (!repo) {
+    return json({ error: "repo_not_found" }, 404);
+  }
+
+  const body = await request.json<LatestBatchRequest>().catch(() => null);
+  if (!body || !Array.isArray(body.paths) || body.paths.some((p) => !p?.path)) {
+    return json({ error: "invalid_request" }, 400);
+  }
+  if (body.paths.length > MAX_BATCH_PATHS) {
+    return json({ error: "too_many_paths" }, 400);
+  }
+  const isAllowed = repoPathChecker(env, repoId);
+  for (const query of body.paths) {
+    if (!(await isAllowed(query.path))) {
+      return json({ error: "invalid_path" }, 400);
+    }
+  }
+  if (body.paths.length === 0) {
+    return json({ objects: [] }, 200);
+  }
+
+  const placeholders = body.paths.map(() => "?").join(", ");
+  const { results } = await env.DB.prepare(
+    `SELECT o.path, o.nonce, o.ciphertext, o.aad, o.ciphertext_hash, o.version, o.created_at, o.schema_version
+     FROM env_objects o
+     WHERE o.repo_id = ? AND o.branch = ? AND o.path IN (${placeholders})
+       AND o.created_at = (
+         SELECT MAX(created_at) FROM env_objects
+         WHERE repo_id = o.repo_id AND branch = o.branch AND path = o.path
+       )`,
+  )
+    .bind(repoId, branch, ...body.paths.map((query) => query.path))
+    .all<Record<string, string | number>>();
+
+  const rows = new Map<string, Record<string, string | number>>();
+  for (const row of results ?? []) {
+    rows.set(String(row.path), row);
+  }
+
+  const objects = body.paths.map((query) => {
+    const row = rows.get(query.path);
+    if (!row) {
+      return { path: query.path, status: "missing" };
+    }
+    const version = Number(row.version);
+    const unchanged =
+      (query.known_hash && query.known_hash === row.ciphertext_hash) ||
+      (Number.isInteger(query.since_version) &&
+        version <= Number(query.since_version));
+    if (unchanged) {
+      return {
+        path: query.path,
+        status: "not_modified",
+        version,
+        ciphertext_hash: row.ciphertext_hash,
+      };
+    }
+    return {
+      path: query.path,
+      status: "found",
+      version,
+      ciphertext_hash: row.ciphertext_hash,
+      object: {
+        path: row.path,
+        nonce: row.nonce,
+        ciphertext: row.ciphertext,
+        aad: row.aad,
+        ciphertext_hash: row.ciphertext_hash,
+        version,
+        created_at: row.created_at,
+        schema_version: Number(row.schema_version),
+      },
+    };
+  });
+
+  return json({ objects }, 200);
+}
+
 async function handleHistory(
   env: Env,
   userId: string,
@@ -1115,7 +1898,9 @@ async function handleHistory(
 ): Promise<Response> {
   const repo = await ensureRepoAccess(env, userId, repoId, "read");
   if (!repo) return json({ error: "repo_not_found" }, 404);
-  if (!isValidEnvPath(path)) return json({ error: "invalid_path" }, 400);
+  if (!(await isAllowedRepoPath(env, repoId, path))) {
+    return json({ error: "invalid_path" }, 400);
+  }
 
   const rows = await env.DB.prepare(
     `SELECT version, created_at, ciphertext_hash
@@ -1145,7 +1930,9 @@ async function handleGetVersion(
 ): Promise<Response> {
   const repo = await ensureRepoAccess(env, userId, repoId, "read");
   if (!repo) return json({ error: "repo_not_found" }, 404);
-  if (!isValidEnvPath(path)) return json({ error: "invalid_path" }, 400);
+  if (!(await isAllowedRepoPath(env, repoId, path))) {
+    return json({ error: "invalid_path" }, 400);
+  }
 
   const row = await env.DB.prepare(
     `SELECT path, nonce, ciphertext, aad, ciphertext_hash, version, created_at, schema_version
@@ -1217,15 +2004,18 @@ async function handleGetAccess(
             repo_access.role as role,
             repo_access.created_at as created_at,
             user_keys.public_key as public_key,
-            user_keys.algorithm as key_algorithm
+            user_keys.algorithm as key_algorithm,
+            repo_keys.user_id IS NOT NULL as has_repo_key
      FROM repo_access
      JOIN users ON users.id = repo_access.user_id
      LEFT JOIN user_keys ON user_keys.user_id = users.id
+     LEFT JOIN repo_keys
+       ON repo_keys.repo_id = repo_access.repo_id AND repo_keys.user_id = users.id
      WHERE repo_access.repo_id = ?
      ORDER BY users.email ASC`,
   )
     .bind(repoId)
-    .all<Record<string, string>>();
+    .all<Record<string, string | number>>();
 
   const pendingRows = await env.DB.prepare(
     `SELECT repo_invites.email as email, repo_invites.role as role, repo_invites.created_at as created_at,
@@ -1240,12 +2030,13 @@ async function handleGetAccess(
 
   const entries: RepoAccessEntry[] = [
     ...activeRows.results.map((row) => ({
-      email: row.email,
-      role: row.role,
+      email: row.email as string,
+      role: row.role as string,
       status: "active" as const,
-      created_at: row.created_at,
-      public_key: row.public_key ?? undefined,
-      key_algorithm: row.key_algorithm ?? undefined,
+      created_at: row.created_at as string,
+      public_key: (row.public_key as string | null) ?? undefined,
+      key_algorithm: (row.key_algorithm as string | null) ?? undefined,
+      has_repo_key: Boolean(row.has_repo_key),
     })),
     ...pendingRows.results.map((row) => ({
       email: row.email,
@@ -1514,6 +2305,89 @@ async function handleInviteAction(
   return json({ ok: true }, 200);
 }
 
+async function handleCreateServiceToken(
+  request: Request,
+  env: Env,
+  userId: string,
+  repoId: string,
+): Promise<Response> {
+  const repo = await ensureRepoOwned(env, userId, repoId);
+  if (!repo) return json({ error: "repo_not_found" }, 404);
+
+  const body = (await request.json()) as ServiceTokenRequest;
+  if (!body.branch || !body.wrapped_key || !body.algorithm) {
+    return json({ error: "invalid_request" }, 400);
+  }
+  if (!isValidAccessRole(body.access)) {
+    return json({ error: "invalid_role" }, 400);
+  }
+
+  const id = crypto.randomUUID();
+  const token = `${SERVICE_TOKEN_PREFIX}${await randomToken()}`;
+  const name = body.name?.trim() || `${body.branch}-${body.access}`;
+  await env.DB.prepare(
+    `INSERT INTO service_tokens
+       (id, token, token_suffix, repo_id, branch, access, name, wrapped_key, algorithm, created_by_user_id, created_at)
+     VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)`,
+  )
+    .bind(
+      id,
+      await hashToken(token),
+      token.slice(-6),
+      repoId,
+      body.branch,
+      body.access,
+      name,
+      body.wrapped_key,
+      body.algorithm,
+      userId,
+      new Date().toISOString(),
+    )
+    .run();
+
+  return json({ id, name, token }, 200);
+}
+
+async function handleListServiceTokens(
+  env: Env,
+  userId: string,
+  repoId: string,
+): Promise<Response> {
+  const repo = await ensureRepoOwned(env, userId, repoId);
+  if (!repo) return json({ error: "repo_not_found" }, 404);
+
+  const rows = await env.DB.prepare(
+    `SELECT id, name, branch, access, token_suffix, created_at, last_used_at
+     FROM service_tokens
+     WHERE repo_id = ?
+     ORDER BY created_at DESC`,
+  )
+    .bind(repoId)
+    .all<ServiceTokenInfo>();
+
+  return json({ tokens: rows.results ?? [] }, 200);
+}
+
+async function handleRevokeServiceToken(
+  env: Env,
+  userId: string,
+  repoId: string,
+  tokenId: string,
+): Promise<Response> {
+  const repo = await ensureRepoOwned(env, userId, repoId);
+  if (!repo) return json({ error: "repo_not_found" }, 404);
+
+  const result = await env.DB.prepare(
+    "DELETE FROM service_tokens WHERE repo_id = ? AND id = ?",
+  )
+    .bind(repoId, tokenId)
+    .run();
+  if (!result.meta.changes) {
+    return json({ error: "service_token_not_found" }, 404);
+  }
+  return json({ ok: true }, 200);
+}
+
 async function handleListBranches(
   env: Env,
   userId: string,
@@ -1543,6 +2417,9 @@ async function withAuth(
     return json({ error: "missing_auth" }, 401);
   }
   const tokenHash = await hashToken(token);
+  if (token.startsWith(SERVICE_TOKEN_PREFIX)) {
+    return withServiceToken(request, env, tokenHash, handler);
+  }
 
   const row = await env.DB.prepare(
     "SELECT user_id, expires_at FROM sessions WHERE token = ?",
@@ -1562,6 +2439,67 @@ async function withAuth(
   return handler(row.user_id);
 }
 
+// Service tokens act as the owner who created them, but only on their own
+// repo and branch: reads, plus object writes for write tokens. The repo key
+// request returns the copy wrapped for the token's own keypair.
+async function withServiceToken(
+  request: Request,
+  env: Env,
+  tokenHash: string,
+  handler: (userId: string) => Promise<Response>,
+): Promise<Response> {
+  const row = await env.DB.prepare(
+    `SELECT id, repo_id, branch, access, wrapped_key, algorithm, created_at, created_by_user_id
+     FROM service_tokens WHERE token = ?`,
+  )
+    .bind(tokenHash)
+    .first<ServiceToken>();
+  if (!row) {
+    return json({ error: "invalid_token" }, 401);
+  }
+
+  const pathname = new URL(request.url).pathname;
+  const repoPrefix = `/v1/repos/${row.repo_id}`;
+  const objectsPrefix = `${repoPrefix}/branches/${row.branch}/objects`;
+  const isRead =
+    request.method === "GET" ||
+    (request.method === "POST" && pathname === `${objectsPrefix}/latest`);
+  const isObjectWrite =
+    request.method === "POST" &&
+    (pathname === objectsPrefix || pathname === `${objectsPrefix}/chunks`);
+
+  let allowed = false;
+  if (
+    pathname === `${repoPrefix}/manifest` ||
+    pathname === objectsPrefix ||
+    pathname.startsWith(`${objectsPrefix}/`)
+  ) {
+    allowed = isRead || (isObjectWrite && row.access === "write");
+  }
+  const isKey = pathname === `${repoPrefix}/key` && request.method === "GET";
+  if (!allowed && !isKey) {
+    return json({ error: "token_scope" }, 403);
+  }
+
+  await env.DB.prepare("UPDATE service_tokens SET last_used_at = ? WHERE id = ?")
+    .bind(new Date().toISOString(), row.id)
+    .run();
+
+  if (isKey) {
+    if (!(await ensureRepoAccess(env, row.created_by_user_id, row.repo_id, "read"))) {
+      return json({ error: "repo_not_found" }, 404);
+    }
+    const response: RepoKeyResponse = {
+      wrapped_key: row.wrapped_key,
+      algorithm: row.algorithm,
+      created_at: row.created_at,
+      updated_at: row.created_at,
+    };
+    return json(response, 200);
+  }
+  return handler(row.created_by_user_id);
+}
+
 function getBearerToken(header: string | null): string | null {
   if (!header) return null;
   const [scheme, token] = header.split(" ");
@@ -1634,7 +2572,131 @@ async function pbkdf2(
   return new Uint8Array(bits);
 }
 
+// Human-readable text for each error code. Responses carry
+// `{ error, message, hint }`; `error` stays the stable machine-readable code.
+const ERROR_DETAILS: Record<string, { message: string; hint?: string }> = {
+  repo_not_found: {
+    message: "repo not found or you do not have access to it",
+    hint: "check the name with `milieu repos list` or ask the owner for access",
+  },
+  not_found: { message: "nothing stored at this path yet" },
+  user_not_found: {
+    message: "no user with that email",
+    hint: "they need to run `milieu register` first",
+  },
+  access_not_found: { message: "that user has no access to this repo" },
+  invite_not_found: {
+    message: "invite not found",
+    hint: "list pending invites with `milieu repos manage invites`",
+  },
+  method_not_allowed: {
+    message: "the server does not support this request",
+    hint: "the server may be older than this client",
+  },
+  invalid_request: { message: "the request was malformed" },
+  invalid_path: {
+    message: "path is not allowed in this repo",
+    hint: "only .env files and paths matching `allowed_files` can be stored",
+  },
+  missing_path: { message: "a file path is required" },
+  missing_params: { message: "required parameters are missing" },
+  missing_name: { message: "a repo name is required" },
+  missing_email: { message: "an email is required" },
+  invalid_role: { message: "access must be read or write" },
+  invalid_email: { message: "that email address is not valid" },
+  invalid_user: { message: "that user is not valid" },
+  invalid_repo_name: {
+    message: "repo names may only contain letters, digits, - and _",
+  },
+  invalid_action: { message: "unknown invite action" },
+  invalid_ciphertext: { message: "ciphertext is not valid base64" },
+  incomplete_upload: {
+    message: "not every chunk of the upload arrived",
+    hint: "push again",
+  },
+  too_many_paths: { message: "too many paths in one request" },
+  repo_id_mismatch: { message: "manifest belongs to a different repo" },
+  cannot_invite_owner: { message: "the owner already has full access" },
+  missing_auth: { message: "not logged in", hint: "run `milieu login`" },
+  invalid_token: {
+    message: "your session is no longer valid",
+    hint: "run `milieu login`",
+  },
+  token_expired: { message: "your session expired", hint: "run `milieu login`" },
+  invalid_refresh_token: {
+    message: "your session can no longer be refreshed",
+    hint: "run `milieu login`",
+  },
+  refresh_token_expired: {
+    message: "your session expired and can no longer be refreshed",
+    hint: "run `milieu login`",
+  },
+  token_scope: {
+    message: "this service token does not allow that request",
+    hint: "service tokens only reach their own repo and branch",
+  },
+  session_not_found: {
+    message: "no session ends with that suffix",
+    hint: "list sessions with `milieu user sessions`",
+  },
+  cannot_revoke_current: {
+    message: "that is the session you are using",
+    hint: "run `milieu logout` to end it",
+  },
+  service_token_not_found: {
+    message: "service token not found",
+    hint: "list tokens with `milieu repos manage token list --repo <name>`",
+  },
+  invalid_credentials: { message: "wrong email or password" },
+  invalid_password: {
+    message: "current password is incorrect",
+    hint: "check it and try again; the recovery phrase is not your password",
+  },
+  rate_limited: {
+    message: "too many attempts",
+    hint: "wait a few minutes before trying again",
+  },
+  invalid_max_repo_bytes: {
+    message: "max_repo_bytes in the manifest is above the server's size limit",
+    hint: "lower it in .milieu/manifest.toml or ask the server admin to raise MAX_REPO_BYTES",
+  },
+  repo_size_exceeded: {
+    message: "repo would exceed the server's size limit",
+    hint: "remove files or ask the server admin to raise MAX_REPO_BYTES",
+  },
+  chunk_too_large: {
+    message: "upload chunk is larger than the server accepts",
+    hint: "upgrade milieu; current clients send 512KB chunks",
+  },
+  conflict: {
+    message: "the file changed on the server since your last pull",
+    hint: "run `milieu pull` first, or push with --force to overwrite",
+  },
+  repo_exists: { message: "a repo with that name already exists" },
+  owns_repos: {
+    message: "you still own repos",
+    hint: "transfer or delete them first",
+  },
+  transfer_target_not_collaborator: {
+    message: "the new owner must be a collaborator who accepted the invite",
+  },
+  transfer_target_no_key: {
+    message: "the new owner has no copy of the repo key yet",
+    hint: "share it with `milieu repos manage share --repo <name>`, or pick another collaborator",
+  },
+  email_exists: { message: "an account with that email already exists" },
+  already_has_access: { message: "that user already has access" },
+};
+
 function json(body: Json, status: number): Response {
+  if (typeof body.error === "string" && body.message === undefined) {
+    const details = ERROR_DETAILS[body.error];
+    body = {
+      ...body,
+      message: details?.message ?? body.error.replace(/_/g, " "),
+      ...(details?.hint ? { hint: details.hint } : {}),
+    };
+  }
   return new Response(JSON.stringify(body), {
     status,
     headers: { "content-type": "application/json" },
@@ -1673,6 +2735,114 @@ function isValidEnvPath(path: string): boolean {
   return filename === ".env" || filename.startsWith(".env.");
 }
 
+function isSafeRepoPath(path: string): boolean {
+  if (!path || path.startsWith("/") || path.includes("\\")) return false;
+  const parts = path.split("/");
+  return !parts.some((part) => part === "" || part === "." || part === "..");
+}
+
+// Same grammar as the client's `glob` crate with a literal separator: `*`
+// and `?` stay within one path component, `**` as a whole component spans
+// any number of directories, and `[...]` / `[!...]` are character classes.
+function globToRegExp(pattern: string): RegExp {
+  let source = "";
+  let i = 0;
+  while (i < pattern.length) {
+    const ch = pattern[i];
+    const atComponentStart = i === 0 || pattern[i - 1] === "/";
+    if (ch === "*" && pattern[i + 1] === "*" && atComponentStart) {
+      if (pattern[i + 2] === "/") {
+        source += "(?:[^/]*/)*";
+        i += 3;
+        continue;
+      }
+      if (i + 2 === pattern.length) {
+        source += ".*";
+        i += 2;
+        continue;
+      }
+    }
+    if (ch === "*") {
+      source += "[^/]*";
+    } else if (ch === "?") {
+      source += "[^/]";
+    } else if (ch === "[") {
+      const cls = parseCharClass(pattern, i);
+      if (cls) {
+        source += cls.source;
+        i = cls.end;
+        continue;
+      }
+      source += "\\[";
+    } else {
+      source += ch.replace(/[.+^${}()|[\]\\]/g, "\\$&");
+    }
+    i += 1;
+  }
+  return new RegExp(`^${source}$`);
+}
+
+// Parses `[...]` starting at `start`. A `]` right after `[` or `[!` is a
+// literal member. Returns null when the class is never closed.
+function parseCharClass(
+  pattern: string,
+  start: number,
+): { source: string; end: number } | null {
+  let i = start + 1;
+  const negated = pattern[i] === "!";
+  if (negated) i += 1;
+  let members = "";
+  let first = true;
+  while (i < pattern.length && (pattern[i] !== "]" || first)) {
+    const ch = pattern[i];
+    members += ch.replace(/[\\[\]^]/g, "\\$&");
+    first = false;
+    i += 1;
+  }
+  if (i >= pattern.length) return null;
+  // Classes never match the separator.
+  const source = negated ? `[^/${members}]` : `(?!/)[${members}]`;
+  return { source, end: i + 1 };
+}
+
+// .env* paths are always accepted; anything else must match the repo's
+// allowed_files list from the stored manifest. The list is loaded at most
+// once per checker, so batch requests read the manifest a single time.
+function repoPathChecker(
+  env: Env,
+  repoId: string,
+): (path: string) => Promise<boolean> {
+  let allowed: { regex: RegExp; byPath: boolean }[] | null = null;
+  return async (path: string) => {
+    if (isValidEnvPath(path)) return true;
+    if (!isSafeRepoPath(path)) return false;
+    if (allowed === null) {
+      const row = await env.DB.prepare(
+        "SELECT manifest_json FROM repos WHERE id = ?",
+      )
+        .bind(repoId)
+        .first<Record<string, string>>();
+      const manifest = row?.manifest_json
+        ? (JSON.parse(row.manifest_json) as RepoManifest)
+        : null;
+      allowed = (manifest?.allowed_files ?? []).map((pattern) => ({
+        regex: globToRegExp(pattern),
+        byPath: pattern.includes("/"),
+      }));
+    }
+    const filename = path.split("/").pop() ?? path;
+    return allowed.some(({ regex, byPath }) => regex.test(byPath ? path : filename));
+  };
+}
+
+function isAllowedRepoPath(
+  env: Env,
+  repoId: string,
+  path: string,
+): Promise<boolean> {
+  return repoPathChecker(env, repoId)(path);
+}
+
 function isValidRepoName(name: string): boolean {
   return /^[A-Za-z0-9_-]+$/.test(name);
 }
@@ -1739,6 +2909,13 @@ async function ensureRepoAccess(
   return { id: repo.id, name: repo.name, role: access.role };
 }
 
+// Stored size of an env_objects row `o` including its committed chunks.
+const CHUNK_BYTES_SQL = `COALESCE((
+  SELECT SUM(length(c.ciphertext))
+  FROM env_object_chunks c
+  WHERE c.repo_id = o.repo_id AND c.branch = o.branch AND c.path = o.path AND c.version = o.version
+), 0)`;
+
 async function latestObjectSize(
   env: Env,
   repoId: string,
@@ -1746,10 +2923,10 @@ async function latestObjectSize(
   path: string,
 ): Promise<number> {
   const row = await env.DB.prepare(
-    `SELECT length(ciphertext) as size
-     FROM env_objects
-     WHERE repo_id = ? AND branch = ? AND path = ?
-     ORDER BY created_at DESC
+    `SELECT length(o.ciphertext) + ${CHUNK_BYTES_SQL} as size
+     FROM env_objects o
+     WHERE o.repo_id = ? AND o.branch = ? AND o.path = ?
+     ORDER BY o.created_at DESC
      LIMIT 1`,
   )
     .bind(repoId, branch, path)
@@ -1761,12 +2938,12 @@ async function latestObjectSize(
 async function totalLatestSize(env: Env, repoId: string): Promise<number> {
   const row = await env.DB.prepare(
     `WITH latest AS (
-       SELECT ciphertext,
-              ROW_NUMBER() OVER (PARTITION BY repo_id, branch, path ORDER BY created_at DESC) AS rn
-       FROM env_objects
-       WHERE repo_id = ?
+       SELECT length(o.ciphertext) + ${CHUNK_BYTES_SQL} AS size,
+              ROW_NUMBER() OVER (PARTITION BY o.repo_id, o.branch, o.path ORDER BY o.created_at DESC) AS rn
+       FROM env_objects o
+       WHERE o.repo_id = ?
      )
-     SELECT COALESCE(SUM(length(ciphertext)), 0) AS total
+     SELECT COALESCE(SUM(size), 0) AS total
      FROM latest
      WHERE rn = 1`,
   )
@@ -1810,4 +2987,10 @@ async function pruneHistory(
   )
     .bind(repoId, branch, path, minVersion)
     .run();
+  await env.DB.prepare(
+    `DELETE FROM env_object_chunks
+     WHERE repo_id = ? AND branch = ? AND path = ? AND version < ?`,
+  )
+    .bind(repoId, branch, path, minVersion)
+    .run();
 }
@@ -1,6 +1,8 @@
 export interface Env {
   DB: D1Database;
   SESSION_TTL_HOURS: string;
+  REFRESH_TTL_HOURS?: string;
   LOGIN_RATE_LIMIT?: string;
   LOGIN_RATE_WINDOW_SECONDS?: string;
+  MAX_REPO_BYTES?: string;
 }